@@ -1,19 +1,24 @@
 use std::{
     io::{stdout, Write},
     path::Path,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use clap::{Arg, ArgAction, Command};
 use crossterm::{
     cursor,
-    event::{Event, KeyEventKind},
+    event::{
+        Event, KeyEventKind, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+        PushKeyboardEnhancementFlags,
+    },
     style::ResetColor,
-    terminal::Clear,
+    terminal::{supports_keyboard_enhancement, Clear},
 };
 use rusty_boy_core::{
-    gameboy::Gameboy,
+    gameboy::{Gameboy, Model, Renderer},
     keypad::{Key, KeyEvent},
+    mobile::LocalServer,
+    tcp_link::TcpLink,
 };
 
 use crossterm::ExecutableCommand;
@@ -21,7 +26,33 @@ use crossterm::ExecutableCommand;
 const WEIGHT: u32 = 160;
 const HEIGHT: u32 = 144;
 
-fn main() -> Result<(), std::io::Error> {
+// Write the save file at most once per second when the cartridge RAM changes
+const SAVE_INTERVAL_FRAMES: u32 = 60;
+
+// Frames rewound per displayed frame while the rewind key is held
+const REWIND_SPEED: usize = 2;
+
+// Without key release events, rewinding stops when the key stops repeating.
+// Terminals wait 250 to 500 ms before the first repeat, then repeat every 30 to 50 ms
+const REWIND_FIRST_REPEAT_TIMEOUT: Duration = Duration::from_millis(600);
+const REWIND_REPEAT_TIMEOUT: Duration = Duration::from_millis(100);
+
+enum Input {
+    Key(KeyEvent),
+    Rewind(bool),
+    Quit,
+}
+
+// Pops the keyboard enhancement flags when the emulator exits, errors included
+struct KeyboardEnhancement;
+
+impl Drop for KeyboardEnhancement {
+    fn drop(&mut self) {
+        let _ = stdout().execute(PopKeyboardEnhancementFlags);
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = Command::new("cli-rusty_boy")
         .version("0.1")
         .author("Flender <tristan.deloeil@gmail.com>")
@@ -32,18 +63,99 @@ fn main() -> Result<(), std::io::Error> {
             Arg::new("skip-checksup")
                 .short('s')
                 .action(ArgAction::SetTrue),
+            Arg::new("model")
+                .long("model")
+                .help("Hardware to emulate, picked from the game header by default")
+                .value_parser(["dmg0", "dmg", "mgb", "sgb", "sgb2", "cgb", "agb"]),
+            Arg::new("boot-rom")
+                .long("boot-rom")
+                .help("Boot ROM to run before the game (DMG, MGB, SGB or CGB)"),
+            Arg::new("scanline")
+                .long("scanline")
+                .help("Draw whole lines instead of running the pixel FIFO, faster but mid-scanline effects are lost")
+                .action(ArgAction::SetTrue),
+            Arg::new("rewind-budget")
+                .long("rewind-budget")
+                .help("Memory used to rewind the game, in MiB (0 to disable)")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("32"),
+            Arg::new("link-host")
+                .long("link-host")
+                .help("Wait for another emulator to plug a link cable on this port")
+                .value_parser(clap::value_parser!(u16))
+                .conflicts_with("link-connect"),
+            Arg::new("link-bind")
+                .long("link-bind")
+                .help("Address the link port listens on, 0.0.0.0 to let other machines connect")
+                .default_value("127.0.0.1")
+                .requires("link-host"),
+            Arg::new("link-connect")
+                .long("link-connect")
+                .help("Plug a link cable to an emulator waiting at this address (host:port)"),
+            Arg::new("printer")
+                .long("printer")
+                .help("Plug a Game Boy Printer, printed pages are saved as PNG files in this directory")
+                .conflicts_with_all(["link-host", "link-connect"]),
+            Arg::new("mobile-server")
+                .long("mobile-server")
+                .help("Plug a Mobile Adapter GB whose calls and connections go to this server (host:port)")
+                .conflicts_with_all(["link-host", "link-connect", "printer"]),
         ])
         .get_matches();
 
     let file = matches.get_one::<String>("file").unwrap();
     let info = matches.get_flag("info");
     let skip_checksum = matches.get_flag("skip-checksup");
+    let rewind_budget = *matches.get_one::<usize>("rewind-budget").unwrap();
+    let boot_rom = matches.get_one::<String>("boot-rom");
+    let model = matches.get_one::<String>("model").map(|model| match model.as_str() {
+        "dmg0" => Model::DMG0,
+        "dmg" => Model::DMG,
+        "mgb" => Model::MGB,
+        "sgb" => Model::SGB,
+        "sgb2" => Model::SGB2,
+        "cgb" => Model::CGB,
+        _ => Model::AGB,
+    });
 
     if Path::new(file).exists() == false {
-        panic!("The file doesn't exists.")
+        return Err(format!("The file {} doesn't exist", file).into());
+    }
+
+    let mut gb = match boot_rom {
+        Some(boot_rom) => Gameboy::new_from_file_with_boot_rom(file, boot_rom, model, skip_checksum)?,
+        None => Gameboy::new_from_file(file, model, skip_checksum)?,
+    };
+    gb.set_rewind_budget(rewind_budget * 1024 * 1024);
+    if matches.get_flag("scanline") {
+        gb.set_renderer(Renderer::Scanline);
+    }
+
+    if let Some(port) = matches.get_one::<u16>("link-host") {
+        let bind = matches.get_one::<String>("link-bind").unwrap();
+        println!("Waiting for the other player on {}:{}...", bind, port);
+        gb.connect_link(Box::new(TcpLink::host((bind.as_str(), *port))?));
+    } else if let Some(address) = matches.get_one::<String>("link-connect") {
+        gb.connect_link(Box::new(TcpLink::connect(address.as_str())?));
+    } else if let Some(directory) = matches.get_one::<String>("printer") {
+        std::fs::create_dir_all(directory)?;
+        let directory = directory.clone();
+        let mut pages = 0;
+        gb.connect_printer(move |page| {
+            pages += 1;
+            let path = Path::new(&directory).join(format!("page_{:03}.png", pages));
+            // The terminal is used by the game, there is no good place to report the error
+            let _ = std::fs::write(path, page.to_png());
+        });
+    } else if let Some(address) = matches.get_one::<String>("mobile-server") {
+        gb.connect_mobile_adapter(Box::new(LocalServer::new(address)));
     }
 
-    let mut gb = Gameboy::new_from_file(file, skip_checksum)?;
+    // Battery-backed RAM is stored next to the ROM
+    let save_path = Path::new(file).with_extension("sav");
+    if save_path.exists() {
+        gb.load_ram_from_file(&save_path)?;
+    }
 
     if info == true {
         println!("{}", gb.header());
@@ -54,14 +166,49 @@ fn main() -> Result<(), std::io::Error> {
         std::io::stdin().read_line(&mut buffer).unwrap();
     }
 
+    // Key releases are only reported by terminals supporting the kitty keyboard protocol
+    let key_releases = supports_keyboard_enhancement().unwrap_or(false);
+    let _keyboard_enhancement = if key_releases {
+        stdout().execute(PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        Some(KeyboardEnhancement)
+    } else {
+        None
+    };
+
     let mut last_time = std::time::Instant::now();
+    let mut frame_count: u32 = 0;
+    // Last press or repeat of the rewind key and how long to wait for the next one, cleared by its release
+    let mut rewind_key: Option<(Instant, Duration)> = None;
     loop {
         // Handle input
-        if let Some(key) = cb_input() {
-            gb.update_input(key);
+        match cb_input() {
+            Some(Input::Key(key)) => gb.update_input(key),
+            Some(Input::Rewind(true)) => {
+                let timeout = match rewind_key {
+                    Some((last, wait)) if last.elapsed() < wait => REWIND_REPEAT_TIMEOUT,
+                    _ => REWIND_FIRST_REPEAT_TIMEOUT,
+                };
+                rewind_key = Some((Instant::now(), timeout));
+            }
+            Some(Input::Rewind(false)) => rewind_key = None,
+            Some(Input::Quit) => break,
+            None => {}
         }
 
-        gb.run_frame();
+        let rewinding = match rewind_key {
+            Some((pressed, timeout)) => key_releases || pressed.elapsed() < timeout,
+            None => false,
+        };
+        if rewinding {
+            gb.rewind(REWIND_SPEED)?;
+        } else {
+            gb.run_frame();
+        }
+
+        frame_count = frame_count.wrapping_add(1);
+        if frame_count.is_multiple_of(SAVE_INTERVAL_FRAMES) && gb.ram_updated() {
+            gb.save_ram_to_file(&save_path)?;
+        }
 
         // Render screen
         let screen = gb.get_screen_data();
@@ -102,13 +249,27 @@ fn main() -> Result<(), std::io::Error> {
         }
         last_time = std::time::Instant::now();
     }
+
+    Ok(gb.save_ram_to_file(&save_path)?)
 }
 
-fn cb_input() -> Option<KeyEvent> {
+fn cb_input() -> Option<Input> {
     let key = None;
     use crossterm::event::{self, KeyCode, KeyEvent};
     if event::poll(Duration::from_millis(0)).unwrap() {
         if let Event::Key(KeyEvent { code, kind, .. }) = event::read().unwrap() {
+            if code == KeyCode::Esc {
+                return Some(Input::Quit);
+            }
+
+            // Hold R to play the game backwards
+            if code == KeyCode::Char('r') {
+                return match kind {
+                    KeyEventKind::Press | KeyEventKind::Repeat => Some(Input::Rewind(true)),
+                    KeyEventKind::Release => Some(Input::Rewind(false)),
+                };
+            }
+
             let k = match code {
                 KeyCode::Char('z') => Some(Key::Up),
                 KeyCode::Char('s') => Some(Key::Down),
@@ -123,9 +284,11 @@ fn cb_input() -> Option<KeyEvent> {
 
             if let Some(k) = k {
                 match kind {
-                    KeyEventKind::Press => return Some(rusty_boy_core::keypad::KeyEvent::Press(k)),
+                    KeyEventKind::Press => {
+                        return Some(Input::Key(rusty_boy_core::keypad::KeyEvent::Press(k)))
+                    }
                     KeyEventKind::Release => {
-                        return Some(rusty_boy_core::keypad::KeyEvent::Release(k))
+                        return Some(Input::Key(rusty_boy_core::keypad::KeyEvent::Release(k)))
                     }
                     _ => {}
                 }
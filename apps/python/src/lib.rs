@@ -1,5 +1,25 @@
-use pyo3::{exceptions::PyValueError, prelude::*};
-use rusty_boy_core::{gameboy::Gameboy, keypad::{Key, KeyEvent}};
+use pyo3::{create_exception, exceptions::{PyIOError, PyValueError}, prelude::*};
+use rusty_boy_core::{gameboy::Gameboy, keypad::{Key, KeyEvent}, tcp_link::TcpLink, Error};
+
+create_exception!(rusty_boy, RomError, PyValueError, "The ROM can't be loaded");
+create_exception!(rusty_boy, ChecksumError, RomError, "The header checksum of the ROM is invalid");
+create_exception!(rusty_boy, UnsupportedCartridgeError, RomError, "The cartridge type is not emulated");
+create_exception!(rusty_boy, StateError, PyValueError, "The save state or save data can't be loaded");
+
+fn to_py_err(error: Error) -> PyErr {
+    let message = error.to_string();
+    match error {
+        Error::Io(error) => PyIOError::new_err(error.to_string()),
+        Error::BadChecksum { .. } => ChecksumError::new_err(message),
+        Error::UnsupportedCartridge(_) => UnsupportedCartridgeError::new_err(message),
+        Error::InvalidHeader
+        | Error::InvalidRomSize(_)
+        | Error::InvalidRamSize(_)
+        | Error::RomSizeMismatch { .. }
+        | Error::InvalidBootRom { .. } => RomError::new_err(message),
+        _ => StateError::new_err(message),
+    }
+}
 
 #[pyclass]
 struct RustyBoy {
@@ -9,14 +29,10 @@ struct RustyBoy {
 #[pymethods]
 impl RustyBoy {
     #[new]
-    fn new(file_path: &str, skip_checksum: bool) -> Self {
-
-        if let Ok(gameboy) = Gameboy::new_from_file(file_path, skip_checksum) {
-            RustyBoy { gameboy }
-        } else {
-            panic!("Error loading gameboy")
-        }
-
+    fn new(file_path: &str, skip_checksum: bool) -> PyResult<Self> {
+        Gameboy::new_from_file(file_path, None, skip_checksum)
+            .map(|gameboy| RustyBoy { gameboy })
+            .map_err(to_py_err)
     }
 
     fn run_frame(&mut self) -> PyResult<()> {
@@ -63,11 +79,57 @@ impl RustyBoy {
         Ok(self.gameboy.get_screen_data().clone())
     }
 
+    /**
+     * Wait for another emulator to plug a link cable on this port
+     * Only local emulators can connect unless `bind` is another address, such as "0.0.0.0"
+     */
+    #[pyo3(signature = (port, bind = "127.0.0.1"))]
+    pub fn host_link(&mut self, port: u16, bind: &str) -> PyResult<()> {
+        let link = TcpLink::host((bind, port)).map_err(|e| PyIOError::new_err(e.to_string()))?;
+        self.gameboy.connect_link(Box::new(link));
+        Ok(())
+    }
+
+    /**
+     * Plug a link cable to an emulator waiting at "host:port"
+     */
+    pub fn connect_link(&mut self, address: &str) -> PyResult<()> {
+        let link = TcpLink::connect(address).map_err(|e| PyIOError::new_err(e.to_string()))?;
+        self.gameboy.connect_link(Box::new(link));
+        Ok(())
+    }
+
+    /**
+     * (pc, opcode) of the illegal opcode that hung the CPU, None while it runs
+     */
+    pub fn get_lockup(&self) -> PyResult<Option<(u16, u8)>> {
+        Ok(self.gameboy.lockup().map(|lockup| (lockup.pc, lockup.opcode)))
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> PyResult<()> {
+        self.gameboy.set_sample_rate(sample_rate);
+        Ok(())
+    }
+
+    /**
+     * Get the interleaved stereo samples produced since the last call
+     */
+    pub fn get_audio_samples(&mut self) -> PyResult<Vec<f32>> {
+        let mut samples = vec![0.0; self.gameboy.audio_samples_available()];
+        let count = self.gameboy.pull_audio_samples(&mut samples);
+        samples.truncate(count);
+        Ok(samples)
+    }
+
 }
 
 // This function name should be same as your project name
 #[pymodule]
 fn rusty_boy(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<RustyBoy>()?;
+    m.add("RomError", m.py().get_type_bound::<RomError>())?;
+    m.add("ChecksumError", m.py().get_type_bound::<ChecksumError>())?;
+    m.add("UnsupportedCartridgeError", m.py().get_type_bound::<UnsupportedCartridgeError>())?;
+    m.add("StateError", m.py().get_type_bound::<StateError>())?;
     Ok(())
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 use core::str;
 
-use rusty_boy_core::{gameboy::Gameboy, keypad::{Key, KeyEvent}};
+use rusty_boy_core::{gameboy::Gameboy, keypad::{Key, KeyEvent}, Error};
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
@@ -15,6 +15,24 @@ macro_rules! console_log {
     ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
 }
 
+/// Converts an error of the core to a JS `Error` whose name can be tested by the page
+fn to_js_error(error: Error) -> JsValue {
+    let name = match error {
+        Error::Io(_) => "IoError",
+        Error::BadChecksum { .. } => "ChecksumError",
+        Error::UnsupportedCartridge(_) => "UnsupportedCartridgeError",
+        Error::InvalidHeader
+        | Error::InvalidRomSize(_)
+        | Error::InvalidRamSize(_)
+        | Error::RomSizeMismatch { .. }
+        | Error::InvalidBootRom { .. } => "RomError",
+        _ => "StateError",
+    };
+    let js_error = js_sys::Error::new(&error.to_string());
+    js_error.set_name(name);
+    js_error.into()
+}
+
 #[wasm_bindgen(start)]
 fn start() -> Result<(), JsValue> {
     console_error_panic_hook::set_once();
@@ -31,9 +49,9 @@ impl RustyBoy {
     
     #[wasm_bindgen(constructor)]
     pub fn new(rom: Vec<u8>, skip_checksum: bool) -> Result<RustyBoy, JsValue> {
-        Gameboy::new_from_data(&rom, skip_checksum)
+        Gameboy::new_from_data(&rom, None, skip_checksum)
             .map(|gameboy| RustyBoy { gameboy })
-            .map_err(|e| JsValue::from_str(&format!("{:?}", e)))
+            .map_err(to_js_error)
     }
 
     #[wasm_bindgen]
@@ -46,6 +64,19 @@ impl RustyBoy {
         self.gameboy.get_screen_data().to_vec()
     }
 
+    #[wasm_bindgen]
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.gameboy.set_sample_rate(sample_rate);
+    }
+
+    #[wasm_bindgen]
+    pub fn pull_audio_samples(&mut self) -> Vec<f32> {
+        let mut samples = vec![0.0; self.gameboy.audio_samples_available()];
+        let count = self.gameboy.pull_audio_samples(&mut samples);
+        samples.truncate(count);
+        samples
+    }
+
     #[wasm_bindgen]
     pub fn press_key(&mut self, i: u8) {
         if let Some(key) = keycode_to_key(i) {
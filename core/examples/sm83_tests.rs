@@ -0,0 +1,31 @@
+//! Run the SM83 single step tests (one JSON fixture per opcode) and print a table of the results
+//!
+//! cargo run --release -p rusty_boy_core --example sm83_tests -- sm83/v1
+
+use std::path::Path;
+use std::process::ExitCode;
+
+use rusty_boy_core::single_step::run_directory;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [directory] = args.as_slice() else {
+        eprintln!("Usage: sm83_tests <directory>");
+        return ExitCode::FAILURE;
+    };
+
+    match run_directory(Path::new(directory)) {
+        Ok(report) => {
+            println!("{}", report);
+            if report.passed() == report.files.len() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(error) => {
+            eprintln!("{}: {}", directory, error);
+            ExitCode::FAILURE
+        }
+    }
+}
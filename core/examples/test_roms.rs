@@ -0,0 +1,59 @@
+//! Run the blargg and mooneye test ROMs headless and print a table per suite
+//!
+//! cargo run --release -p rusty_boy_core --example test_roms -- --blargg gb-test-roms --mooneye mts/acceptance
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use rusty_boy_core::test_rom::{run_suite, Suite, DEFAULT_FRAMES};
+
+const USAGE: &str = "Usage: test_roms [--frames <count>] [--blargg <directory>]... [--mooneye <directory>]...";
+
+fn main() -> ExitCode {
+    let mut frames = DEFAULT_FRAMES;
+    let mut suites = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next();
+        match (arg.as_str(), value) {
+            ("--frames", Some(value)) => match value.parse() {
+                Ok(value) => frames = value,
+                Err(_) => {
+                    eprintln!("Invalid frame count: {}", value);
+                    return ExitCode::FAILURE;
+                }
+            },
+            ("--blargg", Some(directory)) => suites.push((Suite::Blargg, PathBuf::from(directory))),
+            ("--mooneye", Some(directory)) => suites.push((Suite::Mooneye, PathBuf::from(directory))),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if suites.is_empty() {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    }
+
+    let mut all_passed = true;
+    for (suite, directory) in suites {
+        match run_suite(&directory, suite, frames) {
+            Ok(report) => {
+                all_passed &= report.passed() == report.results.len();
+                println!("{}", report);
+            }
+            Err(error) => {
+                eprintln!("{}: {}", directory.display(), error);
+                all_passed = false;
+            }
+        }
+    }
+
+    if all_passed {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,954 @@
+use std::collections::VecDeque;
+
+use crate::state::{StateReader, StateWriter};
+
+const CPU_CLOCK: u32 = 4_194_304;
+
+// The frame sequencer runs at 512 Hz
+const FRAME_SEQUENCER_PERIOD: u32 = CPU_CLOCK / 512;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+const WAVE_RAM_SIZE: usize = 0x10;
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/**
+ * Length counter shared by all channels, clocked at 256 Hz by the frame sequencer.
+ * @see: https://gbdev.io/pandocs/Audio_details.html#length-timer
+ */
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> Self {
+        LengthCounter {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u16(self.counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.enabled = state.bool()?;
+        self.counter = state.u16()?.min(self.max);
+        Ok(())
+    }
+
+    /// Returns true when the counter just expired and the channel must be disabled
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+}
+
+/**
+ * Volume envelope of the square and noise channels (NRx2), clocked at 64 Hz.
+ * @see: https://gbdev.io/pandocs/Audio_Registers.html#ff12--nr12-channel-1-volume--envelope
+ */
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        (self.initial_volume << 4) | ((self.increase as u8) << 3) | self.period
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.read());
+        state.u8(self.volume);
+        state.u8(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.write(state.u8()?);
+        self.volume = state.u8()? & 0x0F;
+        self.timer = state.u8()?;
+        Ok(())
+    }
+
+    // The DAC is powered as long as the upper 5 bits of NRx2 are not all 0
+    fn dac_enabled(&self) -> bool {
+        self.read() & 0xF8 != 0
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+/**
+ * Frequency sweep of channel 1 (NR10), clocked at 128 Hz.
+ * @see: https://gbdev.io/pandocs/Audio_Registers.html#ff10--nr10-channel-1-sweep
+ */
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+
+    enabled: bool,
+    timer: u8,
+    shadow_frequency: u16,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            enabled: false,
+            timer: 0,
+            shadow_frequency: 0,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        0x80 | (self.period << 4) | ((self.negate as u8) << 3) | self.shift
+    }
+
+    fn write(&mut self, value: u8) {
+        self.period = (value >> 4) & 0x07;
+        self.negate = value & 0x08 != 0;
+        self.shift = value & 0x07;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.read());
+        state.bool(self.enabled);
+        state.u8(self.timer);
+        state.u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.write(state.u8()?);
+        self.enabled = state.bool()?;
+        self.timer = state.u8()?;
+        self.shadow_frequency = state.u16()? & 0x07FF;
+        Ok(())
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn calculate(&self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+}
+
+/**
+ * Channels 1 & 2: square waves with a selectable duty cycle.
+ * Only channel 1 uses its sweep unit.
+ */
+struct SquareChannel {
+    enabled: bool,
+    has_sweep: bool,
+
+    sweep: Sweep,
+    length: LengthCounter,
+    envelope: Envelope,
+
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    timer: u32,
+}
+
+impl SquareChannel {
+    fn new(has_sweep: bool) -> Self {
+        SquareChannel {
+            enabled: false,
+            has_sweep,
+            sweep: Sweep::new(),
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            duty: 0,
+            duty_position: 0,
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        match register {
+            0 if self.has_sweep => self.sweep.read(),
+            0 => 0xFF,
+            1 => (self.duty << 6) | 0x3F,
+            2 => self.envelope.read(),
+            3 => 0xFF,
+            _ => 0xBF | ((self.length.enabled as u8) << 6),
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 if self.has_sweep => self.sweep.write(value),
+            0 => {}
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        self.sweep.save_state(state);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.u8(self.duty);
+        state.u8(self.duty_position);
+        state.u16(self.frequency);
+        state.u32(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.enabled = state.bool()?;
+        self.sweep.load_state(state)?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.duty = state.u8()? & 0x03;
+        self.duty_position = state.u8()? & 0x07;
+        self.frequency = state.u16()? & 0x07FF;
+        self.timer = state.u32()?;
+        Ok(())
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = 0;
+        self.envelope.trigger();
+
+        if self.has_sweep {
+            self.sweep.shadow_frequency = self.frequency;
+            self.sweep.reload_timer();
+            self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+            if self.sweep.shift != 0 && self.sweep.calculate() > 0x07FF {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep.timer > 0 {
+            self.sweep.timer -= 1;
+        }
+        if self.sweep.timer != 0 {
+            return;
+        }
+        self.sweep.reload_timer();
+
+        if !self.sweep.enabled || self.sweep.period == 0 {
+            return;
+        }
+
+        let frequency = self.sweep.calculate();
+        if frequency > 0x07FF {
+            self.enabled = false;
+        } else if self.sweep.shift != 0 {
+            self.sweep.shadow_frequency = frequency;
+            self.frequency = frequency;
+            // The overflow check is done a second time with the new frequency
+            if self.sweep.calculate() > 0x07FF {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let period = (2048 - self.frequency as u32) * 4;
+        self.timer += cycles;
+        while self.timer >= period {
+            self.timer -= period;
+            self.duty_position = (self.duty_position + 1) & 0x07;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_PATTERNS[self.duty as usize][self.duty_position as usize] * self.envelope.volume
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+}
+
+/**
+ * Channel 3: plays the 32 4-bit samples stored in wave RAM (0xFF30-0xFF3F).
+ */
+struct WaveChannel {
+    enabled: bool,
+    dac_on: bool,
+
+    length: LengthCounter,
+
+    volume_code: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+
+    wave_ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl WaveChannel {
+    fn new() -> Self {
+        WaveChannel {
+            enabled: false,
+            dac_on: false,
+            length: LengthCounter::new(256),
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            wave_ram: [0; WAVE_RAM_SIZE],
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => 0x7F | ((self.dac_on as u8) << 7),
+            1 => 0xFF,
+            2 => 0x9F | (self.volume_code << 5),
+            3 => 0xFF,
+            _ => 0xBF | ((self.length.enabled as u8) << 6),
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.dac_on = value & 0x80 != 0;
+                if !self.dac_on {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            2 => self.volume_code = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac_on);
+        self.length.save_state(state);
+        state.u8(self.volume_code);
+        state.u16(self.frequency);
+        state.u32(self.timer);
+        state.u8(self.position);
+        state.bytes(&self.wave_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.enabled = state.bool()?;
+        self.dac_on = state.bool()?;
+        self.length.load_state(state)?;
+        self.volume_code = state.u8()? & 0x03;
+        self.frequency = state.u16()? & 0x07FF;
+        self.timer = state.u32()?;
+        self.position = state.u8()? & 0x1F;
+        state.bytes_into(&mut self.wave_ram)
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_on;
+        self.length.trigger();
+        self.timer = 0;
+        self.position = 0;
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let period = (2048 - self.frequency as u32) * 2;
+        self.timer += cycles;
+        while self.timer >= period {
+            self.timer -= period;
+            self.position = (self.position + 1) & 0x1F;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let byte = self.wave_ram[self.position as usize >> 1];
+        let sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+        match self.volume_code {
+            0 => 0,
+            1 => sample,
+            2 => sample >> 1,
+            _ => sample >> 2,
+        }
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.dac_on
+    }
+}
+
+/**
+ * Channel 4: pseudo-random noise from a 15-bit (or 7-bit) LFSR.
+ */
+struct NoiseChannel {
+    enabled: bool,
+
+    length: LengthCounter,
+    envelope: Envelope,
+
+    clock_shift: u8,
+    width_mode: bool,
+    divisor_code: u8,
+
+    lfsr: u16,
+    timer: u32,
+}
+
+impl NoiseChannel {
+    fn new() -> Self {
+        NoiseChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        match register {
+            0 | 1 => 0xFF,
+            2 => self.envelope.read(),
+            3 => (self.clock_shift << 4) | ((self.width_mode as u8) << 3) | self.divisor_code,
+            _ => 0xBF | ((self.length.enabled as u8) << 6),
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {}
+            1 => self.length.load(value & 0x3F),
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = value >> 4;
+                self.width_mode = value & 0x08 != 0;
+                self.divisor_code = value & 0x07;
+            }
+            _ => {
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.u8(self.read(3));
+        state.u16(self.lfsr);
+        state.u32(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.enabled = state.bool()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.write(3, state.u8()?);
+        self.lfsr = state.u16()? & 0x7FFF;
+        self.timer = state.u32()?;
+        Ok(())
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = 0;
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let period = NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift;
+        self.timer += cycles;
+        while self.timer >= period {
+            self.timer -= period;
+            let xor = (self.lfsr & 0x01) ^ ((self.lfsr >> 1) & 0x01);
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !0x40) | (xor << 6);
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 0x01 != 0 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+}
+
+/**
+ * @see: https://gbdev.io/pandocs/Audio.html
+ */
+pub struct Apu {
+    // === NR52 (0xFF26) === Audio master control
+    enabled: bool,
+
+    channel1: SquareChannel,
+    channel2: SquareChannel,
+    channel3: WaveChannel,
+    channel4: NoiseChannel,
+
+    // === NR50 (0xFF24) === Master volume & VIN panning
+    nr50: u8,
+
+    // === NR51 (0xFF25) === Sound panning
+    nr51: u8,
+
+    frame_sequencer: u8,
+    frame_clock: u32,
+
+    sample_rate: u32,
+    sample_clock: u32,
+
+    // High-pass filter emulating the capacitors on the output
+    capacitor_left: f32,
+    capacitor_right: f32,
+    charge_factor: f32,
+
+    // Interleaved stereo samples (left, right) waiting to be pulled by the frontend
+    samples: VecDeque<f32>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        let mut apu = Apu {
+            enabled: true,
+            channel1: SquareChannel::new(true),
+            channel2: SquareChannel::new(false),
+            channel3: WaveChannel::new(),
+            channel4: NoiseChannel::new(),
+            nr50: 0,
+            nr51: 0,
+            frame_sequencer: 0,
+            frame_clock: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_clock: 0,
+            capacitor_left: 0.0,
+            capacitor_right: 0.0,
+            charge_factor: 0.0,
+            samples: VecDeque::new(),
+        };
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        apu
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        // At most one sample per cycle, which also keeps `sample_clock` from overflowing
+        self.sample_rate = sample_rate.clamp(1, CPU_CLOCK);
+        self.sample_clock = 0;
+        self.charge_factor = 0.999958f32.powf(CPU_CLOCK as f32 / self.sample_rate as f32);
+        self.samples.clear();
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        self.channel1.save_state(state);
+        self.channel2.save_state(state);
+        self.channel3.save_state(state);
+        self.channel4.save_state(state);
+        state.u8(self.nr50);
+        state.u8(self.nr51);
+        state.u8(self.frame_sequencer);
+        state.u32(self.frame_clock);
+        state.f32(self.capacitor_left);
+        state.f32(self.capacitor_right);
+    }
+
+    /// The sample rate and the pending samples belong to the frontend and are kept as is
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.enabled = state.bool()?;
+        self.channel1.load_state(state)?;
+        self.channel2.load_state(state)?;
+        self.channel3.load_state(state)?;
+        self.channel4.load_state(state)?;
+        self.nr50 = state.u8()?;
+        self.nr51 = state.u8()?;
+        self.frame_sequencer = state.u8()? & 0x07;
+        self.frame_clock = state.u32()? % FRAME_SEQUENCER_PERIOD;
+        self.capacitor_left = state.f32()?;
+        self.capacitor_right = state.f32()?;
+        Ok(())
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF10..=0xFF14 => self.channel1.read(address - 0xFF10),
+            0xFF15..=0xFF19 => self.channel2.read(address - 0xFF15),
+            0xFF1A..=0xFF1E => self.channel3.read(address - 0xFF1A),
+            0xFF1F..=0xFF23 => self.channel4.read(address - 0xFF1F),
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => {
+                ((self.enabled as u8) << 7)
+                    | 0x70
+                    | ((self.channel4.enabled as u8) << 3)
+                    | ((self.channel3.enabled as u8) << 2)
+                    | ((self.channel2.enabled as u8) << 1)
+                    | (self.channel1.enabled as u8)
+            }
+            0xFF30..=0xFF3F => self.channel3.wave_ram[address as usize - 0xFF30],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        // Wave RAM and NR52 stay accessible while the APU is off
+        match address {
+            0xFF26 => return self.set_power(value & 0x80 != 0),
+            0xFF30..=0xFF3F => {
+                self.channel3.wave_ram[address as usize - 0xFF30] = value;
+                return;
+            }
+            _ if !self.enabled => return,
+            _ => {}
+        }
+
+        match address {
+            0xFF10..=0xFF14 => self.channel1.write(address - 0xFF10, value),
+            0xFF15..=0xFF19 => self.channel2.write(address - 0xFF15, value),
+            0xFF1A..=0xFF1E => self.channel3.write(address - 0xFF1A, value),
+            0xFF1F..=0xFF23 => self.channel4.write(address - 0xFF1F, value),
+            0xFF24 => self.nr50 = value,
+            0xFF25 => self.nr51 = value,
+            _ => {}
+        }
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if self.enabled && !on {
+            // Turning the APU off clears every register except wave RAM
+            let wave_ram = self.channel3.wave_ram;
+            self.channel1 = SquareChannel::new(true);
+            self.channel2 = SquareChannel::new(false);
+            self.channel3 = WaveChannel::new();
+            self.channel3.wave_ram = wave_ram;
+            self.channel4 = NoiseChannel::new();
+            self.nr50 = 0;
+            self.nr51 = 0;
+        } else if !self.enabled && on {
+            self.frame_sequencer = 0;
+            self.frame_clock = 0;
+        }
+        self.enabled = on;
+    }
+
+    pub fn step(&mut self, cycles: u8) {
+        let cycles = cycles as u32;
+
+        if self.enabled {
+            self.frame_clock += cycles;
+            while self.frame_clock >= FRAME_SEQUENCER_PERIOD {
+                self.frame_clock -= FRAME_SEQUENCER_PERIOD;
+                self.clock_frame_sequencer();
+            }
+
+            self.channel1.step(cycles);
+            self.channel2.step(cycles);
+            self.channel3.step(cycles);
+            self.channel4.step(cycles);
+        }
+
+        self.sample_clock += cycles * self.sample_rate;
+        while self.sample_clock >= CPU_CLOCK {
+            self.sample_clock -= CPU_CLOCK;
+            self.push_sample();
+        }
+    }
+
+    /**
+     * Step | Length | Envelope | Sweep
+     * 0    | Clock  |          |
+     * 2    | Clock  |          | Clock
+     * 4    | Clock  |          |
+     * 6    | Clock  |          | Clock
+     * 7    |        | Clock    |
+     */
+    fn clock_frame_sequencer(&mut self) {
+        if self.frame_sequencer & 1 == 0 {
+            if self.channel1.length.clock() {
+                self.channel1.enabled = false;
+            }
+            if self.channel2.length.clock() {
+                self.channel2.enabled = false;
+            }
+            if self.channel3.length.clock() {
+                self.channel3.enabled = false;
+            }
+            if self.channel4.length.clock() {
+                self.channel4.enabled = false;
+            }
+        }
+        if self.frame_sequencer == 2 || self.frame_sequencer == 6 {
+            self.channel1.clock_sweep();
+        }
+        if self.frame_sequencer == 7 {
+            self.channel1.envelope.clock();
+            self.channel2.envelope.clock();
+            self.channel4.envelope.clock();
+        }
+        self.frame_sequencer = (self.frame_sequencer + 1) & 0x07;
+    }
+
+    // Convert the digital value (0-15) of a channel to the analog output (-1.0 to 1.0) of its DAC
+    fn dac(dac_enabled: bool, value: u8) -> f32 {
+        if dac_enabled {
+            1.0 - (value as f32 / 7.5)
+        } else {
+            0.0
+        }
+    }
+
+    fn push_sample(&mut self) {
+        let outputs = [
+            Apu::dac(self.channel1.dac_enabled(), self.channel1.output()),
+            Apu::dac(self.channel2.dac_enabled(), self.channel2.output()),
+            Apu::dac(self.channel3.dac_enabled(), self.channel3.output()),
+            Apu::dac(self.channel4.dac_enabled(), self.channel4.output()),
+        ];
+
+        let (mut left, mut right) = (0.0, 0.0);
+        if self.enabled {
+            for (i, output) in outputs.iter().enumerate() {
+                if self.nr51 & (0x10 << i) != 0 {
+                    left += output;
+                }
+                if self.nr51 & (0x01 << i) != 0 {
+                    right += output;
+                }
+            }
+            left *= (((self.nr50 >> 4) & 0x07) + 1) as f32 / 8.0 / 4.0;
+            right *= ((self.nr50 & 0x07) + 1) as f32 / 8.0 / 4.0;
+        }
+
+        let left_out = left - self.capacitor_left;
+        self.capacitor_left = left - left_out * self.charge_factor;
+        let right_out = right - self.capacitor_right;
+        self.capacitor_right = right - right_out * self.charge_factor;
+
+        // Keep at most one second of audio if the frontend does not pull the samples
+        if self.samples.len() >= self.sample_rate as usize * 2 {
+            self.samples.drain(..2);
+        }
+        self.samples.push_back(left_out);
+        self.samples.push_back(right_out);
+    }
+
+    /// Number of samples (left and right counted separately) ready to be pulled
+    pub fn samples_available(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Move up to `buffer.len()` interleaved samples into `buffer` and return how many were written
+    pub fn pull_samples(&mut self, buffer: &mut [f32]) -> usize {
+        // Only hand out complete (left, right) pairs
+        let count = buffer.len().min(self.samples.len()) & !1;
+        for (slot, sample) in buffer.iter_mut().zip(self.samples.drain(..count)) {
+            *slot = sample;
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apu_read_masks() {
+        let mut apu = Apu::new();
+        apu.set_power(false);
+        apu.set_power(true);
+        assert_eq!(apu.read(0xFF10), 0x80);
+        assert_eq!(apu.read(0xFF11), 0x3F);
+        assert_eq!(apu.read(0xFF13), 0xFF);
+        assert_eq!(apu.read(0xFF1A), 0x7F);
+        assert_eq!(apu.read(0xFF26), 0xF0);
+        assert_eq!(apu.read(0xFF27), 0xFF);
+    }
+
+    #[test]
+    fn test_apu_trigger_and_length() {
+        let mut apu = Apu::new();
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF11, 0x3F); // Length of 1
+        apu.write(0xFF14, 0xC0); // Trigger with length enabled
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x01);
+
+        // The next length clock disables the channel
+        for _ in 0..(FRAME_SEQUENCER_PERIOD / 4) {
+            apu.step(4);
+        }
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_apu_dac_off_disables_channel() {
+        let mut apu = Apu::new();
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF19, 0x80);
+        assert_eq!(apu.read(0xFF26) & 0x02, 0x02);
+        apu.write(0xFF17, 0x00);
+        assert_eq!(apu.read(0xFF26) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn test_apu_power_off() {
+        let mut apu = Apu::new();
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF30, 0x12);
+        apu.write(0xFF26, 0x00);
+        assert_eq!(apu.read(0xFF24), 0x00);
+        assert_eq!(apu.read(0xFF30), 0x12);
+
+        // Registers are read-only while the APU is off
+        apu.write(0xFF24, 0x77);
+        assert_eq!(apu.read(0xFF24), 0x00);
+    }
+
+    #[test]
+    fn test_apu_sample_rate() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(32_768);
+        for _ in 0..(CPU_CLOCK / 4 / 64) {
+            apu.step(4);
+        }
+        // 1/64 second of audio at 32768 Hz, two channels
+        assert_eq!(apu.samples_available(), 512 * 2);
+
+        let mut buffer = [0.0; 100];
+        assert_eq!(apu.pull_samples(&mut buffer), 100);
+        assert_eq!(apu.samples_available(), 512 * 2 - 100);
+    }
+
+    #[test]
+    fn test_apu_sample_rate_above_cpu_clock() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(u32::MAX);
+        apu.step(255);
+        assert_eq!(apu.samples_available(), 255 * 2);
+    }
+}
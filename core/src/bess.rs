@@ -0,0 +1,384 @@
+use crate::cpu::CPU;
+use crate::gameboy::GBMode;
+use crate::state::{StateReader, StateWriter};
+use crate::Error;
+
+const BESS_MAGIC: &[u8; 4] = b"BESS";
+const BESS_MAJOR_VERSION: u16 = 1;
+const BESS_MINOR_VERSION: u16 = 1;
+
+const CORE_BLOCK_SIZE: usize = 0xD0;
+const INFO_BLOCK_SIZE: usize = 0x12;
+const XOAM_BLOCK_SIZE: usize = 0x60;
+// Same layout as the RTC appended to .sav files: 5 current and 5 latched registers, then the timestamp
+const RTC_BLOCK_SIZE: usize = 0x30;
+
+// Offset of the title and of the global checksum in the ROM header
+const TITLE_START: u16 = 0x0134;
+const GLOBAL_CHECKSUM_START: u16 = 0x014E;
+
+/**
+ * Best Effort Save State, the format shared by SameBoy, BGB and other emulators.
+ * The memory buffers are stored first, then the blocks, then a footer pointing to the first block.
+ * @see: https://github.com/LIJI32/SameBoy/blob/master/BESS.md
+ */
+pub fn save(cpu: &CPU) -> Vec<u8> {
+    let memory = &cpu.memory;
+    let cgb = memory.mode() == GBMode::CGB;
+    let (palette_bg, palette_obj) = memory.gpu.cgb_palette_data();
+
+    let wram: &[u8] = if cgb { &memory.wram } else { &memory.wram[..0x2000] };
+    let vram: &[u8] = if cgb { &memory.gpu.vram } else { &memory.gpu.vram[..0x2000] };
+    let palette_bg: &[u8] = if cgb { palette_bg } else { &[] };
+    let palette_obj: &[u8] = if cgb { palette_obj } else { &[] };
+    let buffers = [
+        wram,
+        vram,
+        memory.mbc.ram(),
+        &memory.gpu.oam,
+        &memory.hram,
+        palette_bg,
+        palette_obj,
+    ];
+
+    let mut state = StateWriter::new();
+    let mut buffer_positions = Vec::new();
+    for buffer in buffers {
+        buffer_positions.push((buffer.len() as u32, state.len() as u32));
+        state.raw_bytes(buffer);
+    }
+
+    let first_block = state.len() as u32;
+
+    let name = format!("rusty_boy {}", env!("CARGO_PKG_VERSION"));
+    write_block_header(&mut state, b"NAME", name.len());
+    state.raw_bytes(name.as_bytes());
+
+    write_block_header(&mut state, b"INFO", INFO_BLOCK_SIZE);
+    for address in (TITLE_START..TITLE_START + 0x10).chain(GLOBAL_CHECKSUM_START..GLOBAL_CHECKSUM_START + 2) {
+        state.u8(memory.mbc.read_rom(address));
+    }
+
+    write_block_header(&mut state, b"CORE", CORE_BLOCK_SIZE);
+    state.u16(BESS_MAJOR_VERSION);
+    state.u16(BESS_MINOR_VERSION);
+    state.raw_bytes(if cgb { b"CC  " } else { b"GD  " });
+    state.u16(cpu.registers.pc);
+    state.u16(cpu.registers.af());
+    state.u16(cpu.registers.bc());
+    state.u16(cpu.registers.de());
+    state.u16(cpu.registers.hl());
+    state.u16(cpu.registers.sp);
+    state.bool(cpu.ime);
+    state.u8(memory.interrupt_enable);
+    state.u8(cpu.halt as u8); // 0: running, 1: halted, 2: stopped
+    state.u8(0);
+    for address in 0xFF00..=0xFF7F {
+        state.u8(memory.read(address));
+    }
+    for (size, offset) in buffer_positions {
+        state.u32(size);
+        state.u32(offset);
+    }
+
+    // 0xFEA0-0xFEFF is not emulated and always reads as 0
+    write_block_header(&mut state, b"XOAM", XOAM_BLOCK_SIZE);
+    state.raw_bytes(&[0; XOAM_BLOCK_SIZE]);
+
+    let bank_writes = memory.mbc.bank_writes();
+    if !bank_writes.is_empty() {
+        write_block_header(&mut state, b"MBC ", bank_writes.len() * 3);
+        for (address, value) in bank_writes {
+            state.u16(address);
+            state.u8(value);
+        }
+    }
+
+    let rtc = memory.mbc.dump_ram().split_off(memory.mbc.ram().len());
+    if rtc.len() == RTC_BLOCK_SIZE {
+        write_block_header(&mut state, b"RTC ", RTC_BLOCK_SIZE);
+        state.raw_bytes(&rtc);
+    }
+
+    write_block_header(&mut state, b"END ", 0);
+
+    state.u32(first_block);
+    state.raw_bytes(BESS_MAGIC);
+    state.into_vec()
+}
+
+/**
+ * Load a BESS state, blocks of hardware this emulator does not have (SGB, HuC3...) are skipped.
+ * On error, the CPU may be left in a partially loaded state.
+ */
+pub fn load(cpu: &mut CPU, data: &[u8]) -> Result<(), Error> {
+    if data.len() < 8 || &data[data.len() - 4..] != BESS_MAGIC {
+        return Err(invalid_data("Not a BESS save state"));
+    }
+    let footer = data.len() - 8;
+    let first_block = StateReader::new(&data[footer..]).u32()? as usize;
+    if first_block > footer {
+        return Err(invalid_data("Invalid BESS footer"));
+    }
+
+    let mut blocks = StateReader::new(&data[first_block..footer]);
+    let mut core_loaded = false;
+    let mut first = true;
+    loop {
+        let name = blocks.take(4)?;
+        let length = blocks.u32()? as usize;
+        let content = blocks.take(length)?;
+        let name = std::str::from_utf8(name).unwrap_or("????");
+
+        match name {
+            "NAME" if first => {}
+            "INFO" => load_info(cpu, content)?,
+            "CORE" if !core_loaded => {
+                load_core(cpu, content, data)?;
+                core_loaded = true;
+            }
+            "XOAM" if core_loaded && length != XOAM_BLOCK_SIZE => {
+                return Err(invalid_data("Invalid size for the BESS XOAM block"));
+            }
+            "XOAM" if core_loaded => {}
+            "MBC " if core_loaded => load_mbc(cpu, content)?,
+            "RTC " if core_loaded => load_rtc(cpu, content)?,
+            "END " => break,
+            "NAME" | "CORE" | "XOAM" | "MBC " | "RTC " => {
+                return Err(invalid_data(&format!("Unexpected BESS block: {:?}", name)));
+            }
+            _ => {}
+        }
+        first = false;
+    }
+
+    if !core_loaded {
+        return Err(invalid_data("Missing BESS CORE block"));
+    }
+    Ok(())
+}
+
+fn write_block_header(state: &mut StateWriter, name: &[u8; 4], length: usize) {
+    state.raw_bytes(name);
+    state.u32(length as u32);
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::InvalidState(message.to_string())
+}
+
+fn load_info(cpu: &CPU, content: &[u8]) -> Result<(), Error> {
+    if content.len() != INFO_BLOCK_SIZE {
+        return Err(invalid_data("Invalid size for the BESS INFO block"));
+    }
+    let info = (TITLE_START..TITLE_START + 0x10)
+        .chain(GLOBAL_CHECKSUM_START..GLOBAL_CHECKSUM_START + 2)
+        .map(|address| cpu.memory.mbc.read_rom(address));
+    if !info.eq(content.iter().copied()) {
+        return Err(invalid_data("BESS save state was made with a different ROM"));
+    }
+    Ok(())
+}
+
+fn load_core(cpu: &mut CPU, content: &[u8], data: &[u8]) -> Result<(), Error> {
+    if content.len() < CORE_BLOCK_SIZE {
+        return Err(invalid_data("Invalid size for the BESS CORE block"));
+    }
+    let mut core = StateReader::new(content);
+
+    let major = core.u16()?;
+    let _minor = core.u16()?;
+    if major != BESS_MAJOR_VERSION {
+        return Err(Error::UnsupportedState(format!("BESS version {}", major)));
+    }
+
+    // First letter of the model: G (Game Boy), S (Super Game Boy) or C (Game Boy Color/Advance)
+    let model = core.take(4)?;
+    let cgb = cpu.memory.mode() == GBMode::CGB;
+    match (model[0], cgb) {
+        (b'G' | b'S', false) | (b'C', true) => {}
+        (b'G' | b'S' | b'C', _) => {
+            return Err(invalid_data(&format!(
+                "BESS save state was made on a {}, the game runs in {:?} mode",
+                String::from_utf8_lossy(model).trim(),
+                cpu.memory.mode()
+            )));
+        }
+        _ => return Err(invalid_data("Unknown model in the BESS CORE block")),
+    }
+
+    cpu.registers.pc = core.u16()?;
+    cpu.registers.set_af(core.u16()? & 0xFFF0);
+    cpu.registers.set_bc(core.u16()?);
+    cpu.registers.set_de(core.u16()?);
+    cpu.registers.set_hl(core.u16()?);
+    cpu.registers.sp = core.u16()?;
+    cpu.ime = core.bool()?;
+    // The format has no pending EI, HALT bug nor lockup, the CPU starts clean
+    cpu.ime_pending = false;
+    cpu.halt_bug = false;
+    cpu.lockup = None;
+    cpu.memory.interrupt_enable = core.u8()?;
+    // The STOP mode is not emulated, the CPU waits for an interrupt like in HALT mode
+    cpu.halt = core.u8()? != 0;
+    core.u8()?;
+
+    let mut registers = [0; 0x80];
+    registers.copy_from_slice(core.take(0x80)?);
+
+    let memory = &mut cpu.memory;
+    let (palette_bg, palette_obj) = {
+        let mut buffer = || -> Result<&[u8], Error> {
+            let size = core.u32()? as usize;
+            let offset = core.u32()? as usize;
+            data.get(offset..offset + size)
+                .ok_or_else(|| invalid_data("BESS memory buffer out of bounds"))
+        };
+        copy_buffer(buffer()?, &mut memory.wram);
+        copy_buffer(buffer()?, &mut memory.gpu.vram);
+        copy_buffer(buffer()?, memory.mbc.ram_mut());
+        copy_buffer(buffer()?, &mut memory.gpu.oam);
+        copy_buffer(buffer()?, &mut memory.hram);
+        (buffer()?, buffer()?)
+    };
+    memory.gpu.load_cgb_palette_data(palette_bg, palette_obj);
+
+    memory.restore_io_registers(&registers);
+    Ok(())
+}
+
+fn load_mbc(cpu: &mut CPU, content: &[u8]) -> Result<(), Error> {
+    if !content.len().is_multiple_of(3) {
+        return Err(invalid_data("Invalid size for the BESS MBC block"));
+    }
+    for write in content.chunks_exact(3) {
+        let address = u16::from_le_bytes([write[0], write[1]]);
+        match address {
+            0x0000..=0x7FFF => cpu.memory.mbc.write_rom(address, write[2]),
+            0xA000..=0xBFFF => cpu.memory.mbc.write_ram(address, write[2]),
+            _ => return Err(invalid_data(&format!("Invalid MBC register in BESS state: {:04X}", address))),
+        }
+    }
+    Ok(())
+}
+
+// Carts without a clock ignore the block
+fn load_rtc(cpu: &mut CPU, content: &[u8]) -> Result<(), Error> {
+    if content.len() != RTC_BLOCK_SIZE {
+        return Err(invalid_data("Invalid size for the BESS RTC block"));
+    }
+    let mbc = &mut cpu.memory.mbc;
+    if mbc.dump_ram().len() == mbc.ram().len() {
+        return Ok(());
+    }
+    let mut data = mbc.ram().to_vec();
+    data.extend_from_slice(content);
+    mbc.load_ram(&data)
+}
+
+// Buffers of a different size are truncated or padded, as the format allows
+fn copy_buffer(source: &[u8], destination: &mut [u8]) {
+    let length = source.len().min(destination.len());
+    destination[..length].copy_from_slice(&source[..length]);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gameboy::{Gameboy, Lockup};
+
+    fn new_gameboy(cgb_flag: u8) -> Gameboy {
+        new_gameboy_with_cartridge(cgb_flag, 0x13) // MBC3+RAM+BATTERY
+    }
+
+    fn new_gameboy_with_cartridge(cgb_flag: u8, cartridge_type: u8) -> Gameboy {
+        let mut rom = vec![0; 0x10000];
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        rom[0x0143] = cgb_flag;
+        rom[0x0147] = cartridge_type;
+        rom[0x0148] = 0x01;
+        rom[0x0149] = 0x03;
+        rom[0x014E] = 0xBE;
+        rom[0x014F] = 0xEF;
+        Gameboy::new_from_data(&rom, None, true).unwrap()
+    }
+
+    #[test]
+    fn test_bess_round_trip() {
+        let mut gameboy = new_gameboy(0x80);
+        gameboy.run_frame();
+        gameboy.cpu.memory.write(0x0000, 0x0A);
+        gameboy.cpu.memory.write(0x2000, 0x03);
+        gameboy.cpu.memory.write(0x4000, 0x02);
+        gameboy.cpu.memory.write(0xA000, 0x42);
+        gameboy.cpu.memory.write(0xFF70, 0x05);
+        gameboy.cpu.memory.write(0xD000, 0x24);
+        gameboy.cpu.memory.write(0xFF47, 0xE4);
+        gameboy.cpu.registers.set_bc(0x1234);
+        let state = gameboy.save_bess_state();
+
+        let mut other = new_gameboy(0x80);
+        other.load_bess_state(&state).unwrap();
+        assert_eq!(other.cpu.registers.bc(), 0x1234);
+        assert_eq!(other.cpu.registers.pc, gameboy.cpu.registers.pc);
+        assert_eq!(other.cpu.memory.read(0x4000), gameboy.cpu.memory.read(0x4000));
+        assert_eq!(other.cpu.memory.read(0xA000), 0x42);
+        assert_eq!(other.cpu.memory.read(0xD000), 0x24);
+        assert_eq!(other.cpu.memory.read(0xFF47), 0xE4);
+        assert_eq!(other.cpu.memory.read(0xFF44), gameboy.cpu.memory.read(0xFF44));
+    }
+
+    #[test]
+    fn test_bess_clears_lockup() {
+        let gameboy = new_gameboy(0x00);
+        let state = gameboy.save_bess_state();
+
+        let mut other = new_gameboy(0x00);
+        other.cpu.lockup = Some(Lockup { pc: 0x0150, opcode: 0xD3 });
+        other.cpu.ime_pending = true;
+        other.cpu.halt_bug = true;
+        other.load_bess_state(&state).unwrap();
+        assert_eq!(other.lockup(), None);
+        assert!(!other.cpu.ime_pending);
+        assert!(!other.cpu.halt_bug);
+    }
+
+    #[test]
+    fn test_bess_unknown_block() {
+        let gameboy = new_gameboy(0x00);
+        let mut state = gameboy.save_bess_state();
+
+        // Blocks of hardware that is not emulated are skipped
+        let end = state.len() - 16;
+        assert_eq!(&state[end..end + 4], b"END ");
+        state.splice(end..end, *b"SGB \x04\x00\x00\x00\x01\x02\x03\x04");
+        new_gameboy(0x00).load_bess_state(&state).unwrap();
+    }
+
+    #[test]
+    fn test_bess_rtc() {
+        let mut gameboy = new_gameboy_with_cartridge(0x00, 0x10); // MBC3+TIMER+RAM+BATTERY
+        let memory = &mut gameboy.cpu.memory;
+        memory.write(0x0000, 0x0A);
+        // Halt the clock, then set the seconds
+        memory.write(0x4000, 0x0C);
+        memory.write(0xA000, 0x40);
+        memory.write(0x4000, 0x08);
+        memory.write(0xA000, 42);
+        let state = gameboy.save_bess_state();
+        assert!(state.windows(4).any(|name| name == b"RTC "));
+
+        let mut other = new_gameboy_with_cartridge(0x00, 0x10);
+        other.load_bess_state(&state).unwrap();
+        let memory = &mut other.cpu.memory;
+        memory.write(0x6000, 0x00);
+        memory.write(0x6000, 0x01);
+        assert_eq!(memory.read(0xA000), 42);
+    }
+
+    #[test]
+    fn test_bess_other_model() {
+        let gameboy = new_gameboy(0x00);
+        let state = gameboy.save_bess_state();
+        assert!(new_gameboy(0x80).load_bess_state(&state).is_err());
+    }
+}
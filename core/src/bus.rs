@@ -0,0 +1,147 @@
+use std::cell::RefCell;
+
+use crate::memory::Memory;
+
+/// Everything the CPU is connected to: the memory map and the rest of the hardware, `Memory` on a Gameboy
+pub trait Bus {
+    fn read(&self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+
+    /// Little endian, the low byte is accessed first
+    fn read_word(&self, address: u16) -> u16 {
+        (self.read(address) as u16) | ((self.read(address.wrapping_add(1)) as u16) << 8)
+    }
+
+    fn write_word(&mut self, address: u16, value: u16) {
+        self.write(address, value as u8);
+        self.write(address.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    /// Run the rest of the hardware for `cycles` clock cycles
+    fn step(&mut self, cycles: u8);
+
+    /// 0xFF0F — IF: Interrupts requested
+    fn interrupt_flags(&self) -> u8;
+    fn set_interrupt_flags(&mut self, value: u8);
+
+    /// 0xFFFF — IE: Interrupts enabled
+    fn interrupt_enable(&self) -> u8;
+
+    /// Cycles of the VRAM DMA started since the last call, the CPU is stalled meanwhile
+    fn take_dma_cycles(&mut self) -> u32 {
+        0
+    }
+
+    /// Called by STOP, returns true if the CGB speed switch prepared with KEY1 happened
+    fn switch_speed(&mut self) -> bool {
+        false
+    }
+}
+
+impl Bus for Memory {
+    fn read(&self, address: u16) -> u8 {
+        Memory::read(self, address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        Memory::write(self, address, value)
+    }
+
+    fn step(&mut self, cycles: u8) {
+        Memory::step(self, cycles)
+    }
+
+    fn interrupt_flags(&self) -> u8 {
+        self.interrupt_flags
+    }
+
+    fn set_interrupt_flags(&mut self, value: u8) {
+        self.interrupt_flags = value;
+    }
+
+    fn interrupt_enable(&self) -> u8 {
+        self.interrupt_enable
+    }
+
+    fn take_dma_cycles(&mut self) -> u32 {
+        std::mem::take(&mut self.dma_cycles)
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        Memory::switch_speed(self)
+    }
+}
+
+/// Read or write seen on a `TestBus`
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum BusAccess {
+    Read { address: u16, value: u8 },
+    Write { address: u16, value: u8 },
+}
+
+/// Flat 64 KiB of RAM without any hardware behind it, recording every access of the CPU
+pub struct TestBus {
+    pub ram: Vec<u8>,
+    // Kept in a `RefCell` because reads are recorded too, with the M-cycle they happened in
+    accesses: RefCell<Vec<(u64, BusAccess)>>,
+    // Cycles run since the creation of the bus, the CPU runs 4 of them after each access
+    pub cycles: u64,
+    // IF, nothing requests interrupts on this bus
+    pub interrupt_flags: u8,
+}
+
+impl TestBus {
+    pub fn new() -> Self {
+        TestBus {
+            ram: vec![0; 0x10000],
+            accesses: RefCell::new(Vec::new()),
+            cycles: 0,
+            interrupt_flags: 0,
+        }
+    }
+
+    /// Accesses done since the last call, in order, with their M-cycle counted from the creation of the bus
+    pub fn take_accesses(&mut self) -> Vec<(u64, BusAccess)> {
+        self.accesses.take()
+    }
+
+    fn record(&self, access: BusAccess) {
+        self.accesses.borrow_mut().push((self.cycles / 4, access));
+    }
+}
+
+impl Default for TestBus {
+    fn default() -> Self {
+        TestBus::new()
+    }
+}
+
+impl Bus for TestBus {
+    fn read(&self, address: u16) -> u8 {
+        let value = self.ram[address as usize];
+        self.record(BusAccess::Read { address, value });
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.ram[address as usize] = value;
+        self.record(BusAccess::Write { address, value });
+    }
+
+    fn step(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+    }
+
+    fn interrupt_flags(&self) -> u8 {
+        self.interrupt_flags
+    }
+
+    fn set_interrupt_flags(&mut self, value: u8) {
+        self.interrupt_flags = value;
+    }
+
+    // IE is the last byte of the RAM, like on the real memory map
+    fn interrupt_enable(&self) -> u8 {
+        self.ram[0xFFFF]
+    }
+}
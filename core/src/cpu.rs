@@ -1,110 +1,242 @@
-use crate::{mbc::MBC, memory::Memory, registers::{Flag, Registers}};
+use crate::{bus::Bus, gameboy::{GBMode, Lockup}, mbc::MBC, memory::Memory, registers::{Flag, Registers}, state::{StateReader, StateWriter}};
 
-pub struct CPU {
+/// The CPU runs on `Memory`, or on any other `Bus` such as the `TestBus` of the single step tests
+pub struct CPU<B: Bus = Memory> {
     pub registers: Registers,
-    pub memory: Memory,
-    ime: bool,
-    halt: bool,
+    pub memory: B,
+    pub ime: bool,
+    pub halt: bool,
+
+    // EI enables the interrupts after the next instruction
+    pub(crate) ime_pending: bool,
+
+    // HALT bug: the next opcode is read without incrementing PC
+    pub(crate) halt_bug: bool,
+
+    // Set by an illegal opcode, the CPU stops until the next reset
+    pub lockup: Option<Lockup>,
+
+    // Cycles of the current instruction already run by the rest of the hardware
+    ticked: u8,
 }
 
 impl CPU {
-    pub fn new(mbc: Box<dyn MBC + 'static>) -> CPU {
+    pub fn new(mbc: Box<dyn MBC + 'static>, gbmode: GBMode, boot_rom: Option<Vec<u8>>) -> CPU {
         CPU {
-            registers: Registers::new(),
-            memory: Memory::new(mbc),
+            registers: match (gbmode, &boot_rom) {
+                (_, Some(_)) => Registers::power_on(),
+                (GBMode::DMG, None) => Registers::new(),
+                (GBMode::CGB, None) => Registers::new_cgb(),
+            },
+            memory: Memory::new(mbc, gbmode, boot_rom),
             ime: false,
             halt: false,
+            ime_pending: false,
+            halt_bug: false,
+            lockup: None,
+            ticked: 0,
         }
     }
 
-    #[inline(always)]
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.registers.save_state(state);
+        state.bool(self.ime);
+        state.bool(self.halt);
+        state.bool(self.ime_pending);
+        state.bool(self.halt_bug);
+        state.bool(self.lockup.is_some());
+        let lockup = self.lockup.unwrap_or(Lockup { pc: 0, opcode: 0 });
+        state.u16(lockup.pc);
+        state.u8(lockup.opcode);
+        self.memory.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.registers.load_state(state)?;
+        self.ime = state.bool()?;
+        self.halt = state.bool()?;
+        self.ime_pending = state.bool()?;
+        self.halt_bug = state.bool()?;
+        let locked = state.bool()?;
+        let lockup = Lockup { pc: state.u16()?, opcode: state.u8()? };
+        self.lockup = locked.then_some(lockup);
+        self.memory.load_state(state)
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    /// CPU with the interrupts disabled, connected to `bus`
+    pub fn with_bus(bus: B, registers: Registers) -> CPU<B> {
+        CPU {
+            registers,
+            memory: bus,
+            ime: false,
+            halt: false,
+            ime_pending: false,
+            halt_bug: false,
+            lockup: None,
+            ticked: 0,
+        }
+    }
+
+    /// IME as seen by the next instruction, EI included
+    pub fn interrupts_enabled(&self) -> bool {
+        self.ime || self.ime_pending
+    }
+
+    // Every access takes an M-cycle, the rest of the hardware runs right after it
     fn read_byte(&mut self, address: u16) -> u8 {
-        self.memory.read(address)
+        let value = self.memory.read(address);
+        self.tick();
+        value
     }
 
-    #[inline(always)]
-    fn read_word(&mut self) -> u16 {
-        self.memory.read_word(self.registers.pc)
+    fn read_word(&mut self, address: u16) -> u16 {
+        (self.read_byte(address) as u16) | ((self.read_byte(address.wrapping_add(1)) as u16) << 8)
+    }
+
+    // M-cycle of an access or of an internal step of the instruction
+    fn tick(&mut self) {
+        self.memory.step(4);
+        self.ticked += 4;
     }
 
     fn fetch_byte(&mut self) -> u8 {
-        let value = self.memory.read(self.registers.pc);
+        let value = self.read_byte(self.registers.pc);
         self.registers.pc = self.registers.pc.wrapping_add(1);
         value
     }
 
     fn fetch_word(&mut self) -> u16 {
-        let value = self.read_word();
+        let value = self.read_word(self.registers.pc);
         self.registers.pc = self.registers.pc.wrapping_add(2);
         value
     }
 
-    #[inline(always)]
     fn write_byte(&mut self, address: u16, value: u8) {
         self.memory.write(address, value);
+        self.tick();
     }
 
-    #[inline(always)]
     fn write_word(&mut self, address: u16, value: u16) {
-        self.memory.write_word(address, value);
+        self.write_byte(address, value as u8);
+        self.write_byte(address.wrapping_add(1), (value >> 8) as u8);
     }
 
+    // The high byte is pushed first
+    // CALL, RST and PUSH wait an M-cycle before writing
     fn push_stack(&mut self, value: u16) {
-        self.registers.sp = self.registers.sp.wrapping_sub(2);
-        self.write_word(self.registers.sp, value);
+        self.tick();
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write_byte(self.registers.sp, (value >> 8) as u8);
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write_byte(self.registers.sp, value as u8);
     }
 
     fn pop_stack(&mut self) -> u16 {
-        let res = self.memory.read_word(self.registers.sp);
-        self.registers.sp += 2;
+        let res = self.read_word(self.registers.sp);
+        self.registers.sp = self.registers.sp.wrapping_add(2);
         res
     }
 
-    pub fn step(&mut self) -> u8 {
-        self.handle_interrupt();
-
-        if self.halt {
+    pub fn step(&mut self) -> u32 {
+        // Interrupts cannot wake a locked CPU, the rest of the hardware keeps running
+        if self.lockup.is_some() {
             self.memory.step(4);
             return 4;
         }
 
+        if let Some(cycles) = self.handle_interrupt() {
+            return cycles + self.dma_stall();
+        }
 
-        let opcode = self.fetch_byte();
+        // The instruction following EI runs before the interrupts are enabled
+        if self.ime_pending {
+            self.ime_pending = false;
+            self.ime = true;
+        }
+
+        if self.halt {
+            self.memory.step(4);
+            return 4 + self.dma_stall();
+        }
+
+        self.ticked = 0;
+        let opcode = if self.halt_bug {
+            self.halt_bug = false;
+            self.read_byte(self.registers.pc)
+        } else {
+            self.fetch_byte()
+        };
         let cycles = self.call_opcode(opcode) * 4;
 
-        self.memory.step(cycles);
-        cycles
+        // Internal M-cycles at the end of the instruction
+        self.memory.step(cycles - self.ticked);
+        cycles as u32 + self.dma_stall()
     }
 
+    // The CPU does nothing while the VRAM DMA copies data, but the rest of the hardware keeps running
+    fn dma_stall(&mut self) -> u32 {
+        let mut total = 0;
+        loop {
+            let cycles = self.memory.take_dma_cycles();
+            if cycles == 0 {
+                break;
+            }
+            for _ in 0..cycles / 4 {
+                self.memory.step(4);
+            }
+            total += cycles;
+        }
+        total
+    }
 
-    fn handle_interrupt(&mut self) {
-        
-        
-        if (self.ime || self.halt)
-            && (self.memory.interrupt_flags & self.memory.interrupt_enable) != 0
-        {
-            self.halt = false;
 
-            if self.ime {
-                self.ime = false;
+    // Interrupts both requested (IF) and enabled (IE)
+    fn pending_interrupts(&self) -> u8 {
+        self.memory.interrupt_flags() & self.memory.interrupt_enable() & 0x1F
+    }
 
-                // Push the current PC onto the stack
-                self.push_stack(self.registers.pc);
+    /**
+     * Any pending interrupt ends HALT, it is serviced when IME is set
+     * Returns the cycles taken by the dispatch
+     * @see: https://gbdev.io/pandocs/Interrupts.html#interrupt-handling
+     */
+    fn handle_interrupt(&mut self) -> Option<u32> {
+        if self.pending_interrupts() == 0 {
+            return None;
+        }
+        self.halt = false;
+        if !self.ime {
+            return None;
+        }
 
-                let interrupt = self.memory.interrupt_flags & self.memory.interrupt_enable;
-                let n = interrupt.trailing_zeros() as u8;
+        self.ime = false;
+        self.ime_pending = false;
 
-                if n == 5 {
-                    panic!()
-                }
+        // 2 M-cycles are spent before the push of PC
+        self.tick();
+        self.tick();
 
+        let pc = self.registers.pc;
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write_byte(self.registers.sp, (pc >> 8) as u8);
 
-                self.memory.interrupt_flags &= !(1 << n);
-                let interrupt_vector = 0x0040 | (n as u16) << 3;
+        // The interrupt is picked after the high byte of PC is pushed: writing IE (SP = 0x0000) can cancel it
+        let interrupt = self.pending_interrupts();
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write_byte(self.registers.sp, pc as u8);
 
-                self.registers.pc = interrupt_vector;                
-            }
-        }
+        self.registers.pc = if interrupt == 0 {
+            0x0000
+        } else {
+            let n = interrupt.trailing_zeros();
+            let flags = self.memory.interrupt_flags();
+            self.memory.set_interrupt_flags(flags & !(1 << n));
+            0x0040 | (n as u16) << 3
+        };
+        self.tick();
+        Some(20)
     }
 
     fn call_opcode(&mut self, opcode: u8) -> u8 {
@@ -116,7 +248,7 @@ impl CPU {
                 3
             }
             0x02 => {
-                self.memory.write(self.registers.bc(), self.registers.a);
+                self.write_byte(self.registers.bc(), self.registers.a);
                 2
             }
             0x03 => {
@@ -142,7 +274,7 @@ impl CPU {
             }
             0x08 => {
                 let a = self.fetch_word();
-                self.memory.write_word(a, self.registers.sp);
+                self.write_word(a, self.registers.sp);
                 5
             }
             0x09 => {
@@ -150,7 +282,7 @@ impl CPU {
                 2
             }
             0x0A => {
-                self.registers.a = self.memory.read(self.registers.bc());
+                self.registers.a = self.read_byte(self.registers.bc());
                 2
             }
             0x0B => {
@@ -175,6 +307,10 @@ impl CPU {
                 1
             }
             0x10 => {
+                // STOP is followed by a padding byte
+                self.registers.pc = self.registers.pc.wrapping_add(1);
+                // CGB: STOP performs the speed switch prepared with KEY1
+                self.memory.switch_speed();
                 1
             } // STOP
             0x11 => {
@@ -183,7 +319,7 @@ impl CPU {
                 3
             }
             0x12 => {
-                self.memory.write(self.registers.de(), self.registers.a);
+                self.write_byte(self.registers.de(), self.registers.a);
                 2
             }
             0x13 => {
@@ -216,7 +352,7 @@ impl CPU {
                 2
             }
             0x1A => {
-                self.registers.a = self.memory.read(self.registers.de());
+                self.registers.a = self.read_byte(self.registers.de());
                 2
             }
             0x1B => {
@@ -245,7 +381,7 @@ impl CPU {
                     self.cpu_jr();
                     3
                 } else {
-                    self.registers.pc += 1;
+                    self.fetch_byte();
                     2
                 }
             }
@@ -255,7 +391,8 @@ impl CPU {
                 3
             }
             0x22 => {
-                self.memory.write(self.registers.hli(), self.registers.a);
+                let address = self.registers.hli();
+                self.write_byte(address, self.registers.a);
                 2
             }
             0x23 => {
@@ -284,7 +421,7 @@ impl CPU {
                     self.cpu_jr();
                     3
                 } else {
-                    self.registers.pc += 1;
+                    self.fetch_byte();
                     2
                 }
             }
@@ -294,7 +431,8 @@ impl CPU {
                 2
             }
             0x2A => {
-                self.registers.a = self.memory.read(self.registers.hli());
+                let address = self.registers.hli();
+                self.registers.a = self.read_byte(address);
                 2
             }
             0x2B => {
@@ -325,7 +463,7 @@ impl CPU {
                     self.cpu_jr();
                     3
                 } else {
-                    self.registers.pc += 1;
+                    self.fetch_byte();
                     2
                 }
             }
@@ -334,7 +472,8 @@ impl CPU {
                 3
             }
             0x32 => {
-                self.memory.write(self.registers.hld(), self.registers.a);
+                let address = self.registers.hld();
+                self.write_byte(address, self.registers.a);
                 2
             }
             0x33 => {
@@ -343,21 +482,21 @@ impl CPU {
             }
             0x34 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a);
+                let v = self.read_byte(a);
                 let v2 = self.alu_inc(v);
-                self.memory.write(a, v2);
+                self.write_byte(a, v2);
                 3
             }
             0x35 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a);
+                let v = self.read_byte(a);
                 let v2 = self.alu_dec(v);
-                self.memory.write(a, v2);
+                self.write_byte(a, v2);
                 3
             }
             0x36 => {
                 let v = self.fetch_byte();
-                self.memory.write(self.registers.hl(), v);
+                self.write_byte(self.registers.hl(), v);
                 3
             }
             0x37 => {
@@ -371,7 +510,7 @@ impl CPU {
                     self.cpu_jr();
                     3
                 } else {
-                    self.registers.pc += 1;
+                    self.fetch_byte();
                     2
                 }
             }
@@ -380,7 +519,8 @@ impl CPU {
                 2
             }
             0x3A => {
-                self.registers.a = self.memory.read(self.registers.hld());
+                let address = self.registers.hld();
+                self.registers.a = self.read_byte(address);
                 2
             }
             0x3B => {
@@ -428,7 +568,7 @@ impl CPU {
                 1
             }
             0x46 => {
-                self.registers.b = self.memory.read(self.registers.hl());
+                self.registers.b = self.read_byte(self.registers.hl());
                 2
             }
             0x47 => {
@@ -457,7 +597,7 @@ impl CPU {
                 1
             }
             0x4E => {
-                self.registers.c = self.memory.read(self.registers.hl());
+                self.registers.c = self.read_byte(self.registers.hl());
                 2
             }
             0x4F => {
@@ -486,7 +626,7 @@ impl CPU {
                 1
             }
             0x56 => {
-                self.registers.d = self.memory.read(self.registers.hl());
+                self.registers.d = self.read_byte(self.registers.hl());
                 2
             }
             0x57 => {
@@ -515,7 +655,7 @@ impl CPU {
                 1
             }
             0x5E => {
-                self.registers.e = self.memory.read(self.registers.hl());
+                self.registers.e = self.read_byte(self.registers.hl());
                 2
             }
             0x5F => {
@@ -544,7 +684,7 @@ impl CPU {
                 1
             }
             0x66 => {
-                self.registers.h = self.memory.read(self.registers.hl());
+                self.registers.h = self.read_byte(self.registers.hl());
                 2
             }
             0x67 => {
@@ -573,7 +713,7 @@ impl CPU {
             }
             0x6D => 1,
             0x6E => {
-                self.registers.l = self.memory.read(self.registers.hl());
+                self.registers.l = self.read_byte(self.registers.hl());
                 2
             }
             0x6F => {
@@ -581,35 +721,40 @@ impl CPU {
                 1
             }
             0x70 => {
-                self.memory.write(self.registers.hl(), self.registers.b);
+                self.write_byte(self.registers.hl(), self.registers.b);
                 2
             }
             0x71 => {
-                self.memory.write(self.registers.hl(), self.registers.c);
+                self.write_byte(self.registers.hl(), self.registers.c);
                 2
             }
             0x72 => {
-                self.memory.write(self.registers.hl(), self.registers.d);
+                self.write_byte(self.registers.hl(), self.registers.d);
                 2
             }
             0x73 => {
-                self.memory.write(self.registers.hl(), self.registers.e);
+                self.write_byte(self.registers.hl(), self.registers.e);
                 2
             }
             0x74 => {
-                self.memory.write(self.registers.hl(), self.registers.h);
+                self.write_byte(self.registers.hl(), self.registers.h);
                 2
             }
             0x75 => {
-                self.memory.write(self.registers.hl(), self.registers.l);
+                self.write_byte(self.registers.hl(), self.registers.l);
                 2
             }
             0x76 => {
-                self.halt = true;
+                // HALT bug: with IME off and an interrupt already pending, the CPU does not halt and reads the next byte twice
+                if !self.ime && self.pending_interrupts() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.halt = true;
+                }
                 1
             }
             0x77 => {
-                self.memory.write(self.registers.hl(), self.registers.a);
+                self.write_byte(self.registers.hl(), self.registers.a);
                 2
             }
             0x78 => {
@@ -637,7 +782,7 @@ impl CPU {
                 1
             }
             0x7E => {
-                self.registers.a = self.memory.read(self.registers.hl());
+                self.registers.a = self.read_byte(self.registers.hl());
                 2
             }
             0x7F => 1,
@@ -666,7 +811,7 @@ impl CPU {
                 1
             }
             0x86 => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_add(v, false);
                 2
             }
@@ -699,7 +844,7 @@ impl CPU {
                 1
             }
             0x8E => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_add(v, true);
                 2
             }
@@ -732,7 +877,7 @@ impl CPU {
                 1
             }
             0x96 => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_sub(v, false);
                 2
             }
@@ -765,7 +910,7 @@ impl CPU {
                 1
             }
             0x9E => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_sub(v, true);
                 2
             }
@@ -798,7 +943,7 @@ impl CPU {
                 1
             }
             0xA6 => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_and(v);
                 2
             }
@@ -831,7 +976,7 @@ impl CPU {
                 1
             }
             0xAE => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_xor(v);
                 2
             }
@@ -864,7 +1009,7 @@ impl CPU {
                 1
             }
             0xB6 => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_or(v);
                 2
             }
@@ -897,7 +1042,7 @@ impl CPU {
                 1
             }
             0xBE => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_cp(v);
                 2
             }
@@ -906,6 +1051,8 @@ impl CPU {
                 1
             }
             0xC0 => {
+                // The condition is checked during an M-cycle without bus access
+                self.tick();
                 if !self.registers.get_flag(Flag::Zero) {
                     self.registers.pc = self.pop_stack();
                     5
@@ -923,7 +1070,7 @@ impl CPU {
                     self.registers.pc = self.fetch_word();
                     4
                 } else {
-                    self.registers.pc += 2;
+                    self.fetch_word();
                     3
                 }
             }
@@ -933,11 +1080,12 @@ impl CPU {
             }
             0xC4 => {
                 if !self.registers.get_flag(Flag::Zero) {
-                    self.push_stack(self.registers.pc + 2);
-                    self.registers.pc = self.fetch_word();
+                    let address = self.fetch_word();
+                    self.push_stack(self.registers.pc);
+                    self.registers.pc = address;
                     6
                 } else {
-                    self.registers.pc += 2;
+                    self.fetch_word();
                     3
                 }
             }
@@ -956,6 +1104,7 @@ impl CPU {
                 4
             }
             0xC8 => {
+                self.tick();
                 if self.registers.get_flag(Flag::Zero) {
                     self.registers.pc = self.pop_stack();
                     5
@@ -972,24 +1121,26 @@ impl CPU {
                     self.registers.pc = self.fetch_word();
                     4
                 } else {
-                    self.registers.pc += 2;
+                    self.fetch_word();
                     3
                 }
             }
             0xCB => self.call_cb(),
             0xCC => {
                 if self.registers.get_flag(Flag::Zero) {
-                    self.push_stack(self.registers.pc + 2);
-                    self.registers.pc = self.fetch_word();
+                    let address = self.fetch_word();
+                    self.push_stack(self.registers.pc);
+                    self.registers.pc = address;
                     6
                 } else {
-                    self.registers.pc += 2;
+                    self.fetch_word();
                     3
                 }
             }
             0xCD => {
-                self.push_stack(self.registers.pc + 2);
-                self.registers.pc = self.fetch_word();
+                let address = self.fetch_word();
+                self.push_stack(self.registers.pc);
+                self.registers.pc = address;
                 6
             }
             0xCE => {
@@ -1003,6 +1154,7 @@ impl CPU {
                 4
             }
             0xD0 => {
+                self.tick();
                 if !self.registers.get_flag(Flag::Carry) {
                     self.registers.pc = self.pop_stack();
                     5
@@ -1020,17 +1172,18 @@ impl CPU {
                     self.registers.pc = self.fetch_word();
                     4
                 } else {
-                    self.registers.pc += 2;
+                    self.fetch_word();
                     3
                 }
             }
             0xD4 => {
                 if !self.registers.get_flag(Flag::Carry) {
-                    self.push_stack(self.registers.pc + 2);
-                    self.registers.pc = self.fetch_word();
+                    let address = self.fetch_word();
+                    self.push_stack(self.registers.pc);
+                    self.registers.pc = address;
                     6
                 } else {
-                    self.registers.pc += 2;
+                    self.fetch_word();
                     3
                 }
             }
@@ -1049,6 +1202,7 @@ impl CPU {
                 4
             }
             0xD8 => {
+                self.tick();
                 if self.registers.get_flag(Flag::Carry) {
                     self.registers.pc = self.pop_stack();
                     5
@@ -1066,17 +1220,18 @@ impl CPU {
                     self.registers.pc = self.fetch_word();
                     4
                 } else {
-                    self.registers.pc += 2;
+                    self.fetch_word();
                     3
                 }
             }
             0xDC => {
                 if self.registers.get_flag(Flag::Carry) {
-                    self.push_stack(self.registers.pc + 2);
-                    self.registers.pc = self.fetch_word();
+                    let address = self.fetch_word();
+                    self.push_stack(self.registers.pc);
+                    self.registers.pc = address;
                     6
                 } else {
-                    self.registers.pc += 2;
+                    self.fetch_word();
                     3
                 }
             }
@@ -1092,7 +1247,7 @@ impl CPU {
             }
             0xE0 => {
                 let a = 0xFF00 | self.fetch_byte() as u16;
-                self.memory.write(a, self.registers.a);
+                self.write_byte(a, self.registers.a);
                 3
             }
             0xE1 => {
@@ -1129,7 +1284,7 @@ impl CPU {
             }
             0xEA => {
                 let a = self.fetch_word();
-                self.memory.write(a, self.registers.a);
+                self.write_byte(a, self.registers.a);
                 4
             }
             0xEE => {
@@ -1144,7 +1299,7 @@ impl CPU {
             }
             0xF0 => {
                 let a = 0xFF00 | self.fetch_byte() as u16;
-                self.registers.a = self.memory.read(a);
+                self.registers.a = self.read_byte(a);
                 3
             }
             0xF1 => {
@@ -1153,11 +1308,12 @@ impl CPU {
                 3
             }
             0xF2 => {
-                self.registers.a = self.memory.read(0xFF00 | self.registers.c as u16);
+                self.registers.a = self.read_byte(0xFF00 | self.registers.c as u16);
                 2
             }
             0xF3 => {
                 self.ime = false;
+                self.ime_pending = false;
                 1
             }
             0xF5 => {
@@ -1185,11 +1341,11 @@ impl CPU {
             }
             0xFA => {
                 let a = self.fetch_word();
-                self.registers.a = self.memory.read(a);
+                self.registers.a = self.read_byte(a);
                 4
             }
             0xFB => {
-                self.ime = true;
+                self.ime_pending = true;
                 1
             }
             0xFE => {
@@ -1202,7 +1358,14 @@ impl CPU {
                 self.registers.pc = 0x38;
                 4
             }
-            other => panic!("Instruction {:2X} is not implemented", other),
+            // Illegal opcodes hang the CPU
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+                self.lockup = Some(Lockup {
+                    pc: self.registers.pc.wrapping_sub(1),
+                    opcode,
+                });
+                1
+            }
         }
     }
 
@@ -1235,9 +1398,9 @@ impl CPU {
             }
             0x06 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a);
+                let v = self.read_byte(a);
                 let v2 = self.alu_rlc(v);
-                self.memory.write(a, v2);
+                self.write_byte(a, v2);
                 4
             }
             0x07 => {
@@ -1270,9 +1433,9 @@ impl CPU {
             }
             0x0E => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a);
+                let v = self.read_byte(a);
                 let v2 = self.alu_rrc(v);
-                self.memory.write(a, v2);
+                self.write_byte(a, v2);
                 4
             }
             0x0F => {
@@ -1305,9 +1468,9 @@ impl CPU {
             }
             0x16 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a);
+                let v = self.read_byte(a);
                 let v2 = self.alu_rl(v);
-                self.memory.write(a, v2);
+                self.write_byte(a, v2);
                 4
             }
             0x17 => {
@@ -1340,9 +1503,9 @@ impl CPU {
             }
             0x1E => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a);
+                let v = self.read_byte(a);
                 let v2 = self.alu_rr(v);
-                self.memory.write(a, v2);
+                self.write_byte(a, v2);
                 4
             }
             0x1F => {
@@ -1375,9 +1538,9 @@ impl CPU {
             }
             0x26 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a);
+                let v = self.read_byte(a);
                 let v2 = self.alu_sla(v);
-                self.memory.write(a, v2);
+                self.write_byte(a, v2);
                 4
             }
             0x27 => {
@@ -1410,9 +1573,9 @@ impl CPU {
             }
             0x2E => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a);
+                let v = self.read_byte(a);
                 let v2 = self.alu_sra(v);
-                self.memory.write(a, v2);
+                self.write_byte(a, v2);
                 4
             }
             0x2F => {
@@ -1445,9 +1608,9 @@ impl CPU {
             }
             0x36 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a);
+                let v = self.read_byte(a);
                 let v2 = self.alu_swap(v);
-                self.memory.write(a, v2);
+                self.write_byte(a, v2);
                 4
             }
             0x37 => {
@@ -1480,9 +1643,9 @@ impl CPU {
             }
             0x3E => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a);
+                let v = self.read_byte(a);
                 let v2 = self.alu_srl(v);
-                self.memory.write(a, v2);
+                self.write_byte(a, v2);
                 4
             }
             0x3F => {
@@ -1514,7 +1677,7 @@ impl CPU {
                 2
             }
             0x46 => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_bit(v, 0);
                 3
             }
@@ -1547,7 +1710,7 @@ impl CPU {
                 2
             }
             0x4E => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_bit(v, 1);
                 3
             }
@@ -1580,7 +1743,7 @@ impl CPU {
                 2
             }
             0x56 => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_bit(v, 2);
                 3
             }
@@ -1613,7 +1776,7 @@ impl CPU {
                 2
             }
             0x5E => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_bit(v, 3);
                 3
             }
@@ -1646,7 +1809,7 @@ impl CPU {
                 2
             }
             0x66 => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_bit(v, 4);
                 3
             }
@@ -1679,7 +1842,7 @@ impl CPU {
                 2
             }
             0x6E => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_bit(v, 5);
                 3
             }
@@ -1712,7 +1875,7 @@ impl CPU {
                 2
             }
             0x76 => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_bit(v, 6);
                 3
             }
@@ -1745,7 +1908,7 @@ impl CPU {
                 2
             }
             0x7E => {
-                let v = self.memory.read(self.registers.hl());
+                let v = self.read_byte(self.registers.hl());
                 self.alu_bit(v, 7);
                 3
             }
@@ -1779,8 +1942,8 @@ impl CPU {
             }
             0x86 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) & !(1 << 0);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) & !(1 << 0);
+                self.write_byte(a, v);
                 4
             }
             0x87 => {
@@ -1813,8 +1976,8 @@ impl CPU {
             }
             0x8E => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) & !(1 << 1);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) & !(1 << 1);
+                self.write_byte(a, v);
                 4
             }
             0x8F => {
@@ -1847,8 +2010,8 @@ impl CPU {
             }
             0x96 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) & !(1 << 2);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) & !(1 << 2);
+                self.write_byte(a, v);
                 4
             }
             0x97 => {
@@ -1881,8 +2044,8 @@ impl CPU {
             }
             0x9E => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) & !(1 << 3);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) & !(1 << 3);
+                self.write_byte(a, v);
                 4
             }
             0x9F => {
@@ -1915,8 +2078,8 @@ impl CPU {
             }
             0xA6 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) & !(1 << 4);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) & !(1 << 4);
+                self.write_byte(a, v);
                 4
             }
             0xA7 => {
@@ -1949,8 +2112,8 @@ impl CPU {
             }
             0xAE => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) & !(1 << 5);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) & !(1 << 5);
+                self.write_byte(a, v);
                 4
             }
             0xAF => {
@@ -1983,8 +2146,8 @@ impl CPU {
             }
             0xB6 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) & !(1 << 6);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) & !(1 << 6);
+                self.write_byte(a, v);
                 4
             }
             0xB7 => {
@@ -2017,8 +2180,8 @@ impl CPU {
             }
             0xBE => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) & !(1 << 7);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) & !(1 << 7);
+                self.write_byte(a, v);
                 4
             }
             0xBF => {
@@ -2051,8 +2214,8 @@ impl CPU {
             }
             0xC6 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) | (1 << 0);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) | (1 << 0);
+                self.write_byte(a, v);
                 4
             }
             0xC7 => {
@@ -2085,8 +2248,8 @@ impl CPU {
             }
             0xCE => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) | (1 << 1);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) | (1 << 1);
+                self.write_byte(a, v);
                 4
             }
             0xCF => {
@@ -2119,8 +2282,8 @@ impl CPU {
             }
             0xD6 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) | (1 << 2);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) | (1 << 2);
+                self.write_byte(a, v);
                 4
             }
             0xD7 => {
@@ -2153,8 +2316,8 @@ impl CPU {
             }
             0xDE => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) | (1 << 3);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) | (1 << 3);
+                self.write_byte(a, v);
                 4
             }
             0xDF => {
@@ -2187,8 +2350,8 @@ impl CPU {
             }
             0xE6 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) | (1 << 4);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) | (1 << 4);
+                self.write_byte(a, v);
                 4
             }
             0xE7 => {
@@ -2221,8 +2384,8 @@ impl CPU {
             }
             0xEE => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) | (1 << 5);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) | (1 << 5);
+                self.write_byte(a, v);
                 4
             }
             0xEF => {
@@ -2255,8 +2418,8 @@ impl CPU {
             }
             0xF6 => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) | (1 << 6);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) | (1 << 6);
+                self.write_byte(a, v);
                 4
             }
             0xF7 => {
@@ -2289,8 +2452,8 @@ impl CPU {
             }
             0xFE => {
                 let a = self.registers.hl();
-                let v = self.memory.read(a) | (1 << 7);
-                self.memory.write(a, v);
+                let v = self.read_byte(a) | (1 << 7);
+                self.write_byte(a, v);
                 4
             }
             0xFF => {
@@ -2506,3 +2669,98 @@ impl CPU {
         self.registers.pc = ((self.registers.pc as u32 as i32) + (n as i32)) as u16;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu_with_program(program: &[u8]) -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        let mbc = crate::mbc::from_rom(&rom).unwrap();
+        CPU::new(mbc, GBMode::DMG, None)
+    }
+
+    #[test]
+    fn test_ei_delay_and_dispatch() {
+        // EI, NOP, NOP
+        let mut cpu = cpu_with_program(&[0xFB, 0x00, 0x00]);
+        cpu.memory.write(0xFFFF, 0x04);
+        cpu.memory.write(0xFF0F, 0x04);
+        let sp = cpu.registers.sp;
+
+        cpu.step();
+        assert!(!cpu.ime);
+        // The NOP after EI runs before the interrupt
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0x102);
+
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.registers.pc, 0x50);
+        assert_eq!(cpu.registers.sp, sp.wrapping_sub(2));
+        assert_eq!(cpu.memory.read_word(cpu.registers.sp), 0x102);
+        assert_eq!(cpu.memory.read(0xFF0F) & 0x04, 0);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn test_access_timing() {
+        // NOP, NOP, LDH A,(0x05): TIMA is read on the third M-cycle of LDH
+        let mut cpu = cpu_with_program(&[0x00, 0x00, 0xF0, 0x05]);
+        cpu.memory.write(0xFF07, 0x05);
+        cpu.memory.write(0xFF04, 0x00);
+        cpu.memory.write(0xFF05, 0x00);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.step(), 12);
+        // The timer ran for the fetch and the operand: 16 cycles since DIV was reset
+        assert_eq!(cpu.registers.a, 0x01);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        // HALT, INC A, NOP
+        let mut cpu = cpu_with_program(&[0x76, 0x3C, 0x00]);
+        cpu.memory.write(0xFFFF, 0x01);
+        cpu.memory.write(0xFF0F, 0x01);
+        let a = cpu.registers.a;
+
+        cpu.step();
+        assert!(!cpu.halt);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.a, a.wrapping_add(2));
+        assert_eq!(cpu.registers.pc, 0x102);
+    }
+
+    #[test]
+    fn test_illegal_opcode_lockup() {
+        let mut cpu = cpu_with_program(&[0x00, 0xD3, 0x00]);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.lockup, Some(Lockup { pc: 0x101, opcode: 0xD3 }));
+
+        // Even an interrupt does not get the CPU out
+        cpu.memory.write(0xFFFF, 0x04);
+        cpu.memory.write(0xFF0F, 0x04);
+        cpu.ime = true;
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.registers.pc, 0x102);
+    }
+
+    #[test]
+    fn test_dispatch_cancelled_by_ie_write() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.memory.write(0xFFFF, 0x04);
+        cpu.memory.write(0xFF0F, 0x04);
+        cpu.ime = true;
+
+        // The high byte of PC is pushed to IE, no interrupt is enabled anymore
+        cpu.registers.pc = 0x0020;
+        cpu.registers.sp = 0x0000;
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.registers.pc, 0x0000);
+        assert_eq!(cpu.memory.interrupt_enable, 0x00);
+        assert_eq!(cpu.memory.read(0xFF0F) & 0x04, 0x04);
+    }
+}
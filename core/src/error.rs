@@ -0,0 +1,104 @@
+use std::fmt;
+
+use crate::gameboy::Model;
+
+/// Errors returned by the public API of the emulator
+#[derive(Debug)]
+pub enum Error {
+    // Reading or writing a file failed
+    Io(std::io::Error),
+
+    // The ROM is too small to hold a cartridge header (0x0100-0x014F)
+    InvalidHeader,
+
+    // The header checksum (0x014D) does not match the header, see `skip_checksum`
+    BadChecksum { expected: u8, computed: u8 },
+
+    // Cartridge type (0x0147) whose MBC is not emulated
+    UnsupportedCartridge(u8),
+
+    // Unknown ROM size code (0x0148)
+    InvalidRomSize(u8),
+
+    // Unknown RAM size code (0x0149)
+    InvalidRamSize(u8),
+
+    // The length of the ROM does not match the ROM size of the header
+    RomSizeMismatch { expected: usize, actual: usize },
+
+    // The boot ROM is neither a DMG (256 bytes) nor a CGB (2304 bytes) boot ROM, or can't run on the model
+    InvalidBootRom { size: usize, model: Option<Model> },
+
+    // The save data of the cartridge RAM does not have the size of the RAM
+    InvalidSaveSize { expected: usize, actual: usize },
+
+    // The data is not a save state of this emulator
+    NotAState,
+
+    // Save state written by another version of the emulator
+    StateVersion { expected: u16, found: u16 },
+
+    // Save state made with another ROM
+    StateRomMismatch,
+
+    // Truncated or corrupted save state
+    InvalidState(String),
+
+    // Well-formed save state relying on something that is not emulated (BESS blocks, SGB...)
+    UnsupportedState(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{}", error),
+            Error::InvalidHeader => write!(f, "The ROM is too small to hold a cartridge header"),
+            Error::BadChecksum { expected, computed } => write!(
+                f,
+                "Invalid header checksum: expected {:02x}, computed {:02x}",
+                expected, computed
+            ),
+            Error::UnsupportedCartridge(value) => write!(f, "Unsupported cartridge type: {:02x}", value),
+            Error::InvalidRomSize(value) => write!(f, "Invalid ROM size code: {:02x}", value),
+            Error::InvalidRamSize(value) => write!(f, "Invalid RAM size code: {:02x}", value),
+            Error::RomSizeMismatch { expected, actual } => write!(
+                f,
+                "The header announces a ROM of {} bytes, got {} bytes",
+                expected, actual
+            ),
+            Error::InvalidBootRom { size, model: Some(model) } => {
+                write!(f, "A boot ROM of {} bytes can't run on a {:?}", size, model)
+            }
+            Error::InvalidBootRom { size, model: None } => write!(f, "Invalid boot ROM size: {} bytes", size),
+            Error::InvalidSaveSize { expected, actual } => write!(
+                f,
+                "Invalid save size: expected {} bytes, got {}",
+                expected, actual
+            ),
+            Error::NotAState => write!(f, "Not a save state"),
+            Error::StateVersion { expected, found } => write!(
+                f,
+                "Unsupported save state version: {} (expected {})",
+                found, expected
+            ),
+            Error::StateRomMismatch => write!(f, "Save state was made with a different ROM"),
+            Error::InvalidState(message) => write!(f, "Invalid save state: {}", message),
+            Error::UnsupportedState(message) => write!(f, "Unsupported save state: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
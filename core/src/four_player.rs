@@ -0,0 +1,376 @@
+use std::sync::{Arc, Mutex};
+
+use crate::serial::LinkTransport;
+
+pub const MAX_PLAYERS: usize = 4;
+
+// Answers of the Gameboys to the ping packet header and to the first STAT byte
+const PING_HEADER: u8 = 0xFE;
+const ACK: u8 = 0x88;
+
+// Player 1 sends 4 times START to leave the ping phase, the adapter confirms with 4 times STARTED
+const START: u8 = 0xAA;
+const STARTED: u8 = 0xCC;
+// Player 1 sends 4 times STOP during the transmission phase to go back to the ping phase
+const STOP: u8 = 0xFF;
+
+// The adapter clocks a byte about every millisecond during the ping phase
+const PING_BYTE_CYCLES: u64 = 4096;
+// During the transmission phase, the lower nibble of RATE slows the transfers down
+const TRANSMISSION_BYTE_CYCLES: u64 = 2048;
+const RATE_STEP_CYCLES: u64 = 256;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Phase {
+    // Packets of 4 bytes: header and 3 STAT bytes with the player number and the connected players
+    Ping,
+    // Bytes of 0xCC left to send before the transmission phase
+    Starting(u8),
+    // Packets of 4 * SIZE bytes: the data sent by each player during the previous packet
+    Transmission,
+}
+
+struct AdapterState {
+    phase: Phase,
+    // Index of the current byte in the packet
+    index: usize,
+
+    // Time of the current byte, the byte is exchanged with each Gameboy when it reaches it
+    byte_at: u64,
+    output: [u8; MAX_PLAYERS],
+    input: [u8; MAX_PLAYERS],
+    delivered: [bool; MAX_PLAYERS],
+    plugged: [bool; MAX_PLAYERS],
+
+    // Players that answered the last ping
+    connected: [bool; MAX_PLAYERS],
+    // Set by player 1 during the ping phase: speed of the transfers and bytes sent per player in a packet
+    rate: u8,
+    size: usize,
+
+    // Data received from each player during the current and the previous packet
+    packets: [Vec<u8>; MAX_PLAYERS],
+    previous_packets: [Vec<u8>; MAX_PLAYERS],
+    // Consecutive bytes of START or STOP sent by player 1
+    command_count: usize,
+}
+
+impl AdapterState {
+    fn new() -> Self {
+        let mut state = AdapterState {
+            phase: Phase::Ping,
+            index: 0,
+            byte_at: PING_BYTE_CYCLES,
+            output: [0; MAX_PLAYERS],
+            input: [0xFF; MAX_PLAYERS],
+            delivered: [false; MAX_PLAYERS],
+            plugged: [false; MAX_PLAYERS],
+            connected: [false; MAX_PLAYERS],
+            rate: 0,
+            size: 1,
+            packets: Default::default(),
+            previous_packets: Default::default(),
+            command_count: 0,
+        };
+        state.prepare_byte();
+        state
+    }
+
+    fn connected_flags(&self) -> u8 {
+        self.connected.iter().enumerate().fold(0, |flags, (player, &connected)| flags | (connected as u8) << (4 + player))
+    }
+
+    /// Bytes sent to each Gameboy for the current index
+    fn prepare_byte(&mut self) {
+        for player in 0..MAX_PLAYERS {
+            self.output[player] = match self.phase {
+                Phase::Ping if self.index == 0 => PING_HEADER,
+                Phase::Ping => self.connected_flags() | (player as u8 + 1),
+                Phase::Starting(_) => STARTED,
+                Phase::Transmission => {
+                    let source = self.index / self.size;
+                    self.previous_packets[source].get(self.index % self.size).copied().unwrap_or(0xFF)
+                }
+            };
+        }
+    }
+
+    /// Every plugged Gameboy exchanged the current byte
+    fn end_byte(&mut self) {
+        let input = self.input;
+        let phase = self.phase;
+        let (length, interval) = match self.phase {
+            Phase::Ping => {
+                self.end_ping_byte(input);
+                (4, PING_BYTE_CYCLES)
+            }
+            Phase::Starting(remaining) => {
+                self.phase = if remaining > 1 { Phase::Starting(remaining - 1) } else { Phase::Transmission };
+                (1, PING_BYTE_CYCLES)
+            }
+            Phase::Transmission => {
+                self.end_transmission_byte(input);
+                (MAX_PLAYERS * self.size, TRANSMISSION_BYTE_CYCLES + (self.rate & 0x0F) as u64 * RATE_STEP_CYCLES)
+            }
+        };
+
+        self.index = if self.phase == phase { (self.index + 1) % length } else { 0 };
+        if self.phase == Phase::Transmission && self.index == 0 {
+            self.previous_packets = std::mem::take(&mut self.packets);
+        }
+
+        self.byte_at += interval;
+        self.delivered = [false; MAX_PLAYERS];
+        self.input = [0xFF; MAX_PLAYERS];
+        self.prepare_byte();
+    }
+
+    fn end_ping_byte(&mut self, input: [u8; MAX_PLAYERS]) {
+        // The START bytes of player 1 neither disconnect it nor change the settings
+        let starting = input[0] == START;
+        match self.index {
+            0 => {
+                for (player, connected) in self.connected.iter_mut().enumerate() {
+                    if !(player == 0 && starting) {
+                        *connected = input[player] == ACK;
+                    }
+                }
+            }
+            2 if !starting => self.rate = input[0],
+            3 if !starting => self.size = (input[0] as usize).clamp(1, 4),
+            _ => {}
+        }
+
+        self.command_count = if starting { self.command_count + 1 } else { 0 };
+        if self.command_count == 4 {
+            self.command_count = 0;
+            self.phase = Phase::Starting(4);
+            self.packets = Default::default();
+            self.previous_packets = Default::default();
+        }
+    }
+
+    fn end_transmission_byte(&mut self, input: [u8; MAX_PLAYERS]) {
+        // Each player sends its data at the start of the packet, the rest of its bytes are ignored
+        if self.index < self.size {
+            for (player, packet) in self.packets.iter_mut().enumerate() {
+                packet.push(if self.connected[player] { input[player] } else { 0xFF });
+            }
+
+            self.command_count = if input[0] == STOP { self.command_count + 1 } else { 0 };
+            if self.command_count == 4 {
+                self.command_count = 0;
+                self.phase = Phase::Ping;
+            }
+        }
+    }
+}
+
+/**
+ * DMG-07 adapter linking up to four Gameboys. The adapter drives the clock of every Gameboy:
+ * it pings them until player 1 starts the game, then sends to everyone the data of all players.
+ * The Gameboys must be run with `Gameboy::run_linked_frame` to stay in sync.
+ * @see: https://gbdev.io/pandocs/Four_Player_Adapter.html
+ */
+pub struct FourPlayerAdapter {
+    state: Arc<Mutex<AdapterState>>,
+}
+
+impl FourPlayerAdapter {
+    pub fn new() -> Self {
+        FourPlayerAdapter {
+            state: Arc::new(Mutex::new(AdapterState::new())),
+        }
+    }
+
+    /// Cable of player `player` (0 to 3), to plug in a Gameboy with `Gameboy::connect_link`
+    /// None if the port does not exist or is already in use
+    pub fn port(&self, player: usize) -> Option<FourPlayerPort> {
+        let mut state = self.state.lock().unwrap();
+        if player >= MAX_PLAYERS || state.plugged[player] {
+            return None;
+        }
+        state.plugged[player] = true;
+        Some(FourPlayerPort {
+            player,
+            state: self.state.clone(),
+            elapsed: 0,
+            last_cycle: None,
+        })
+    }
+
+    /// Players that answered the last ping
+    pub fn connected_players(&self) -> [bool; MAX_PLAYERS] {
+        self.state.lock().unwrap().connected
+    }
+
+    /// Set once player 1 started the game
+    pub fn transmitting(&self) -> bool {
+        self.state.lock().unwrap().phase == Phase::Transmission
+    }
+}
+
+impl Default for FourPlayerAdapter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One of the 4 cables of the adapter
+pub struct FourPlayerPort {
+    player: usize,
+    state: Arc<Mutex<AdapterState>>,
+    // Cycles run by the Gameboy since its first poll
+    elapsed: u64,
+    last_cycle: Option<u64>,
+}
+
+impl LinkTransport for FourPlayerPort {
+    // The adapter drives the clock, nothing answers a Gameboy that uses its internal clock
+    fn transfer(&mut self, _byte: u8, _cycle: u64) -> u8 {
+        0xFF
+    }
+
+    fn poll(&mut self, ready: Option<u8>, cycle: u64) -> Option<u8> {
+        if let Some(last_cycle) = self.last_cycle {
+            self.elapsed += cycle.saturating_sub(last_cycle);
+        }
+        self.last_cycle = Some(cycle);
+
+        let mut state = self.state.lock().unwrap();
+        if state.delivered[self.player] || self.elapsed < state.byte_at {
+            return None;
+        }
+
+        // A Gameboy that is not waiting for the clock misses the byte
+        let byte = state.output[self.player];
+        state.delivered[self.player] = true;
+        state.input[self.player] = ready.unwrap_or(0xFF);
+        if (0..MAX_PLAYERS).all(|player| state.delivered[player] || !state.plugged[player]) {
+            state.end_byte();
+        }
+        ready.map(|_| byte)
+    }
+}
+
+impl Drop for FourPlayerPort {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.plugged[self.player] = false;
+        state.connected[self.player] = false;
+        if state.plugged.iter().any(|&plugged| plugged)
+            && (0..MAX_PLAYERS).all(|player| state.delivered[player] || !state.plugged[player])
+        {
+            state.end_byte();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Players {
+        ports: Vec<FourPlayerPort>,
+        cycle: u64,
+    }
+
+    impl Players {
+        fn new(adapter: &FourPlayerAdapter, count: usize) -> Self {
+            Players {
+                ports: (0..count).map(|player| adapter.port(player).unwrap()).collect(),
+                cycle: 0,
+            }
+        }
+
+        /// Run until every player exchanged a byte, returns the bytes received
+        fn exchange(&mut self, answers: &[u8]) -> Vec<u8> {
+            let mut received = vec![None; self.ports.len()];
+            while received.iter().any(|byte| byte.is_none()) {
+                self.cycle += 4;
+                for (player, port) in self.ports.iter_mut().enumerate() {
+                    if let Some(byte) = port.poll(Some(answers[player]), self.cycle) {
+                        received[player] = Some(byte);
+                    }
+                }
+            }
+            received.into_iter().map(|byte| byte.unwrap()).collect()
+        }
+    }
+
+    #[test]
+    fn test_gameboys_in_lockstep() {
+        use crate::gameboy::{Gameboy, Model};
+
+        // LD A,ACK / LDH (0x01),A / LD A,0x80 / LDH (0x02),A / JR -10: answer every ping
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x010A].copy_from_slice(&[0x3E, ACK, 0xE0, 0x01, 0x3E, 0x80, 0xE0, 0x02, 0x18, 0xF6]);
+        let adapter = FourPlayerAdapter::new();
+        let mut gameboys: Vec<Gameboy> = (0..2).map(|player| {
+            let mut gameboy = Gameboy::new_from_data(&rom, Some(Model::DMG), true).unwrap();
+            gameboy.connect_link(Box::new(adapter.port(player).unwrap()));
+            gameboy
+        }).collect();
+
+        let mut gameboys: Vec<&mut Gameboy> = gameboys.iter_mut().collect();
+        Gameboy::run_linked_frame(&mut gameboys);
+        assert_eq!(adapter.connected_players(), [true, true, false, false]);
+    }
+
+    #[test]
+    fn test_port() {
+        let adapter = FourPlayerAdapter::new();
+        let port = adapter.port(0);
+        assert!(port.is_some());
+        assert!(adapter.port(0).is_none());
+        assert!(adapter.port(MAX_PLAYERS).is_none());
+
+        // Unplugging the cable frees the port
+        drop(port);
+        assert!(adapter.port(0).is_some());
+    }
+
+    #[test]
+    fn test_ping() {
+        let adapter = FourPlayerAdapter::new();
+        let mut players = Players::new(&adapter, 2);
+        assert_eq!(players.exchange(&[ACK, ACK]), vec![PING_HEADER, PING_HEADER]);
+        assert_eq!(adapter.connected_players(), [true, true, false, false]);
+
+        // STAT bytes: connected players in the upper nibble, player number in the lower one
+        assert_eq!(players.exchange(&[ACK, ACK]), vec![0x31, 0x32]);
+        players.exchange(&[0x00, 0x00]);
+        players.exchange(&[0x02, 0x00]);
+        assert_eq!(players.exchange(&[0x00, 0x00]), vec![PING_HEADER, PING_HEADER]);
+        assert_eq!(adapter.connected_players(), [false; MAX_PLAYERS]);
+    }
+
+    #[test]
+    fn test_transmission() {
+        let adapter = FourPlayerAdapter::new();
+        let mut players = Players::new(&adapter, 3);
+        players.exchange(&[ACK, ACK, ACK]);
+        players.exchange(&[ACK, ACK, ACK]);
+        // Rate 0, one byte per player
+        players.exchange(&[0x00, 0x00, 0x00]);
+        players.exchange(&[0x01, 0x00, 0x00]);
+
+        for _ in 0..4 {
+            players.exchange(&[START, ACK, ACK]);
+        }
+        for _ in 0..4 {
+            assert_eq!(players.exchange(&[0x00, 0x00, 0x00]), vec![STARTED; 3]);
+        }
+        assert!(adapter.transmitting());
+
+        // First packet: each player sends its byte, nothing was received yet
+        players.exchange(&[0x10, 0x20, 0x30]);
+        for _ in 0..3 {
+            players.exchange(&[0x00, 0x00, 0x00]);
+        }
+
+        // Second packet: everyone gets the bytes of every player, 0xFF for the missing one
+        let packet: Vec<Vec<u8>> = (0..4).map(|_| players.exchange(&[0x00, 0x00, 0x00])).collect();
+        assert_eq!(packet, vec![vec![0x10; 3], vec![0x20; 3], vec![0x30; 3], vec![0xFF; 3]]);
+    }
+}
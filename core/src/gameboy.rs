@@ -1,36 +1,125 @@
 use std::io::Seek;
+use std::path::Path;
 
 use crate::cpu::CPU;
 use crate::gpu::SCREEN_SIZE_RGB;
 use crate::header::Header;
+use crate::registers::Registers;
+use crate::memory::{BOOT_ROM_SIZE, CGB_BOOT_ROM_SIZE};
 use crate::keypad::KeyEvent;
+use crate::rewind::Rewind;
+use crate::serial::LinkTransport;
+use crate::printer::{PrintedPage, Printer};
+use crate::mobile::{MobileAdapter, MobileNetwork};
+use crate::state::{StateReader, StateWriter, STATE_HEADER_SIZE, STATE_MAGIC, STATE_VERSION};
+use crate::Error;
 
 const FRAME_TIME: f64 = 1.0 / 60.0;
 const CYCLES_PER_SECOND: u32 = 4_194_304;
 const CYCLES_PER_FRAME: u32 = CYCLES_PER_SECOND / 60;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum GBMode {
     DMG,
     CGB,
 }
 
+/**
+ * Hardware model, games and test ROMs tell them apart with the registers left by the boot ROM
+ * @see: https://gbdev.io/pandocs/Power_Up_Sequence.html
+ */
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Model {
+    // Early Game Boy (DMG-CPU 0)
+    DMG0,
+    // Game Boy
+    DMG,
+    // Game Boy Pocket
+    MGB,
+    // Super Game Boy
+    SGB,
+    // Super Game Boy 2
+    SGB2,
+    // Game Boy Color
+    CGB,
+    // Game Boy Advance, in Game Boy Color mode
+    AGB,
+}
+
+impl Model {
+    /// The CGB features (colors, VRAM & WRAM banks, double speed...) are only available on these models
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::CGB | Model::AGB)
+    }
+}
+
+/// Illegal opcode that hung the CPU, see `Gameboy::lockup`
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Lockup {
+    // Address of the opcode
+    pub pc: u16,
+    pub opcode: u8,
+}
+
+/**
+ * How the PPU draws the screen
+ * @see: https://gbdev.io/pandocs/Rendering.html
+ */
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Renderer {
+    // Pixel FIFO run dot by dot: mid-scanline effects and the length of mode 3 are accurate
+    Fifo,
+    // Whole line drawn when HBlank begins, mode 3 always lasts 172 dots, faster
+    Scanline,
+}
+
 pub struct Gameboy {
     pub cpu: CPU,
     header: Header,
+    model: Model,
+    rewind: Rewind,
 }
 
 impl Gameboy {
-    fn new_abs(rom: &Vec<u8>, header: Header) -> Result<Gameboy, std::io::Error> {
-        match crate::mbc::from_rom(&rom) {
-            Ok(mbc) => Ok(Gameboy {
-                cpu: CPU::new(mbc),
-                header,
-            }),
-            Err(e) => {
-                return Err(e);
+    fn new_abs(rom: &Vec<u8>, header: Header, model: Option<Model>, boot_rom: Option<Vec<u8>>) -> Result<Gameboy, Error> {
+        // 0x80: CGB enhanced but DMG compatible, 0xC0: CGB only
+        let cgb_game = header.cgb_flag() & 0x80 != 0;
+
+        let model = match (model, boot_rom.as_ref().map(|boot_rom| boot_rom.len())) {
+            (Some(model), None) => model,
+            (None, None) if cgb_game => Model::CGB,
+            (None, None) => Model::DMG,
+            // The hardware must match the boot ROM
+            (None, Some(BOOT_ROM_SIZE)) => Model::DMG,
+            (None, Some(CGB_BOOT_ROM_SIZE)) => Model::CGB,
+            (Some(model), Some(BOOT_ROM_SIZE)) if !model.is_cgb() => model,
+            (Some(model), Some(CGB_BOOT_ROM_SIZE)) if model.is_cgb() => model,
+            (Some(model), Some(size @ (BOOT_ROM_SIZE | CGB_BOOT_ROM_SIZE))) => {
+                return Err(Error::InvalidBootRom { size, model: Some(model) });
             }
+            (_, Some(size)) => return Err(Error::InvalidBootRom { size, model: None }),
+        };
+
+        // The CGB boot ROM switches by itself to the DMG mode if needed
+        let gbmode = if model.is_cgb() && (cgb_game || boot_rom.is_some()) {
+            GBMode::CGB
+        } else {
+            GBMode::DMG
+        };
+
+        let has_boot_rom = boot_rom.is_some();
+        let mut cpu = CPU::new(crate::mbc::from_rom(rom)?, gbmode, boot_rom);
+        if !has_boot_rom {
+            cpu.registers = Registers::after_boot(model, gbmode, &rom[0x0100..0x0150]);
+            cpu.memory.init_model(model);
         }
+
+        Ok(Gameboy {
+            cpu,
+            header,
+            model,
+            rewind: Rewind::new(),
+        })
     }
 
     pub fn update_input(&mut self, event: KeyEvent) {
@@ -41,34 +130,145 @@ impl Gameboy {
     }
 
     pub fn run_frame(&mut self) {
+        self.run_frame_until(|_| false);
+    }
+
+    /// Run a frame, or stop before the first instruction for which `stop` returns true
+    pub(crate) fn run_frame_until<F: FnMut(&CPU) -> bool>(&mut self, mut stop: F) -> bool {
         let mut cycles = 0;
         while cycles < CYCLES_PER_FRAME {
-            cycles += self.cpu.step() as u32;
+            if stop(&self.cpu) {
+                return true;
+            }
+            // A frame lasts twice as many CPU cycles in double speed mode
+            let speed_factor = self.cpu.memory.speed_factor();
+            cycles += self.cpu.step() / speed_factor;
         }
+        self.end_frame();
+        false
     }
 
-    pub fn new_from_data(rom: &Vec<u8>, skip_checksum: bool) -> Result<Gameboy, std::io::Error> {
-        if rom.len() <= 0x0150 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid ROM size",
-            ));
+    /// Run a frame on Gameboys connected by link cables, interleaving their instructions
+    /// so that each transfer sees the other side as it is at the same moment
+    pub fn run_linked_frame(gameboys: &mut [&mut Gameboy]) {
+        let mut cycles = vec![0; gameboys.len()];
+        // Always run the Gameboy that is the most behind
+        while let Some((index, _)) = cycles.iter().enumerate()
+            .filter(|(_, &cycles)| cycles < CYCLES_PER_FRAME)
+            .min_by_key(|(_, &cycles)| cycles)
+        {
+            let gameboy = &mut gameboys[index];
+            let speed_factor = gameboy.cpu.memory.speed_factor();
+            cycles[index] += gameboy.cpu.step() / speed_factor;
+        }
+
+        for gameboy in gameboys.iter_mut() {
+            gameboy.end_frame();
         }
+    }
+
+    fn end_frame(&mut self) {
+        if self.rewind.enabled() {
+            let state = self.save_state();
+            self.rewind.push(state);
+        }
+    }
+
+    /// Plug a link cable in the serial port, the previous one is returned
+    pub fn connect_link(&mut self, transport: Box<dyn LinkTransport>) -> Option<Box<dyn LinkTransport>> {
+        self.cpu.memory.serial.connect(Some(transport))
+    }
+
+    /// Plug a Game Boy Printer in the serial port, `on_page` is called with each printed page
+    pub fn connect_printer<F: FnMut(&PrintedPage) + Send + 'static>(&mut self, on_page: F) {
+        self.connect_link(Box::new(Printer::new(Box::new(on_page))));
+    }
+
+    /// Plug a Mobile Adapter GB in the serial port, its calls and connections go through `network`
+    pub fn connect_mobile_adapter(&mut self, network: Box<dyn MobileNetwork>) {
+        self.connect_link(Box::new(MobileAdapter::new(network)));
+    }
+
+    /// Unplug the link cable, transfers started with the internal clock then read 0xFF
+    pub fn disconnect_link(&mut self) -> Option<Box<dyn LinkTransport>> {
+        self.cpu.memory.serial.connect(None)
+    }
+
+    /// Set when the game ran an illegal opcode: the CPU is hung until the next reset, frames are still produced
+    pub fn lockup(&self) -> Option<Lockup> {
+        self.cpu.lockup
+    }
 
-        let header = Header::load_rom(&rom[0x0100..=0x014F].try_into().map_err(|_| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Failed to convert ROM slice to header",
-            )
-        })?);
-        Gameboy::new_abs(rom, header)
+    /// Pick how the screen is drawn, the pixel FIFO is used by default
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.cpu.memory.gpu.set_renderer(renderer);
     }
 
-    pub fn new_from_file(file_path: &str, skip_checksum: bool) -> Result<Gameboy, std::io::Error> {
+    pub fn renderer(&self) -> Renderer {
+        self.cpu.memory.gpu.renderer()
+    }
+
+    /// Set the memory (in bytes) used to keep the states of the last frames, 0 disables the rewind
+    pub fn set_rewind_budget(&mut self, bytes: usize) {
+        self.rewind.set_budget(bytes);
+    }
+
+    /// Number of frames that can currently be rewound
+    pub fn rewind_frames(&self) -> usize {
+        self.rewind.frames()
+    }
+
+    /// Memory currently used by the rewind buffer, in bytes
+    pub fn rewind_size(&self) -> usize {
+        self.rewind.size()
+    }
+
+    /// Go back `frames` frames in time, or as far as the history allows
+    /// Returns the number of frames actually rewound
+    pub fn rewind(&mut self, frames: usize) -> Result<usize, Error> {
+        match self.rewind.rewind(frames) {
+            Some((state, rewound)) => {
+                self.load_state(&state)?;
+                Ok(rewound)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// When no model is given, the game runs on a CGB if it supports it, on a DMG otherwise
+    /// With `skip_checksum`, games with a wrong header checksum are accepted like on a Gameboy without boot ROM
+    pub fn new_from_data(rom: &Vec<u8>, model: Option<Model>, skip_checksum: bool) -> Result<Gameboy, Error> {
+        Gameboy::new_from_data_abs(rom, model, None, skip_checksum)
+    }
+
+    /// Start the Gameboy from a DMG/MGB/SGB (256 bytes) or CGB (2304 bytes) boot ROM instead of the post-boot state
+    pub fn new_from_data_with_boot_rom(rom: &Vec<u8>, boot_rom: &[u8], model: Option<Model>, skip_checksum: bool) -> Result<Gameboy, Error> {
+        Gameboy::new_from_data_abs(rom, model, Some(boot_rom.to_vec()), skip_checksum)
+    }
+
+    fn new_from_data_abs(rom: &Vec<u8>, model: Option<Model>, boot_rom: Option<Vec<u8>>, skip_checksum: bool) -> Result<Gameboy, Error> {
+        let header: &[u8; 0x50] = rom.get(0x0100..0x0150)
+            .and_then(|header| header.try_into().ok())
+            .ok_or(Error::InvalidHeader)?;
+        if !skip_checksum {
+            Header::check_checksum(header)?;
+        }
+        Gameboy::new_abs(rom, Header::load_rom(header), model, boot_rom)
+    }
+
+    pub fn new_from_file(file_path: &str, model: Option<Model>, skip_checksum: bool) -> Result<Gameboy, Error> {
+        let mut file = std::fs::File::open(file_path)?;
+        let header = Header::load_from_file(&mut file, skip_checksum)?;
+        let rom = std::fs::read(file_path)?;
+        Gameboy::new_abs(&rom, header, model, None)
+    }
+
+    pub fn new_from_file_with_boot_rom(file_path: &str, boot_rom_path: &str, model: Option<Model>, skip_checksum: bool) -> Result<Gameboy, Error> {
         let mut file = std::fs::File::open(file_path)?;
         let header = Header::load_from_file(&mut file, skip_checksum)?;
         let rom = std::fs::read(file_path)?;
-        Gameboy::new_abs(&rom, header)
+        let boot_rom = std::fs::read(boot_rom_path)?;
+        Gameboy::new_abs(&rom, header, model, Some(boot_rom))
     }
 
     /// Get the screen data
@@ -76,6 +276,129 @@ impl Gameboy {
         return self.cpu.memory.gpu.screen_data();
     }
 
+    /// Set the rate (in Hz) at which audio samples are produced, 44100 by default
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu.memory.apu.set_sample_rate(sample_rate);
+    }
+
+    /// Number of audio samples ready to be pulled
+    pub fn audio_samples_available(&self) -> usize {
+        self.cpu.memory.apu.samples_available()
+    }
+
+    /// Pull the audio samples produced so far into `buffer`
+    /// Samples are interleaved stereo (left, right) in the range -1.0..=1.0
+    /// Returns the number of samples written
+    pub fn pull_audio_samples(&mut self, buffer: &mut [f32]) -> usize {
+        self.cpu.memory.apu.pull_samples(buffer)
+    }
+
+    /// Get the battery-backed RAM of the cartridge (with the RTC state for clock carts)
+    /// Returns None if the cartridge has no battery
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
+        if !self.cpu.memory.mbc.has_battery() {
+            return None;
+        }
+        Some(self.cpu.memory.mbc.dump_ram())
+    }
+
+    /// Restore the battery-backed RAM of the cartridge from data produced by `save_ram`
+    pub fn load_ram(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.cpu.memory.mbc.load_ram(data)
+    }
+
+    /// Return true if the cartridge RAM was written since the last call
+    pub fn ram_updated(&mut self) -> bool {
+        self.cpu.memory.mbc.check_ram_updated()
+    }
+
+    /// Write the battery-backed RAM to a .sav file, does nothing if the cartridge has no battery
+    pub fn save_ram_to_file(&self, path: &Path) -> Result<(), Error> {
+        match self.save_ram() {
+            Some(data) => Ok(std::fs::write(path, data)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Load the battery-backed RAM from a .sav file
+    pub fn load_ram_from_file(&mut self, path: &Path) -> Result<(), Error> {
+        let data = std::fs::read(path)?;
+        self.load_ram(&data)
+    }
+
+    /// Snapshot of the whole machine (CPU, memory, video, audio, timer, cartridge...)
+    /// The state is tied to the loaded ROM through its header and global checksums
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.u8(STATE_MAGIC[0]);
+        state.u8(STATE_MAGIC[1]);
+        state.u8(STATE_MAGIC[2]);
+        state.u8(STATE_MAGIC[3]);
+        state.u16(STATE_VERSION);
+        state.u8(self.header.header_checksum());
+        state.u16(self.header.global_checksum());
+        self.cpu.save_state(&mut state);
+        state.into_vec()
+    }
+
+    /// Restore a snapshot produced by `save_state`
+    /// The current state is left untouched if the data is invalid or comes from another ROM
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut state = StateReader::new(data);
+        let magic = state.take(STATE_MAGIC.len()).map_err(|_| Error::NotAState)?;
+        if magic != STATE_MAGIC {
+            return Err(Error::NotAState);
+        }
+
+        let version = state.u16()?;
+        if version != STATE_VERSION {
+            return Err(Error::StateVersion { expected: STATE_VERSION, found: version });
+        }
+
+        let header_checksum = state.u8()?;
+        let global_checksum = state.u16()?;
+        if header_checksum != self.header.header_checksum() || global_checksum != self.header.global_checksum() {
+            return Err(Error::StateRomMismatch);
+        }
+
+        let backup = self.save_state();
+        let result = self.cpu.load_state(&mut state).and_then(|_| {
+            if state.is_empty() {
+                Ok(())
+            } else {
+                Err(Error::InvalidState("trailing data".to_string()))
+            }
+        });
+
+        if result.is_err() {
+            // Loading stopped halfway, go back to the state before the call
+            self.restore(&backup)?;
+        }
+        result
+    }
+
+    // Reload a snapshot taken by `save_state` after a failed load, only fails if the snapshot is broken
+    fn restore(&mut self, backup: &[u8]) -> Result<(), Error> {
+        let mut state = StateReader::new(&backup[STATE_HEADER_SIZE..]);
+        self.cpu.load_state(&mut state)
+    }
+
+    /// Export the machine in the BESS format shared with SameBoy, BGB...
+    pub fn save_bess_state(&self) -> Vec<u8> {
+        crate::bess::save(&self.cpu)
+    }
+
+    /// Import a BESS save state, blocks of hardware that is not emulated (SGB...) are skipped
+    /// The current state is left untouched if the import fails
+    pub fn load_bess_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        let backup = self.save_state();
+        let result = crate::bess::load(&mut self.cpu, data);
+        if result.is_err() {
+            self.restore(&backup)?;
+        }
+        result
+    }
+
     #[deprecated]
     pub fn save_vram(&self, path: &str) {
         use std::io::Write;
@@ -83,9 +406,169 @@ impl Gameboy {
         file.write_all(&self.cpu.memory.gpu.vram).unwrap();
     }
 
+    /// Get the mode (DMG or CGB) the Gameboy is running in
+    pub fn mode(&self) -> GBMode {
+        self.cpu.memory.mode()
+    }
+
+    /// Get the hardware model being emulated
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
     /// Get the header of the loaded ROM
     /// This function will return the header of the loaded ROM or panic if no ROM is loaded
     pub fn header(&self) -> &Header {
         &self.header
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_gameboy(cartridge_type: u8, header_checksum: u8) -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = cartridge_type;
+        rom[0x0149] = 0x02;
+        rom[0x014D] = header_checksum;
+        Gameboy::new_from_data(&rom, None, true).unwrap()
+    }
+
+    fn boot_rom(size: usize, program: &[u8]) -> Vec<u8> {
+        let mut boot_rom = vec![0; size];
+        boot_rom[..program.len()].copy_from_slice(program);
+        boot_rom
+    }
+
+    #[test]
+    fn test_boot_rom_unmap() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0000] = 0x12;
+        // LD A,0x42 / LDH (0x50),A
+        let boot_rom = boot_rom(BOOT_ROM_SIZE, &[0x3E, 0x42, 0xE0, 0x50]);
+        let mut gameboy = Gameboy::new_from_data_with_boot_rom(&rom, &boot_rom, None, true).unwrap();
+        assert_eq!(gameboy.cpu.registers.pc, 0x0000);
+        assert_eq!(gameboy.cpu.memory.read(0x0000), 0x3E);
+
+        gameboy.run_frame();
+        assert_eq!(gameboy.cpu.registers.a, 0x42);
+        assert_eq!(gameboy.cpu.memory.read(0x0000), 0x12);
+    }
+
+    #[test]
+    fn test_cgb_boot_rom_compatibility_mode() {
+        let rom = vec![0; 0x8000];
+        // LD A,0x04 / LDH (0x4C),A / LDH (0x50),A
+        let boot_rom = boot_rom(CGB_BOOT_ROM_SIZE, &[0x3E, 0x04, 0xE0, 0x4C, 0xE0, 0x50]);
+        let mut gameboy = Gameboy::new_from_data_with_boot_rom(&rom, &boot_rom, None, true).unwrap();
+        assert_eq!(gameboy.mode(), GBMode::CGB);
+
+        gameboy.run_frame();
+        assert_eq!(gameboy.mode(), GBMode::DMG);
+        assert!(Gameboy::new_from_data_with_boot_rom(&rom, &[0; 0x200], None, true).is_err());
+    }
+
+    #[test]
+    fn test_model_power_on_state() {
+        let mut rom = vec![0; 0x8000];
+        let gameboy = Gameboy::new_from_data(&rom, Some(Model::DMG0), true).unwrap();
+        assert_eq!(gameboy.cpu.registers.bc(), 0xFF13);
+        assert_eq!(gameboy.cpu.memory.read(0xFF04), 0x18);
+
+        // A DMG game on a CGB runs in compatibility mode with its own register values
+        let gameboy = Gameboy::new_from_data(&rom, Some(Model::CGB), true).unwrap();
+        assert_eq!(gameboy.mode(), GBMode::DMG);
+        assert_eq!(gameboy.cpu.registers.de(), 0x0008);
+        assert_eq!(gameboy.cpu.registers.hl(), 0x007C);
+
+        rom[0x0143] = 0x80;
+        let gameboy = Gameboy::new_from_data(&rom, Some(Model::AGB), true).unwrap();
+        assert_eq!(gameboy.mode(), GBMode::CGB);
+        assert_eq!(gameboy.cpu.registers.a, 0x11);
+        assert_eq!(gameboy.cpu.registers.b, 0x01);
+
+        let gameboy = Gameboy::new_from_data(&rom, Some(Model::DMG), true).unwrap();
+        assert_eq!(gameboy.mode(), GBMode::DMG);
+        assert_eq!(gameboy.cpu.registers.a, 0x01);
+
+        // The boot ROM size tells which models can run it
+        let boot_rom = boot_rom(BOOT_ROM_SIZE, &[]);
+        assert!(Gameboy::new_from_data_with_boot_rom(&rom, &boot_rom, Some(Model::CGB), true).is_err());
+    }
+
+    #[test]
+    fn test_linked_transfer() {
+        // LD A,value / LDH (0x01),A / LD A,control / LDH (0x02),A / JR -2
+        let program = |value: u8, control: u8| {
+            let mut rom = vec![0; 0x8000];
+            rom[0x0100..0x010A].copy_from_slice(&[0x3E, value, 0xE0, 0x01, 0x3E, control, 0xE0, 0x02, 0x18, 0xFE]);
+            Gameboy::new_from_data(&rom, Some(Model::DMG), true).unwrap()
+        };
+        let mut master = program(0x42, 0x81);
+        let mut slave = program(0x24, 0x80);
+        let (first, second) = crate::serial::LocalLink::pair();
+        master.connect_link(Box::new(first));
+        slave.connect_link(Box::new(second));
+
+        Gameboy::run_linked_frame(&mut [&mut master, &mut slave]);
+        assert_eq!(master.cpu.memory.read(0xFF01), 0x24);
+        assert_eq!(slave.cpu.memory.read(0xFF01), 0x42);
+        assert_eq!(master.cpu.memory.read(0xFF02) & 0x80, 0);
+        assert_eq!(slave.cpu.memory.read(0xFF02) & 0x80, 0);
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut gameboy = new_gameboy(0x03, 0x12);
+        gameboy.run_frame();
+        gameboy.cpu.memory.write(0x0000, 0x0A);
+        gameboy.cpu.memory.write(0xA010, 0x42);
+        gameboy.cpu.memory.write(0xC123, 0x24);
+        let state = gameboy.save_state();
+        let pc = gameboy.cpu.registers.pc;
+
+        gameboy.run_frame();
+        gameboy.cpu.memory.write(0xA010, 0x00);
+        gameboy.cpu.memory.write(0xC123, 0x00);
+
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.cpu.registers.pc, pc);
+        assert_eq!(gameboy.cpu.memory.read(0xA010), 0x42);
+        assert_eq!(gameboy.cpu.memory.read(0xC123), 0x24);
+        assert_eq!(gameboy.save_state(), state);
+    }
+
+    #[test]
+    fn test_state_from_other_rom() {
+        let gameboy = new_gameboy(0x03, 0x12);
+        let mut other = new_gameboy(0x03, 0x34);
+        assert!(other.load_state(&gameboy.save_state()).is_err());
+    }
+
+    #[test]
+    fn test_state_truncated() {
+        let mut gameboy = new_gameboy(0x00, 0x12);
+        gameboy.cpu.memory.write(0xC000, 0x55);
+        let mut state = gameboy.save_state();
+        gameboy.cpu.memory.write(0xC000, 0x66);
+
+        state.truncate(state.len() / 2);
+        assert!(gameboy.load_state(&state).is_err());
+        // The machine is left as it was before the failed load
+        assert_eq!(gameboy.cpu.memory.read(0xC000), 0x66);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        rom[0x014D] = 0x12;
+        let error = Gameboy::new_from_data(&rom, None, false).err();
+        assert!(matches!(error, Some(Error::BadChecksum { expected: 0x12, computed: 0xA7 })));
+        assert!(Gameboy::new_from_data(&rom, None, true).is_ok());
+
+        rom[0x014D] = 0xA7;
+        assert!(Gameboy::new_from_data(&rom, None, false).is_ok());
+    }
+}
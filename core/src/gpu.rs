@@ -1,5 +1,7 @@
 use std::cmp::Ordering;
-// use crate::gbmode::GbMode;
+use std::collections::VecDeque;
+use crate::gameboy::{GBMode, Renderer};
+use crate::state::{StateReader, StateWriter};
 
 const VRAM_SIZE: usize = 0x4000;
 const VOAM_SIZE: usize = 0xA0;
@@ -9,6 +11,17 @@ const SCREEN_HEIGHT: usize = 144;
 
 const OAM_SIZE: usize = 0xA0;
 
+// Length of the OAM scan and of mode 3 with the scanline renderer, a line lasts 456 dots
+const OAM_SCAN_DOTS: u32 = 80;
+const SCANLINE_VRAM_DOTS: u32 = 172;
+const LINE_DOTS: u32 = 456;
+
+// The first tile of a line is fetched twice, the first fetch is thrown away
+const FIRST_FETCH_DOTS: u8 = 6;
+
+// Fetching the tile of a sprite stops the background fetcher and the pixel output for 6 dots
+const SPRITE_FETCH_DOTS: u8 = 6;
+
 pub const SCREEN_SIZE_RGB: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 3;
 
 #[derive(PartialEq, Copy, Clone)]
@@ -33,17 +46,286 @@ enum Mode {
     VRAM,
 }
 
+#[derive(Copy, Clone)]
 struct Sprite {
     y: u8,
     x: u8,
     tile: u8,
     flags: u8,
+    // Index in the OAM, the lowest one has the priority on CGB
+    index: u8,
+}
+
+impl Sprite {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.y);
+        state.u8(self.x);
+        state.u8(self.tile);
+        state.u8(self.flags);
+        state.u8(self.index);
+    }
+
+    fn load_state(state: &mut StateReader) -> Result<Sprite, crate::Error> {
+        Ok(Sprite {
+            y: state.u8()?,
+            x: state.u8()?,
+            tile: state.u8()?,
+            flags: state.u8()?,
+            index: state.u8()?,
+        })
+    }
+}
+
+#[derive(Copy, Clone)]
+struct BgPixel {
+    color: u8,
+    // CGB BG map attributes of the tile (palette, priority)
+    attributes: u8,
+}
+
+#[derive(Copy, Clone)]
+struct ObjPixel {
+    color: u8,
+    // OAM flags of the sprite (palette, priority)
+    flags: u8,
+    index: u8,
+}
+
+#[derive(PartialEq, Copy, Clone)]
+enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+/**
+* Background / window fetcher, every step but the push takes 2 dots
+* @see: https://gbdev.io/pandocs/pixel_fifo.html#get-tile
+*/
+struct Fetcher {
+    step: FetchStep,
+    dots: u8,
+    // Tile column, counted from the left of the line or of the window
+    x: u8,
+    window: bool,
+    tile: u8,
+    attributes: u8,
+    low: u8,
+    high: u8,
+}
+
+impl Fetcher {
+    fn new(window: bool) -> Self {
+        Fetcher {
+            step: FetchStep::Tile,
+            dots: 0,
+            x: 0,
+            window,
+            tile: 0,
+            attributes: 0,
+            low: 0,
+            high: 0,
+        }
+    }
+}
+
+/**
+* State of mode 3 with the FIFO renderer
+* @see: https://gbdev.io/pandocs/pixel_fifo.html
+*/
+struct PixelFifo {
+    bg: VecDeque<BgPixel>,
+    obj: VecDeque<ObjPixel>,
+    fetcher: Fetcher,
+
+    // Dots left before the fetcher starts
+    delay: u8,
+
+    // Pixels thrown away instead of being drawn: SCX low bits, or the window pixels left of the screen
+    discard: u8,
+
+    // X of the next pixel sent to the LCD
+    lx: u8,
+
+    // Window started on the current line
+    window: bool,
+
+    // Sprites of the line found during the OAM scan and not fetched yet
+    sprites: Vec<Sprite>,
+
+    // Sprite being fetched (index in `sprites`) and dots left
+    sprite_fetch: Option<(usize, u8)>,
+}
+
+impl PixelFifo {
+    fn new() -> Self {
+        PixelFifo {
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            fetcher: Fetcher::new(false),
+            delay: 0,
+            discard: 0,
+            lx: 0,
+            window: false,
+            sprites: Vec::with_capacity(10),
+            sprite_fetch: None,
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.bg.len() as u8);
+        for pixel in self.bg.iter() {
+            state.u8(pixel.color);
+            state.u8(pixel.attributes);
+        }
+        state.u8(self.obj.len() as u8);
+        for pixel in self.obj.iter() {
+            state.u8(pixel.color);
+            state.u8(pixel.flags);
+            state.u8(pixel.index);
+        }
+
+        let fetcher = &self.fetcher;
+        state.u8(fetcher.step as u8);
+        state.u8(fetcher.dots);
+        state.u8(fetcher.x);
+        state.bool(fetcher.window);
+        state.u8(fetcher.tile);
+        state.u8(fetcher.attributes);
+        state.u8(fetcher.low);
+        state.u8(fetcher.high);
+
+        state.u8(self.delay);
+        state.u8(self.discard);
+        state.u8(self.lx);
+        state.bool(self.window);
+        state.u8(self.sprites.len() as u8);
+        for sprite in self.sprites.iter() {
+            sprite.save_state(state);
+        }
+        let (index, dots) = self.sprite_fetch.unwrap_or((0, 0));
+        state.u8(index as u8);
+        state.u8(dots);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.bg.clear();
+        for _ in 0..state.u8()? {
+            let color = state.u8()? & 0x03;
+            let attributes = state.u8()?;
+            self.bg.push_back(BgPixel { color, attributes });
+        }
+        self.obj.clear();
+        for _ in 0..state.u8()? {
+            let color = state.u8()? & 0x03;
+            let flags = state.u8()?;
+            let index = state.u8()?;
+            self.obj.push_back(ObjPixel { color, flags, index });
+        }
+
+        let fetcher = &mut self.fetcher;
+        fetcher.step = match state.u8()? & 0x03 {
+            0 => FetchStep::Tile,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            _ => FetchStep::Push,
+        };
+        fetcher.dots = state.u8()?;
+        fetcher.x = state.u8()?;
+        fetcher.window = state.bool()?;
+        fetcher.tile = state.u8()?;
+        fetcher.attributes = state.u8()?;
+        fetcher.low = state.u8()?;
+        fetcher.high = state.u8()?;
+
+        self.delay = state.u8()?;
+        self.discard = state.u8()?;
+        self.lx = state.u8()?.min(SCREEN_WIDTH as u8);
+        self.window = state.bool()?;
+        self.sprites.clear();
+        for _ in 0..state.u8()? {
+            self.sprites.push(Sprite::load_state(state)?);
+        }
+        let index = state.u8()? as usize;
+        let dots = state.u8()?;
+        self.sprite_fetch = (dots > 0 && index < self.sprites.len()).then_some((index, dots));
+        Ok(())
+    }
+}
+
+/**
+* CGB palette memory, accessed through an index register (BCPS/OCPS) and a data register (BCPD/OCPD)
+* @see: https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only
+*/
+struct ColorPalette {
+    index: u8,
+    auto_increment: bool,
+    // 8 palettes of 4 colors, each color is 2 bytes of little-endian RGB555
+    data: [u8; 64],
+}
+
+impl ColorPalette {
+    fn new(value: u8) -> Self {
+        ColorPalette {
+            index: 0,
+            auto_increment: false,
+            data: [value; 64],
+        }
+    }
+
+    fn read_spec(&self) -> u8 {
+        ((self.auto_increment as u8) << 7) | 0x40 | self.index
+    }
+
+    fn write_spec(&mut self, value: u8) {
+        self.auto_increment = value & 0x80 != 0;
+        self.index = value & 0x3F;
+    }
+
+    fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.read_spec());
+        state.bytes(&self.data);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.write_spec(state.u8()?);
+        state.bytes_into(&mut self.data)
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    // Convert a RGB555 color to RGB888
+    fn color(&self, palette: u8, color_number: u8) -> (u8, u8, u8) {
+        let index = (palette as usize * 4 + color_number as usize) * 2;
+        let color = self.data[index] as u16 | (self.data[index + 1] as u16) << 8;
+        let scale = |c: u16| ((c << 3) | (c >> 2)) as u8;
+        (
+            scale(color & 0x1F),
+            scale((color >> 5) & 0x1F),
+            scale((color >> 10) & 0x1F),
+        )
+    }
 }
 
 /**
 * @see: https://gbdev.io/pandocs/Graphics.html
 */
 pub struct GPU {
+    gbmode: GBMode,
+
+    // DMG game running on a CGB: the monochrome palettes select colors of the CGB palettes set by the boot ROM
+    cgb_compat: bool,
+
     wy_pos: i32,
 
     pub data: [u8; SCREEN_SIZE_RGB],
@@ -59,6 +341,13 @@ pub struct GPU {
 
     clock: u32,
 
+    renderer: Renderer,
+
+    // Length of the HBlank of the current line, what is left of the line after mode 3
+    hblank_dots: u32,
+
+    fifo: PixelFifo,
+
     // === LCDC (0xFF40) === see https://gbdev.io/pandocs/LCDC.html
     // LCDC.7 - LCD Display Enable (0=Off, 1=On)
     lcd_on: bool,
@@ -139,16 +428,33 @@ pub struct GPU {
     palette_obp0: [u8; 4],
     palette_obp1: [u8; 4],
 
+    // === CGB palettes (0xFF68-0xFF6B) === see https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only
+    // BCPS/BCPD - Background palette specification & data
+    cgb_palette_bg: ColorPalette,
+    // OCPS/OCPD - Object palette specification & data
+    cgb_palette_obj: ColorPalette,
+
+    // Priority of the background pixels of the current line, used when drawing sprites
+    bg_priority: [PrioType; SCREEN_WIDTH],
+
     wy_trigger: bool,
 
     pub interrupt: u8,
+
+    // Set when entering HBlank, used by the MMU to run HBlank DMA
+    pub hblank: bool,
 }
 
 impl GPU {
     pub fn new() -> Self {
         GPU {
+            gbmode: GBMode::DMG,
+            cgb_compat: false,
             mode: Mode::HBlank,
             clock: 0,
+            renderer: Renderer::Fifo,
+            hblank_dots: LINE_DOTS - OAM_SCAN_DOTS - SCANLINE_VRAM_DOTS,
+            fifo: PixelFifo::new(),
 
             vram: [0; VRAM_SIZE],
             vram_bank: 0,
@@ -183,14 +489,46 @@ impl GPU {
             palette_obp0: [0; 4],
             palette_obp1: [0; 4],
 
+            // The boot ROM leaves every background color white
+            cgb_palette_bg: ColorPalette::new(0xFF),
+            cgb_palette_obj: ColorPalette::new(0x00),
+            bg_priority: [PrioType::Normal; SCREEN_WIDTH],
+
             data: [255; SCREEN_SIZE_RGB],
             interrupt: 0,
+            hblank: false,
             lyc_interrupt: false,
         }
     }
 
     pub fn new_cgb() -> GPU {
-        GPU::new()
+        let mut gpu = GPU::new();
+        gpu.gbmode = GBMode::CGB;
+        gpu
+    }
+
+    /// Switch to the DMG compatibility mode of the CGB, the CGB palettes stay in use
+    pub fn set_dmg_compatibility(&mut self) {
+        self.gbmode = GBMode::DMG;
+        self.cgb_compat = true;
+        self.vram_bank = 0;
+    }
+
+    // DMG compatibility mode: the shade picked by the monochrome palette is a color index of a CGB palette
+    fn compat_color(palette: &ColorPalette, number: u8, value: u8, color_number: u8) -> (u8, u8, u8) {
+        palette.color(number, (value >> (color_number * 2)) & 0x03)
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        // The FIFO was not fed during the line being drawn, it starts it over
+        if renderer == Renderer::Fifo && self.renderer != Renderer::Fifo && self.mode == Mode::VRAM {
+            self.start_fifo_line();
+        }
+        self.renderer = renderer;
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        self.renderer
     }
 
     pub fn step(&mut self, ticks: u8) {
@@ -198,11 +536,23 @@ impl GPU {
             return;
         }
 
-        self.clock += ticks as u32;
+        match self.renderer {
+            Renderer::Scanline => self.step_modes(ticks as u32),
+            // The FIFO renderer runs dot by dot
+            Renderer::Fifo => {
+                for _ in 0..ticks {
+                    self.step_modes(1);
+                }
+            }
+        }
+    }
+
+    fn step_modes(&mut self, ticks: u32) {
+        self.clock += ticks;
 
         match self.mode {
             Mode::HBlank => {
-                if self.clock >= 204 {
+                if self.clock >= self.hblank_dots {
                     self.clock = 0;
                     self.line += 1;
                     if self.line == 144 {
@@ -213,7 +563,7 @@ impl GPU {
                 }
             }
             Mode::VBlank => {
-                if self.clock >= 456 {
+                if self.clock >= LINE_DOTS {
                     self.clock = 0;
                     self.line += 1;
                     if self.line > 153 {
@@ -223,13 +573,21 @@ impl GPU {
                 }
             }
             Mode::OAM => {
-                if self.clock >= 80 {
+                if self.clock >= OAM_SCAN_DOTS {
                     self.clock = 0;
                     self.change_mode(Mode::VRAM);
                 }
             }
             Mode::VRAM => {
-                if self.clock >= 172 {
+                let vram_dots = match self.renderer {
+                    Renderer::Scanline => (self.clock >= SCANLINE_VRAM_DOTS).then_some(SCANLINE_VRAM_DOTS),
+                    Renderer::Fifo => {
+                        self.fifo_dot();
+                        (self.fifo.lx as usize == SCREEN_WIDTH).then_some(self.clock)
+                    }
+                };
+                if let Some(vram_dots) = vram_dots {
+                    self.hblank_dots = (LINE_DOTS - OAM_SCAN_DOTS).saturating_sub(vram_dots);
                     self.clock = 0;
                     self.change_mode(Mode::HBlank);
                     self.check_interrupt_lyc();
@@ -250,7 +608,10 @@ impl GPU {
 
         if match self.mode {
             Mode::HBlank => {
-                self.renderscan();
+                if self.renderer == Renderer::Scanline {
+                    self.renderscan();
+                }
+                self.hblank = true;
                 self.mode0_interrupt
             }
             Mode::VBlank => {
@@ -265,6 +626,9 @@ impl GPU {
                     self.wy_trigger = true;
                     self.wy_pos = -1;
                 }
+                if self.renderer == Renderer::Fifo {
+                    self.start_fifo_line();
+                }
                 false
             }
         } {
@@ -307,6 +671,10 @@ impl GPU {
             0xFF4C => 0xFF,
             0xFF4E => 0xFF,
             0xFF4F => self.vram_bank as u8 | 0xFE,
+            0xFF68 if self.gbmode == GBMode::CGB => self.cgb_palette_bg.read_spec(),
+            0xFF69 if self.gbmode == GBMode::CGB => self.cgb_palette_bg.read_data(),
+            0xFF6A if self.gbmode == GBMode::CGB => self.cgb_palette_obj.read_spec(),
+            0xFF6B if self.gbmode == GBMode::CGB => self.cgb_palette_obj.read_data(),
             _ => 0xFF,
         }
     }
@@ -325,7 +693,7 @@ impl GPU {
         if a < 0x8000 || a >= 0xA000 {
             0xFF
         } else {
-            self.vram[a as usize & 0x1FFF]
+            self.vram[0x2000 | (a as usize & 0x1FFF)]
         }
     }
 
@@ -368,7 +736,7 @@ impl GPU {
                 self.lyc = v;
                 self.check_interrupt_lyc();
             }
-            0xFF46 => panic!("0xFF46 should be handled by MMU"),
+            0xFF46 => {} // OAM DMA, handled by `Memory`
             0xFF47 => {
                 self.palette_bg_value = v;
                 self.update_palette(PaletteType::Bg);
@@ -385,8 +753,13 @@ impl GPU {
             0xFF4B => self.wx = v,
             0xFF4C => {}
             0xFF4E => {}
-            0xFF4F => self.vram_bank = (v & 0x01) as usize,
-            _ => panic!("GPU does not handle write {:04X}", a),
+            0xFF4F if self.gbmode == GBMode::CGB => self.vram_bank = (v & 0x01) as usize,
+            0xFF68 if self.gbmode == GBMode::CGB => self.cgb_palette_bg.write_spec(v),
+            0xFF69 if self.gbmode == GBMode::CGB => self.cgb_palette_bg.write_data(v),
+            0xFF6A if self.gbmode == GBMode::CGB => self.cgb_palette_obj.write_spec(v),
+            0xFF6B if self.gbmode == GBMode::CGB => self.cgb_palette_obj.write_data(v),
+            0xFF4F | 0xFF68..=0xFF6B => {}
+            _ => {}
         }
     }
 
@@ -421,14 +794,26 @@ impl GPU {
         self.draw_sprites();
     }
 
-    fn set_color(&mut self, x: usize, color: u8) {
+    fn set_rgb(&mut self, x: usize, (r, g, b): (u8, u8, u8)) {
         let index = self.line as usize * SCREEN_WIDTH * 3 + x * 3;
-        self.data[index + 0] = color;
-        self.data[index + 1] = color;
-        self.data[index + 2] = color;
+        self.data[index + 0] = r;
+        self.data[index + 1] = g;
+        self.data[index + 2] = b;
     }
 
     fn draw_bg(&mut self) {
+        let cgb = self.gbmode == GBMode::CGB;
+
+        // On DMG, LCDC.0 disables both the background and the window
+        if !cgb && !self.bgw_on {
+            let color = self.bg_rgb(0, 0);
+            for x in 0..SCREEN_WIDTH {
+                self.set_rgb(x, color);
+                self.bg_priority[x] = PrioType::Color0;
+            }
+            return;
+        }
+
         let w_trigger = self.win_on && self.wy_trigger && self.wx <= 166;
 
         // Can be calculated with:
@@ -440,10 +825,6 @@ impl GPU {
             -1
         };
 
-        if winy < 0 && self.bgw_on == false {
-            return;
-        }
-
         let wintiley = (winy as u16 >> 3) & 31;
 
         let bgy = self.scy.wrapping_add(self.line);
@@ -471,7 +852,14 @@ impl GPU {
                 )
             };
 
-            let tile_number: u8 = self.rbvram0(tilemapbase + tiley * 32 + tilex);
+            let tile_map_address = tilemapbase + tiley * 32 + tilex;
+            let tile_number: u8 = self.rbvram0(tile_map_address);
+
+            // CGB: BG map attributes are stored in VRAM bank 1
+            // see https://gbdev.io/pandocs/Tile_Maps.html#bg-map-attributes-cgb-mode-only
+            let attributes = if cgb { self.rbvram1(tile_map_address) } else { 0 };
+            let pixely = if attributes & 0x40 != 0 { 7 - pixely } else { pixely };
+            let pixelx = if attributes & 0x20 != 0 { 7 - pixelx } else { pixelx };
 
             let offset = if self.bgw_tiles == 0x8000 {
                 tile_number as u16
@@ -482,7 +870,11 @@ impl GPU {
 
             // Tile data (2 bytes per line)
             let data = tile_address + (pixely * 2);
-            let (b1, b2) = (self.rbvram0(data), self.rbvram0(data + 1));
+            let (b1, b2) = if attributes & 0x08 != 0 {
+                (self.rbvram1(data), self.rbvram1(data + 1))
+            } else {
+                (self.rbvram0(data), self.rbvram0(data + 1))
+            };
 
             // Shift bit
             let xbit = 7 - pixelx as u32;
@@ -490,67 +882,125 @@ impl GPU {
             // Color number
             let color_number = ((b1 >> xbit) & 1) | (((b2 >> xbit) & 1) << 1);
 
+            self.bg_priority[x] = GPU::bg_priority(color_number, attributes);
+            let color = self.bg_rgb(attributes, color_number);
+            self.set_rgb(x, color);
+        }
+    }
+
+    fn bg_priority(color_number: u8, attributes: u8) -> PrioType {
+        if color_number == 0 {
+            PrioType::Color0
+        } else if attributes & 0x80 != 0 {
+            PrioType::PrioFlag
+        } else {
+            PrioType::Normal
+        }
+    }
+
+    fn bg_rgb(&self, attributes: u8, color_number: u8) -> (u8, u8, u8) {
+        if self.gbmode == GBMode::CGB {
+            self.cgb_palette_bg.color(attributes & 0x07, color_number)
+        } else if self.cgb_compat {
+            GPU::compat_color(&self.cgb_palette_bg, 0, self.palette_bg_value, color_number)
+        } else {
             let color = self.palette_bg[color_number as usize];
-            self.set_color(x, color);
+            (color, color, color)
         }
     }
 
-    fn draw_sprites(&mut self) {
-        if !self.sprite_on {
-            return;
+    fn obj_rgb(&self, flags: u8, color_number: u8) -> (u8, u8, u8) {
+        let palette = flags & 0x10 != 0;
+        if self.gbmode == GBMode::CGB {
+            self.cgb_palette_obj.color(flags & 0x07, color_number)
+        } else if self.cgb_compat {
+            let value = if palette { self.palette_obp1_value } else { self.palette_obp0_value };
+            GPU::compat_color(&self.cgb_palette_obj, palette as u8, value, color_number)
+        } else {
+            let color = if palette {
+                self.palette_obp1[color_number as usize]
+            } else {
+                self.palette_obp0[color_number as usize]
+            };
+            (color, color, color)
         }
+    }
 
-        let line = self.line;
-        let sprite_size = self.sprite_size;
+    // Whether a sprite pixel is drawn over a background pixel
+    fn obj_visible(&self, bg_priority: PrioType, flags: u8) -> bool {
+        // CGB: when LCDC.0 is off, sprites are always drawn over the background
+        let master_priority = self.gbmode == GBMode::CGB && !self.bgw_on;
+        let hidden = match bg_priority {
+            PrioType::Color0 => false,
+            PrioType::PrioFlag => true,
+            PrioType::Normal => flags & 0x80 != 0,
+        };
+        !hidden || master_priority
+    }
 
-        let mut sprites_to_draw = Vec::<Sprite>::with_capacity(10);
-        let mut sprite_count = 0;
+    // Sprites on the current line, at most 10 in the OAM order
+    fn scan_oam(&self) -> Vec<Sprite> {
+        let mut sprites = Vec::<Sprite>::with_capacity(10);
 
         for i in 0..40 {
             let sprite_address = i * 4;
             let sprite_y = self.oam[sprite_address].wrapping_sub(16);
 
             // If the sprite is not on the current line, skip it
-            if line < sprite_y || line >= sprite_y + sprite_size {
+            if self.line.wrapping_sub(sprite_y) >= self.sprite_size {
                 continue;
             }
 
-            let sprite_x = self.oam[sprite_address + 1].wrapping_sub(8);
-
-            let sprite = Sprite {
+            sprites.push(Sprite {
                 y: sprite_y,
-                x: sprite_x,
+                x: self.oam[sprite_address + 1].wrapping_sub(8),
                 tile: self.oam[sprite_address + 2],
                 flags: self.oam[sprite_address + 3],
-            };
-
-            sprites_to_draw.push(sprite);
-            sprite_count += 1;
-            if sprite_count >= 10 {
+                index: i as u8,
+            });
+            if sprites.len() >= 10 {
                 break;
             }
         }
+        sprites
+    }
+
+    // Tile data (low, high) of the current line of a sprite
+    fn sprite_tile_data(&self, sprite: &Sprite) -> (u8, u8) {
+        let line = self.line.wrapping_sub(sprite.y) & (self.sprite_size - 1);
+        let tile_y = if sprite.flags & 0x40 != 0 { self.sprite_size - 1 - line } else { line };
+
+        // In 8x16 mode, the lowest bit of the tile index is ignored
+        let tile = if self.sprite_size == 16 { sprite.tile & 0xFE } else { sprite.tile };
+        let tile_address = 0x8000 + (tile as u16) * 16 + (tile_y as u16) * 2;
+        if self.gbmode == GBMode::CGB && sprite.flags & 0x08 != 0 {
+            (self.rbvram1(tile_address), self.rbvram1(tile_address + 1))
+        } else {
+            (self.rbvram0(tile_address), self.rbvram0(tile_address + 1))
+        }
+    }
 
-        for sprite in sprites_to_draw {
-            let sprite_x = sprite.x as i32;
+    fn draw_sprites(&mut self) {
+        if !self.sprite_on {
+            return;
+        }
+
+        let mut sprites_to_draw = self.scan_oam();
+
+        // On DMG the sprite with the smallest X has the priority (OAM order on ties), on CGB only the OAM order matters.
+        // Sprites are drawn from the lowest to the highest priority.
+        if self.gbmode != GBMode::CGB {
+            sprites_to_draw.sort_by_key(|sprite| sprite.x.wrapping_add(8));
+        }
+
+        for sprite in sprites_to_draw.iter().rev() {
+            let sprite_x = sprite.x.wrapping_add(8) as i32 - 8;
             if sprite_x < -7 || sprite_x >= SCREEN_WIDTH as i32 {
                 continue;
             }
 
             let flip_x = sprite.flags & 0x20 != 0;
-            let flip_y = sprite.flags & 0x40 != 0;
-            let below_bg = sprite.flags & 0x80 != 0;
-            let palette = sprite.flags & 0x10 != 0;
-
-            let tile_y = if flip_y {
-                7 - (line - sprite.y)
-            } else {
-                line - sprite.y
-            };
-
-            let tile_address = 0x8000 + (sprite.tile as u16) * 16 + (tile_y as u16) * 2;
-            let (low_byte, high_byte) =
-                (self.rbvram0(tile_address), self.rbvram0(tile_address + 1));
+            let (low_byte, high_byte) = self.sprite_tile_data(sprite);
 
             for x in 0..8 {
                 let tile_x = if flip_x { x } else { 7 - x };
@@ -561,29 +1011,439 @@ impl GPU {
                 }
 
                 let x = sprite_x + x;
-                if x >= SCREEN_WIDTH as i32 {
+                if !(0..SCREEN_WIDTH as i32).contains(&x) {
                     continue;
                 }
 
-                if below_bg && self.data[(line as usize * SCREEN_WIDTH + x as usize) * 3] != 255 {
+                if !self.obj_visible(self.bg_priority[x as usize], sprite.flags) {
                     continue;
                 }
 
-                let color = if palette {
-                    self.palette_obp1[color_number as usize]
+                let color = self.obj_rgb(sprite.flags, color_number);
+                self.set_rgb(x as usize, color);
+            }
+        }
+    }
+
+    // Mode 3 begins: the FIFOs are emptied and the sprites of the line are picked
+    fn start_fifo_line(&mut self) {
+        let sprites = self.scan_oam();
+        let fifo = &mut self.fifo;
+        fifo.bg.clear();
+        fifo.obj.clear();
+        fifo.fetcher = Fetcher::new(false);
+        fifo.delay = FIRST_FETCH_DOTS;
+        fifo.discard = self.scx & 0x07;
+        fifo.lx = 0;
+        fifo.window = false;
+        fifo.sprite_fetch = None;
+        fifo.sprites = sprites;
+    }
+
+    /**
+     * One dot of mode 3 with the FIFO renderer
+     * @see: https://gbdev.io/pandocs/pixel_fifo.html
+     */
+    fn fifo_dot(&mut self) {
+        // A sprite fetch stops both the background fetcher and the pixel output
+        if let Some((index, dots)) = self.fifo.sprite_fetch {
+            if dots > 1 {
+                self.fifo.sprite_fetch = Some((index, dots - 1));
+            } else {
+                self.fifo.sprite_fetch = None;
+                self.fetch_sprite(index);
+            }
+            return;
+        }
+
+        if self.fifo.delay > 0 {
+            self.fifo.delay -= 1;
+            return;
+        }
+
+        // The window starts when the X of the next pixel reaches WX - 7, the background fetch is restarted
+        if !self.fifo.window && self.win_on && self.wy_trigger && self.wx <= 166
+            && self.fifo.discard == 0 && self.fifo.lx + 7 >= self.wx
+        {
+            self.fifo.window = true;
+            self.wy_pos += 1;
+            self.fifo.bg.clear();
+            self.fifo.fetcher = Fetcher::new(true);
+            self.fifo.discard = 7u8.saturating_sub(self.wx);
+        }
+
+        if self.sprite_on && self.fifo.discard == 0 {
+            let lx = self.fifo.lx as u16;
+            let sprite = self.fifo.sprites.iter().position(|sprite| sprite.x.wrapping_add(8) as u16 <= lx + 8);
+            if let Some(index) = sprite {
+                // The sprite waits for the background fetcher to be reading the last byte of its tile
+                let fetcher = &self.fifo.fetcher;
+                if !self.fifo.bg.is_empty() && matches!(fetcher.step, FetchStep::DataHigh | FetchStep::Push) {
+                    // This dot is the first one of the fetch
+                    self.fifo.sprite_fetch = Some((index, SPRITE_FETCH_DOTS - 1));
                 } else {
-                    self.palette_obp0[color_number as usize]
+                    self.fetcher_dot();
+                }
+                return;
+            }
+        }
+
+        self.fetcher_dot();
+
+        if let Some(bg) = self.fifo.bg.pop_front() {
+            let obj = self.fifo.obj.pop_front();
+            if self.fifo.discard > 0 {
+                self.fifo.discard -= 1;
+                return;
+            }
+            self.draw_fifo_pixel(bg, obj);
+            self.fifo.lx += 1;
+        }
+    }
+
+    fn fetcher_dot(&mut self) {
+        let fetcher = &mut self.fifo.fetcher;
+        if fetcher.step == FetchStep::Push {
+            // The 8 pixels of the tile are pushed once the background FIFO is empty
+            if self.fifo.bg.is_empty() {
+                let attributes = fetcher.attributes;
+                for x in 0..8 {
+                    let bit = if attributes & 0x20 != 0 { x } else { 7 - x };
+                    let color = ((fetcher.low >> bit) & 1) | (((fetcher.high >> bit) & 1) << 1);
+                    self.fifo.bg.push_back(BgPixel { color, attributes });
+                }
+                fetcher.x = fetcher.x.wrapping_add(1);
+                fetcher.step = FetchStep::Tile;
+            }
+            return;
+        }
+
+        fetcher.dots += 1;
+        if fetcher.dots < 2 {
+            return;
+        }
+        fetcher.dots = 0;
+
+        match fetcher.step {
+            FetchStep::Tile => {
+                // The window stops when it is disabled in the middle of the line
+                if fetcher.window && !self.win_on {
+                    fetcher.window = false;
+                }
+                let (tilemap, column, y) = if fetcher.window {
+                    (self.win_tilemap, fetcher.x as u16 & 31, self.wy_pos as u8)
+                } else {
+                    (self.bg_tilemap, ((self.scx >> 3) as u16 + fetcher.x as u16) & 31, self.scy.wrapping_add(self.line))
                 };
+                let address = tilemap + ((y as u16 >> 3) & 31) * 32 + column;
+                let tile = self.rbvram0(address);
+                let attributes = if self.gbmode == GBMode::CGB { self.rbvram1(address) } else { 0 };
+
+                let fetcher = &mut self.fifo.fetcher;
+                fetcher.tile = tile;
+                fetcher.attributes = attributes;
+                fetcher.step = FetchStep::DataLow;
+            }
+            FetchStep::DataLow => {
+                self.fifo.fetcher.low = self.fetcher_tile_data(0);
+                self.fifo.fetcher.step = FetchStep::DataHigh;
+            }
+            _ => {
+                self.fifo.fetcher.high = self.fetcher_tile_data(1);
+                self.fifo.fetcher.step = FetchStep::Push;
+            }
+        }
+    }
+
+    // Byte of the tile data read by the fetcher, with the registers of the moment
+    fn fetcher_tile_data(&self, byte: u16) -> u8 {
+        let fetcher = &self.fifo.fetcher;
+        let y = if fetcher.window { self.wy_pos as u16 } else { self.scy.wrapping_add(self.line) as u16 } & 0x07;
+        let y = if fetcher.attributes & 0x40 != 0 { 7 - y } else { y };
+
+        let offset = if self.bgw_tiles == 0x8000 {
+            fetcher.tile as u16
+        } else {
+            (fetcher.tile as i8 as i16 + 128) as u16
+        };
+        let address = self.bgw_tiles + offset * 16 + y * 2 + byte;
+        if fetcher.attributes & 0x08 != 0 {
+            self.rbvram1(address)
+        } else {
+            self.rbvram0(address)
+        }
+    }
 
-                let index = (line as usize) * SCREEN_WIDTH * 3 + x as usize * 3;
-                self.data[index + 0] = color;
-                self.data[index + 1] = color;
-                self.data[index + 2] = color;
+    // Mix the pixels of the sprite in the object FIFO, the pixels already there keep the priority
+    fn fetch_sprite(&mut self, index: usize) {
+        let sprite = self.fifo.sprites.remove(index);
+        let (low, high) = self.sprite_tile_data(&sprite);
+        let cgb = self.gbmode == GBMode::CGB;
+
+        // Pixels left of the next pixel drawn are dropped
+        let skip = (self.fifo.lx + 8).saturating_sub(sprite.x.wrapping_add(8)).min(8);
+
+        let fifo = &mut self.fifo;
+        while fifo.obj.len() < 8 {
+            fifo.obj.push_back(ObjPixel { color: 0, flags: 0, index: 0xFF });
+        }
+        for x in skip..8 {
+            let bit = if sprite.flags & 0x20 != 0 { x } else { 7 - x };
+            let color = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+            let pixel = &mut fifo.obj[(x - skip) as usize];
+            // On CGB the OAM order decides, on DMG the sprite fetched first (smallest X) wins
+            if color != 0 && (pixel.color == 0 || (cgb && sprite.index < pixel.index)) {
+                *pixel = ObjPixel { color, flags: sprite.flags, index: sprite.index };
             }
         }
     }
 
+    fn draw_fifo_pixel(&mut self, bg: BgPixel, obj: Option<ObjPixel>) {
+        // On DMG, LCDC.0 disables both the background and the window
+        let bg_color = if self.gbmode != GBMode::CGB && !self.bgw_on { 0 } else { bg.color };
+        let bg_priority = GPU::bg_priority(bg_color, bg.attributes);
+
+        let color = match obj {
+            Some(obj) if obj.color != 0 && self.sprite_on && self.obj_visible(bg_priority, obj.flags) => {
+                self.obj_rgb(obj.flags, obj.color)
+            }
+            _ => self.bg_rgb(bg.attributes, bg_color),
+        };
+        self.set_rgb(self.fifo.lx as usize, color);
+    }
+
+    /// Raw CGB palette memory (background, objects), 8 palettes of 4 RGB555 colors each
+    pub fn cgb_palette_data(&self) -> (&[u8], &[u8]) {
+        (&self.cgb_palette_bg.data, &self.cgb_palette_obj.data)
+    }
+
+    pub fn load_cgb_palette_data(&mut self, bg: &[u8], obj: &[u8]) {
+        let length = bg.len().min(self.cgb_palette_bg.data.len());
+        self.cgb_palette_bg.data[..length].copy_from_slice(&bg[..length]);
+        let length = obj.len().min(self.cgb_palette_obj.data.len());
+        self.cgb_palette_obj.data[..length].copy_from_slice(&obj[..length]);
+    }
+
+    /// Put the LCD at the given line and in the mode of the STAT value, without firing interrupts
+    pub fn restore_lcd_status(&mut self, stat: u8, line: u8) {
+        self.write(0xFF41, stat);
+        if !self.lcd_on {
+            return;
+        }
+        self.line = line.min(153);
+        self.mode = match stat & 0x03 {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OAM,
+            _ => Mode::VRAM,
+        };
+        self.clock = 0;
+        self.hblank_dots = LINE_DOTS - OAM_SCAN_DOTS - SCANLINE_VRAM_DOTS;
+        if self.mode == Mode::VRAM {
+            self.start_fifo_line();
+        }
+        self.interrupt = 0;
+        self.hblank = false;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.gbmode == GBMode::CGB);
+        state.bool(self.cgb_compat);
+        state.bytes(&self.vram);
+        state.bytes(&self.oam);
+        state.bytes(&self.data);
+
+        state.u8(self.mode as u8);
+        state.u32(self.clock);
+        state.u8(self.read(0xFF40));
+        state.u8(self.read(0xFF41));
+        state.u8(self.scy);
+        state.u8(self.scx);
+        state.u8(self.line);
+        state.u8(self.lyc);
+        state.u8(self.palette_bg_value);
+        state.u8(self.palette_obp0_value);
+        state.u8(self.palette_obp1_value);
+        state.u8(self.wy);
+        state.u8(self.wx);
+        state.u8(self.vram_bank as u8);
+        self.cgb_palette_bg.save_state(state);
+        self.cgb_palette_obj.save_state(state);
+
+        state.bool(self.wy_trigger);
+        state.i32(self.wy_pos);
+        state.u8(self.interrupt);
+        state.bool(self.hblank);
+
+        state.u32(self.hblank_dots);
+        self.fifo.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.gbmode = if state.bool()? { GBMode::CGB } else { GBMode::DMG };
+        self.cgb_compat = state.bool()?;
+        state.bytes_into(&mut self.vram)?;
+        state.bytes_into(&mut self.oam)?;
+        state.bytes_into(&mut self.data)?;
+
+        self.mode = match state.u8()? & 0x03 {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OAM,
+            _ => Mode::VRAM,
+        };
+        self.clock = state.u32()?;
+
+        // LCDC is restored without the side effects of turning the LCD on or off
+        let lcdc = state.u8()?;
+        self.lcd_on = lcdc & 0x80 == 0x80;
+        self.win_tilemap = if lcdc & 0x40 == 0x40 { 0x9C00 } else { 0x9800 };
+        self.win_on = lcdc & 0x20 == 0x20;
+        self.bgw_tiles = if lcdc & 0x10 == 0x10 { 0x8000 } else { 0x8800 };
+        self.bg_tilemap = if lcdc & 0x08 == 0x08 { 0x9C00 } else { 0x9800 };
+        self.sprite_size = if lcdc & 0x04 == 0x04 { 16 } else { 8 };
+        self.sprite_on = lcdc & 0x02 == 0x02;
+        self.bgw_on = lcdc & 0x01 == 0x01;
+
+        let stat = state.u8()?;
+        self.lyc_interrupt = stat & 0x40 == 0x40;
+        self.mode2_interrupt = stat & 0x20 == 0x20;
+        self.mode1_interrupt = stat & 0x10 == 0x10;
+        self.mode0_interrupt = stat & 0x08 == 0x08;
+
+        self.scy = state.u8()?;
+        self.scx = state.u8()?;
+        self.line = state.u8()?;
+        self.lyc = state.u8()?;
+        self.palette_bg_value = state.u8()?;
+        self.palette_obp0_value = state.u8()?;
+        self.palette_obp1_value = state.u8()?;
+        self.update_palette(PaletteType::Bg);
+        self.update_palette(PaletteType::Obj0);
+        self.update_palette(PaletteType::Obj1);
+        self.wy = state.u8()?;
+        self.wx = state.u8()?;
+        self.vram_bank = (state.u8()? & 0x01) as usize;
+        self.cgb_palette_bg.load_state(state)?;
+        self.cgb_palette_obj.load_state(state)?;
+
+        self.wy_trigger = state.bool()?;
+        self.wy_pos = state.i32()?;
+        self.interrupt = state.u8()?;
+        self.hblank = state.bool()?;
+
+        self.hblank_dots = state.u32()?;
+        self.fifo.load_state(state)
+    }
+
     pub fn screen_data(&self) -> &[u8; 160 * 144 * 3] {
         &self.data
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cgb_palette_auto_increment() {
+        let mut gpu = GPU::new_cgb();
+        gpu.write(0xFF68, 0x80 | 0x3E);
+        gpu.write(0xFF69, 0x1F);
+        gpu.write(0xFF69, 0x00);
+        assert_eq!(gpu.read(0xFF68), 0xC0);
+        assert_eq!(gpu.cgb_palette_bg.color(7, 3), (255, 0, 0));
+    }
+
+    #[test]
+    fn test_dmg_ignores_cgb_registers() {
+        let mut gpu = GPU::new();
+        gpu.write(0xFF4F, 0x01);
+        gpu.write(0xFF68, 0x80);
+        assert_eq!(gpu.vram_bank, 0);
+        assert_eq!(gpu.read(0xFF68), 0xFF);
+    }
+
+    // Length in dots of the next mode 3
+    fn mode3_length(gpu: &mut GPU) -> u32 {
+        while gpu.mode != Mode::VRAM {
+            gpu.step(1);
+        }
+        let mut dots = 0;
+        while gpu.mode == Mode::VRAM {
+            gpu.step(1);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn test_mode3_length() {
+        let mut gpu = GPU::new();
+        gpu.write(0xFF40, 0x93);
+        assert_eq!(mode3_length(&mut gpu), 172);
+
+        // The low bits of SCX are thrown away at the start of the line
+        gpu.write(0xFF43, 3);
+        assert_eq!(mode3_length(&mut gpu), 175);
+        gpu.write(0xFF43, 0);
+
+        // The window restarts the background fetch
+        gpu.write(0xFF40, 0xB3);
+        gpu.write(0xFF4A, gpu.line + 1);
+        gpu.write(0xFF4B, 87);
+        assert_eq!(mode3_length(&mut gpu), 178);
+        gpu.write(0xFF40, 0x93);
+
+        // A sprite at X=0 waits for the first tile and stops the output 6 dots
+        let line = gpu.line.wrapping_add(1);
+        gpu.oam[0] = line + 16;
+        gpu.oam[1] = 8;
+        assert_eq!(mode3_length(&mut gpu), 183);
+
+        gpu.set_renderer(Renderer::Scanline);
+        assert_eq!(mode3_length(&mut gpu), 172);
+    }
+
+    #[test]
+    fn test_switch_renderer_during_mode3() {
+        let mut gpu = GPU::new();
+        gpu.write(0xFF40, 0x93);
+        assert_eq!(mode3_length(&mut gpu), 172);
+
+        // The FIFO left from the previous line must not end this one right away
+        gpu.set_renderer(Renderer::Scanline);
+        while gpu.mode != Mode::VRAM {
+            gpu.step(1);
+        }
+        gpu.set_renderer(Renderer::Fifo);
+        assert_eq!(mode3_length(&mut gpu), 172);
+    }
+
+    #[test]
+    fn test_fifo_matches_scanline() {
+        let frame = |renderer: Renderer| {
+            let mut gpu = GPU::new();
+            gpu.set_renderer(renderer);
+            gpu.write(0xFF47, 0xE4);
+            gpu.write(0xFF48, 0x1B);
+            // Tile 1 is striped, the map alternates tiles 0 and 1
+            for y in 0..8 {
+                gpu.vram[0x10 + y * 2] = 0x55;
+                gpu.vram[0x11 + y * 2] = 0x0F;
+            }
+            for i in 0..0x400 {
+                gpu.vram[0x1800 + i] = (i % 3 == 0) as u8;
+            }
+            gpu.oam[..8].copy_from_slice(&[40, 20, 1, 0x00, 50, 100, 1, 0xA0]);
+            gpu.write(0xFF43, 5);
+            gpu.write(0xFF42, 3);
+            gpu.write(0xFF40, 0x93);
+            for _ in 0..(LINE_DOTS * 154 * 2) {
+                gpu.step(1);
+            }
+            gpu.data
+        };
+        assert!(frame(Renderer::Fifo) == frame(Renderer::Scanline));
+    }
+}
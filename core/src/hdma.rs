@@ -0,0 +1,152 @@
+use crate::state::{StateReader, StateWriter};
+
+// Number of bytes copied per block
+pub const HDMA_BLOCK_SIZE: u16 = 0x10;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum HdmaMode {
+    // General purpose DMA: every block is copied at once, the CPU is halted during the transfer
+    General,
+    // HBlank DMA: one block is copied at the start of each HBlank
+    HBlank,
+}
+
+/**
+* CGB VRAM DMA, the copy itself is done by the MMU
+* @see: https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers
+*/
+pub struct Hdma {
+    // 0xFF51/0xFF52 — HDMA1/HDMA2: Source (the lower 4 bits are ignored)
+    source: u16,
+
+    // 0xFF53/0xFF54 — HDMA3/HDMA4: Destination in VRAM (only bits 12-4 are used)
+    destination: u16,
+
+    // 0xFF55 — HDMA5: Remaining length divided by 0x10, minus 1
+    length: u8,
+
+    mode: HdmaMode,
+    active: bool,
+}
+
+impl Hdma {
+    pub fn new() -> Self {
+        Hdma {
+            source: 0,
+            destination: 0x8000,
+            length: 0x7F,
+            mode: HdmaMode::General,
+            active: false,
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF55 => ((!self.active as u8) << 7) | self.length,
+            _ => 0xFF, // Write only
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF51 => self.source = (self.source & 0x00FF) | (value as u16) << 8,
+            0xFF52 => self.source = (self.source & 0xFF00) | (value as u16 & 0xF0),
+            0xFF53 => self.destination = 0x8000 | (self.destination & 0x00FF) | (value as u16 & 0x1F) << 8,
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (value as u16 & 0xF0),
+            0xFF55 => {
+                if self.active && self.mode == HdmaMode::HBlank && value & 0x80 == 0 {
+                    // Writing 0 to bit 7 during a HBlank DMA cancels it
+                    self.active = false;
+                    return;
+                }
+                self.length = value & 0x7F;
+                self.mode = if value & 0x80 == 0 { HdmaMode::General } else { HdmaMode::HBlank };
+                self.active = true;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.source);
+        state.u16(self.destination);
+        state.u8(self.length);
+        state.bool(self.mode == HdmaMode::HBlank);
+        state.bool(self.active);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.source = state.u16()?;
+        self.destination = state.u16()?;
+        self.length = state.u8()? & 0x7F;
+        self.mode = if state.bool()? { HdmaMode::HBlank } else { HdmaMode::General };
+        self.active = state.bool()?;
+        Ok(())
+    }
+
+    pub fn is_active(&self, mode: HdmaMode) -> bool {
+        self.active && self.mode == mode
+    }
+
+    /// Source and destination of the next block
+    pub fn next_block(&self) -> (u16, u16) {
+        (self.source, self.destination)
+    }
+
+    /// Move to the next block once one has been copied
+    pub fn advance(&mut self) {
+        self.source = self.source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.destination = self.destination.wrapping_add(HDMA_BLOCK_SIZE);
+
+        // The transfer stops when the destination goes past the end of VRAM
+        if self.destination > 0x9FFF {
+            self.destination = 0x8000;
+            self.length = 0x7F;
+            self.active = false;
+            return;
+        }
+
+        self.length = self.length.wrapping_sub(1) & 0x7F;
+        if self.length == 0x7F {
+            self.active = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hdma_alignment() {
+        let mut hdma = Hdma::new();
+        hdma.write(0xFF51, 0xC1);
+        hdma.write(0xFF52, 0x2F);
+        hdma.write(0xFF53, 0xE3);
+        hdma.write(0xFF54, 0x4F);
+        assert_eq!(hdma.next_block(), (0xC120, 0x8340));
+    }
+
+    #[test]
+    fn test_hdma_length_readback() {
+        let mut hdma = Hdma::new();
+        assert_eq!(hdma.read(0xFF55), 0xFF);
+
+        hdma.write(0xFF55, 0x81);
+        assert_eq!(hdma.read(0xFF55), 0x01);
+        hdma.advance();
+        assert_eq!(hdma.read(0xFF55), 0x00);
+        hdma.advance();
+        assert_eq!(hdma.read(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn test_hdma_cancel() {
+        let mut hdma = Hdma::new();
+        hdma.write(0xFF55, 0x83);
+        hdma.advance();
+        hdma.write(0xFF55, 0x00);
+        assert!(!hdma.is_active(HdmaMode::HBlank));
+        assert_eq!(hdma.read(0xFF55), 0x82);
+    }
+}
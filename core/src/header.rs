@@ -1,4 +1,6 @@
-use std::io::{Error, ErrorKind, Seek, SeekFrom};
+use std::io::{Seek, SeekFrom};
+
+use crate::Error;
 
 pub struct Header {
     title: String,
@@ -42,32 +44,40 @@ impl Header {
         }
     }
 
+    /// The boot ROM locks up when the header checksum (0x014D) is wrong
+    pub fn check_checksum(header: &[u8; 0x50]) -> Result<(), Error> {
+        let mut checksum = 0u8;
+        for &byte in &header[0x34..0x4D] {
+            checksum = checksum.wrapping_sub(byte).wrapping_sub(1);
+        }
+
+        if checksum != header[0x4D] {
+            return Err(Error::BadChecksum {
+                expected: header[0x4D],
+                computed: checksum,
+            });
+        }
+
+        // /!\ The global checksum (0x014E-0x014F) is not checked, 'cause it's not mandatory and not checked by the Gameboy
+        Ok(())
+    }
+
     pub fn load_from_file(
         file: &mut std::fs::File,
         skip_checksum: bool,
-    ) -> Result<Self, std::io::Error> {
+    ) -> Result<Self, Error> {
         use std::io::Read;
 
         // Skip the first 0x100 bytes & read the next 0x50 bytes
         let mut buffer = [0; 0x50];
         file.seek(SeekFrom::Start(0x100))?;
-        file.read_exact(&mut buffer)?;
-
-        if skip_checksum == false {
-            // Check the header checksum
-            let mut checksum = 0u8;
-            for i in 0x34..0x4D {
-                checksum = checksum.wrapping_sub(buffer[i] + 1);
-            }
-
-            if checksum != buffer[0x4D] {
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    "Invalid header checksum",
-                ));
-            }
-
-            // /!\ The global checksum (0x014E-0x014F) is not checked, 'cause it's not mandatory and not checked by the Gameboy
+        file.read_exact(&mut buffer).map_err(|error| match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => Error::InvalidHeader,
+            _ => Error::Io(error),
+        })?;
+
+        if !skip_checksum {
+            Header::check_checksum(&buffer)?;
         }
 
         let header = Header::load_rom(&buffer);
@@ -89,6 +99,14 @@ impl Header {
     pub fn cartridge_type(&self) -> u8 {
         self.cartridge_type
     }
+
+    pub fn header_checksum(&self) -> u8 {
+        self.header_checksum
+    }
+
+    pub fn global_checksum(&self) -> u16 {
+        self.global_checksum
+    }
 }
 
 impl std::fmt::Display for Header {
@@ -1,3 +1,5 @@
+use crate::state::{StateReader, StateWriter};
+
 const ROW0_FLAG: u8 = 0x10;
 const ROW1_FLAG: u8 = 0x20;
 
@@ -67,6 +69,21 @@ impl Keypad {
         self.data = (self.data & 0xF0) | new;
     }
 
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.data);
+        state.u8(self.row0);
+        state.u8(self.row1);
+        state.u8(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.data = state.u8()?;
+        self.row0 = state.u8()?;
+        self.row1 = state.u8()?;
+        self.interrupt = state.u8()?;
+        Ok(())
+    }
+
     /**
      * is_pressed
      */
@@ -1,13 +1,30 @@
 pub mod gameboy;
 mod header;
 mod cpu;
+pub mod bus;
 mod registers;
+mod rewind;
 mod memory;
 mod mbc;
 pub mod keypad;
 mod gpu;
+mod apu;
+mod bess;
+mod hdma;
 mod timer;
-mod serial;
+pub mod serial;
+#[cfg(not(target_family = "wasm"))]
+pub mod tcp_link;
+pub mod printer;
+pub mod four_player;
+pub mod mobile;
+pub mod test_rom;
+pub mod single_step;
+mod png;
+mod state;
+mod error;
+
+pub use error::Error;
 
 
 #[cfg(test)]
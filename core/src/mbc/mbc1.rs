@@ -1,11 +1,12 @@
-use std::cmp::max;
-
-use super::{get_number_ram_banks, get_number_rom_banks, MBC};
+use super::{get_number_ram_banks, get_number_rom_banks, load_ram_data, MBC};
+use crate::state::{StateReader, StateWriter};
 
 #[repr(C)]
 #[derive(PartialEq, Clone, Copy)]
 enum Mode {
+    // Simple banking: 0x0000-0x3FFF and 0xA000-0xBFFF are locked to bank 0
     Mode0,
+    // Advanced banking: the 2-bit register also applies to 0x0000-0x3FFF and 0xA000-0xBFFF
     Mode1,
 }
 
@@ -18,6 +19,18 @@ impl From<bool> for Mode {
     }
 }
 
+// Offset of the Nintendo logo in the header
+const LOGO_START: usize = 0x0104;
+
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/**
+* @see: https://gbdev.io/pandocs/MBC1.html
+*/
 pub struct MBC1 {
     rom: Vec<u8>,
     ram: Vec<u8>,
@@ -25,49 +38,91 @@ pub struct MBC1 {
     rom_banks_number: usize,
     ram_banks_number: usize,
 
-    rom_bank: usize,
-    ram_bank: usize,
+    // 0x2000-0x3FFF — Lower 5 bits of the ROM bank number
+    bank1: usize,
+    // 0x4000-0x5FFF — RAM bank number or upper 2 bits of the ROM bank number
+    bank2: usize,
     ram_enabled: bool,
     ram_updated: bool,
+    // 0x6000-0x7FFF — Banking mode select
     mode: Mode,
 
+    // MBC1M multicarts only wire 4 bits of the lower register, the upper register selects one of the games
+    multicart: bool,
+
     has_battery: bool,
 }
 
 impl MBC1 {
-    pub fn new(rom: &Vec<u8>) -> Self {
-        let (has_battery, ram_size) = match rom[0x0149] {
-            0x00 | 0x01 => (false, 0),
-            0x02 => (false, get_number_ram_banks(rom[0x0149])),
-            0x03 => (true, get_number_ram_banks(rom[0x0149])),
-            _ => panic!("Invalid MBC1 RAM size: {:02x}", rom[0x0149]),
+    pub fn new(rom: &[u8]) -> Result<Self, crate::Error> {
+        let (has_battery, ram_banks_number) = match rom[0x0147] {
+            0x02 => (false, get_number_ram_banks(rom[0x0149])?),
+            0x03 => (true, get_number_ram_banks(rom[0x0149])?),
+            _ => (false, 0),
         };
 
-        MBC1 {
+        Ok(MBC1 {
             rom: rom.to_vec(),
-            ram: std::iter::repeat(0).take(ram_size * 0x2000).collect(),
-            rom_bank: 1,
-            ram_bank: 0,
+            ram: vec![0; ram_banks_number * 0x2000],
+            bank1: 1,
+            bank2: 0,
 
             ram_enabled: false,
             ram_updated: false,
             mode: Mode::Mode0,
 
-            rom_banks_number: get_number_rom_banks(rom[0x0148]),
-            ram_banks_number: ram_size,
+            rom_banks_number: get_number_rom_banks(rom[0x0148])?,
+            ram_banks_number,
+
+            multicart: MBC1::is_multicart(rom),
+            has_battery,
+        })
+    }
+
+    // MBC1M carts are 1 MiB and every game has its own header, so the logo is also found at the start of bank 0x10
+    fn is_multicart(rom: &[u8]) -> bool {
+        let logo = 0x10 * 0x4000 + LOGO_START;
+        rom.len() == 64 * 0x4000 && rom[logo..logo + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+    }
+
+    fn upper_bits_shift(&self) -> usize {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
 
-            has_battery: has_battery,
+    fn rom_bank_low(&self) -> usize {
+        match self.mode {
+            Mode::Mode0 => 0,
+            Mode::Mode1 => self.bank2 << self.upper_bits_shift(),
         }
     }
+
+    fn rom_bank_high(&self) -> usize {
+        let bank1 = if self.multicart { self.bank1 & 0x0F } else { self.bank1 };
+        (self.bank2 << self.upper_bits_shift()) | bank1
+    }
+
+    fn ram_address(&self, address: u16) -> usize {
+        let bank = match self.mode {
+            Mode::Mode0 => 0,
+            Mode::Mode1 => self.bank2,
+        };
+        ((bank * 0x2000) | (address as usize & 0x1FFF)) % self.ram.len()
+    }
 }
 
 impl MBC for MBC1 {
     fn read_rom(&self, address: u16) -> u8 {
-        let bank = match (address < 0x4000, self.mode) {
-            (true, Mode::Mode0) => 0,
-            (true, _) => self.rom_bank & 0xE0,
-            _ => self.rom_bank,
+        let bank = if address < 0x4000 {
+            self.rom_bank_low()
+        } else {
+            self.rom_bank_high()
         };
+        // The bank number is masked to the size of the ROM
+        let bank = bank % self.rom_banks_number;
         let index = bank * 0x4000 + (address as usize & 0x3FFF);
         self.rom.get(index).copied().unwrap_or(0xFF)
     }
@@ -75,49 +130,175 @@ impl MBC for MBC1 {
     fn write_rom(&mut self, address: u16, value: u8) {
         match address {
             0x0000..=0x1FFF => self.ram_enabled = value & 0xF == 0xA, // Value with 0xa on the lowest but enable the RAM. Else disable.
-            0x2000..=0x3FFF => {
-                let rom_bank = max(1, value & 0x1f) as usize; // Bank is selected using 5 lower bits. 0x00 -> 0x01
-                self.rom_bank = ((self.rom_bank & 0x60) | rom_bank) % self.rom_banks_number;
-            }
-            0x4000..=0x5FFF => {
-                if self.rom_banks_number > 0x20 {
-                    let upper_bits = (value as usize & 0x03) % (self.ram_banks_number >> 5);
-                    self.rom_bank = (self.rom_bank & 0x1F) | (upper_bits << 5);
-                }
-                if self.rom_banks_number > 1 {
-                    self.rom_bank = (value as usize) & 0x03;
-                }
-                
-                //self.ram_bank = value as usize & 0x03;      // Redo : https://gbdev.io/pandocs/MBC1.html#40005fff--ram-bank-number--or--upper-bits-of-rom-bank-number-write-only
-            }
-            0x6000..=0x7FFF => self.mode = Mode::from(value & 0x01 == 0),
+            0x2000..=0x3FFF => self.bank1 = (value & 0x1F).max(1) as usize, // Bank is selected using 5 lower bits. 0x00 -> 0x01
+            0x4000..=0x5FFF => self.bank2 = value as usize & 0x03,
+            0x6000..=0x7FFF => self.mode = Mode::from(value & 0x01 == 1),
             _ => { /* Do nothing */ }
         }
     }
 
     fn read_ram(&self, address: u16) -> u8 {
-        if !self.ram_enabled { return 0xFF; }
-        let bank = if self.mode == Mode::Mode0 { 0 } else { self.ram_bank };
-        let offset = (address as usize) & 0x1FFF;
-        self.ram[bank * 0x2000 | offset]
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xFF;
+        }
+        self.ram[self.ram_address(address)]
     }
 
     fn write_ram(&mut self, address: u16, value: u8) {
-        if !self.ram_enabled { return }
-        let rambank = if self.mode == Mode::Mode0 { 0 } else { self.ram_bank };
-        let address = (rambank * 0x2000) | ((address & 0x1FFF) as usize);
-        if address < self.ram.len() {
-            self.ram[address] = value;
-            self.ram_updated = true;
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
         }
+        let address = self.ram_address(address);
+        self.ram[address] = value;
+        self.ram_updated = true;
     }
 
     fn has_battery(&self) -> bool { self.has_battery }
 
     fn info(&self) -> String {
         format!(
-            "MBC1: {:02x}, {:02x}, {}, {}",
-            self.rom_bank, self.ram_bank, self.ram_enabled, self.mode == Mode::Mode1
+            "MBC1{}: {:02x}, {:02x}, {}, {}, RAM banks: {}",
+            if self.multicart { "M" } else { "" },
+            self.rom_bank_high() % self.rom_banks_number,
+            self.bank2,
+            self.ram_enabled,
+            self.mode == Mode::Mode1,
+            self.ram_banks_number
         )
     }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), crate::Error> {
+        load_ram_data(&mut self.ram, data)
+    }
+
+    fn check_ram_updated(&mut self) -> bool {
+        std::mem::take(&mut self.ram_updated)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn bank_writes(&self) -> Vec<(u16, u8)> {
+        vec![
+            (0x0000, if self.ram_enabled { 0x0A } else { 0x00 }),
+            (0x2000, self.bank1 as u8),
+            (0x4000, self.bank2 as u8),
+            (0x6000, (self.mode == Mode::Mode1) as u8),
+        ]
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.bank1 as u8);
+        state.u8(self.bank2 as u8);
+        state.bool(self.ram_enabled);
+        state.bool(self.mode == Mode::Mode1);
+        state.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.bank1 = (state.u8()? & 0x1F).max(1) as usize;
+        self.bank2 = (state.u8()? & 0x03) as usize;
+        self.ram_enabled = state.bool()?;
+        self.mode = Mode::from(state.bool()?);
+        state.bytes_into(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_rom(banks: usize, rom_size: u8, cartridge_type: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; banks * 0x4000];
+        for bank in 0..banks {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom[0x0147] = cartridge_type;
+        rom[0x0148] = rom_size;
+        rom[0x0149] = ram_size;
+        rom
+    }
+
+    #[test]
+    fn test_mbc1_large_rom() {
+        // 2 MiB
+        let mut mbc = MBC1::new(&new_rom(128, 0x06, 0x01, 0x00)).unwrap();
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x01);
+
+        // 0x20 can not be selected, 0x21 is mapped instead
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x21);
+        assert_eq!(mbc.read_rom(0x0000), 0x00);
+
+        // Mode 1 maps bank 0x20 at 0x0000-0x3FFF
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x20);
+
+        mbc.write_rom(0x2000, 0x1F);
+        mbc.write_rom(0x4000, 0x03);
+        assert_eq!(mbc.read_rom(0x4000), 0x7F);
+    }
+
+    #[test]
+    fn test_mbc1_bank_masked_to_rom_size() {
+        // 256 KiB: the upper bits are ignored
+        let mut mbc = MBC1::new(&new_rom(16, 0x03, 0x01, 0x00)).unwrap();
+        mbc.write_rom(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(0x4000), 0x02);
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_mbc1_ram_banking() {
+        let mut mbc = MBC1::new(&new_rom(4, 0x01, 0x03, 0x03)).unwrap();
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x02);
+        mbc.write_ram(0xA000, 0x11);
+
+        // Mode 1 is needed to use the RAM banks
+        mbc.write_rom(0x6000, 0x01);
+        mbc.write_ram(0xA000, 0x22);
+        assert_eq!(mbc.read_ram(0xA000), 0x22);
+        mbc.write_rom(0x6000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0x11);
+
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+        assert!(mbc.has_battery());
+    }
+
+    #[test]
+    fn test_mbc1_not_multicart() {
+        let mbc = MBC1::new(&new_rom(64, 0x05, 0x01, 0x00)).unwrap();
+        assert!(!mbc.multicart);
+    }
+
+    #[test]
+    fn test_mbc1_multicart() {
+        let mut rom = new_rom(64, 0x05, 0x01, 0x00);
+        for game in 0..4 {
+            let offset = game * 0x10 * 0x4000;
+            rom[offset + LOGO_START..offset + LOGO_START + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        }
+        let mut mbc = MBC1::new(&rom).unwrap();
+        assert!(mbc.multicart);
+
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(0x4000), 0x12);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x10);
+    }
 }
@@ -0,0 +1,171 @@
+use super::{get_number_rom_banks, load_ram_data, MBC};
+use crate::state::{StateReader, StateWriter};
+
+// 512 half-bytes of built-in RAM
+const RAM_SIZE: usize = 0x200;
+
+/**
+* @see: https://gbdev.io/pandocs/MBC2.html
+*/
+pub struct MBC2 {
+    rom: Vec<u8>,
+    ram: [u8; RAM_SIZE],
+
+    rom_banks_number: usize,
+
+    rom_bank: usize,
+    ram_enabled: bool,
+    ram_updated: bool,
+
+    has_battery: bool,
+}
+
+impl MBC2 {
+    pub fn new(rom: &[u8]) -> Result<Self, crate::Error> {
+        Ok(MBC2 {
+            rom: rom.to_vec(),
+            ram: [0; RAM_SIZE],
+            rom_banks_number: get_number_rom_banks(rom[0x0148])?,
+            rom_bank: 1,
+            ram_enabled: false,
+            ram_updated: false,
+            has_battery: rom[0x0147] == 0x06,
+        })
+    }
+}
+
+impl MBC for MBC2 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 { 0 } else { self.rom_bank };
+        let index = bank * 0x4000 + (address as usize & 0x3FFF);
+        self.rom.get(index).copied().unwrap_or(0xFF)
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        if address >= 0x4000 {
+            return;
+        }
+        // Bit 8 of the address selects the register
+        if address & 0x0100 == 0 {
+            self.ram_enabled = value & 0x0F == 0x0A;
+        } else {
+            let rom_bank = (value & 0x0F).max(1) as usize; // 0x00 -> 0x01
+            self.rom_bank = rom_bank % self.rom_banks_number;
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        // Only the lower 4 bits are stored, the upper bits read as 1
+        0xF0 | self.ram[address as usize & (RAM_SIZE - 1)]
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        self.ram[address as usize & (RAM_SIZE - 1)] = value & 0x0F;
+        self.ram_updated = true;
+    }
+
+    fn has_battery(&self) -> bool {
+        self.has_battery
+    }
+
+    fn info(&self) -> String {
+        format!(
+            "MBC2: ROM bank: {:02X}, RAM enabled: {}",
+            self.rom_bank, self.ram_enabled
+        )
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), crate::Error> {
+        load_ram_data(&mut self.ram, data)?;
+        for value in self.ram.iter_mut() {
+            *value &= 0x0F;
+        }
+        Ok(())
+    }
+
+    fn check_ram_updated(&mut self) -> bool {
+        std::mem::take(&mut self.ram_updated)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn bank_writes(&self) -> Vec<(u16, u8)> {
+        vec![
+            (0x0000, if self.ram_enabled { 0x0A } else { 0x00 }),
+            (0x0100, self.rom_bank as u8),
+        ]
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.rom_bank as u8);
+        state.bool(self.ram_enabled);
+        state.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.rom_bank = state.u8()? as usize % self.rom_banks_number;
+        self.ram_enabled = state.bool()?;
+        state.bytes_into(&mut self.ram)?;
+        for value in self.ram.iter_mut() {
+            *value &= 0x0F;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_mbc2() -> MBC2 {
+        let mut rom = vec![0; 0x4000 * 16];
+        rom[0x0147] = 0x06;
+        rom[0x0148] = 0x03;
+        for bank in 0..16 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        MBC2::new(&rom).unwrap()
+    }
+
+    #[test]
+    fn test_mbc2_registers_on_address_bit_8() {
+        let mut mbc = new_mbc2();
+        mbc.write_rom(0x2100, 0x03);
+        assert_eq!(mbc.read_rom(0x4000), 3);
+
+        // Bit 8 clear: RAM enable, the ROM bank does not change
+        mbc.write_rom(0x2000, 0x0A);
+        assert_eq!(mbc.read_rom(0x4000), 3);
+        assert_eq!(mbc.read_ram(0xA000), 0xF0);
+
+        mbc.write_rom(0x0100, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+    }
+
+    #[test]
+    fn test_mbc2_ram_echo() {
+        let mut mbc = new_mbc2();
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA001, 0xAB);
+        assert_eq!(mbc.read_ram(0xA001), 0xFB);
+        assert_eq!(mbc.read_ram(0xA201), 0xFB);
+        assert_eq!(mbc.read_ram(0xBE01), 0xFB);
+        assert!(mbc.has_battery());
+    }
+}
@@ -0,0 +1,455 @@
+use super::{get_number_ram_banks, get_number_rom_banks, load_ram_data, MBC};
+use crate::state::{StateReader, StateWriter};
+
+#[cfg(not(target_family = "wasm"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(target_family = "wasm")]
+use instant::SystemTime;
+#[cfg(target_family = "wasm")]
+const UNIX_EPOCH: SystemTime = SystemTime::UNIX_EPOCH;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// RTC footer appended to the RAM in .sav files (BGB/VBA layout):
+// 5 current registers and 5 latched registers as u32, then the unix timestamp as u64
+const RTC_SAVE_SIZE: usize = 10 * 4 + 8;
+// Older saves store the timestamp as u32
+const RTC_SAVE_SIZE_32: usize = 10 * 4 + 4;
+
+/// Source of time for the real-time clock
+pub trait RtcClock: Send {
+    /// Current time in seconds, only the difference between two calls matters
+    fn now(&self) -> u64;
+}
+
+/// Wall clock of the host
+pub struct SystemClock;
+
+impl RtcClock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/**
+* @see: https://gbdev.io/pandocs/MBC3.html#the-clock-counter-registers
+*/
+#[derive(Clone, Copy, Default)]
+struct Rtc {
+    // 0x08 — RTC S: Seconds 0-59
+    seconds: u8,
+    // 0x09 — RTC M: Minutes 0-59
+    minutes: u8,
+    // 0x0A — RTC H: Hours 0-23
+    hours: u8,
+    // 0x0B — RTC DL: Lower 8 bits of the day counter
+    // 0x0C — RTC DH: Bit 0 is the upper bit of the day counter
+    days: u16,
+    // 0x0C — RTC DH: Bit 6 halts the clock
+    halt: bool,
+    // 0x0C — RTC DH: Bit 7 is set when the day counter overflows
+    day_carry: bool,
+}
+
+impl Rtc {
+    fn read(&self, register: usize) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.days as u8,
+            _ => {
+                ((self.day_carry as u8) << 7)
+                    | ((self.halt as u8) << 6)
+                    | (self.days >> 8) as u8
+            }
+        }
+    }
+
+    fn write(&mut self, register: usize, value: u8) {
+        match register {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            _ => {
+                self.days = (self.days & 0xFF) | ((value as u16 & 0x01) << 8);
+                self.halt = value & 0x40 != 0;
+                self.day_carry = value & 0x80 != 0;
+            }
+        }
+    }
+
+    fn dump(&self, data: &mut Vec<u8>) {
+        for register in 0x08..=0x0C {
+            data.extend_from_slice(&(self.read(register) as u32).to_le_bytes());
+        }
+    }
+
+    fn load(&mut self, data: &[u8]) {
+        for (register, value) in (0x08..=0x0C).zip(data.chunks_exact(4)) {
+            self.write(register, value[0]);
+        }
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        if self.halt || seconds == 0 {
+            return;
+        }
+
+        let total = seconds
+            + self.seconds as u64
+            + self.minutes as u64 * 60
+            + self.hours as u64 * 60 * 60
+            + self.days as u64 * SECONDS_PER_DAY;
+
+        self.seconds = (total % 60) as u8;
+        self.minutes = ((total / 60) % 60) as u8;
+        self.hours = ((total / (60 * 60)) % 24) as u8;
+
+        let days = total / SECONDS_PER_DAY;
+        if days > 0x1FF {
+            // The carry stays set until the game clears it
+            self.day_carry = true;
+        }
+        self.days = (days & 0x1FF) as u16;
+    }
+}
+
+pub struct MBC3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+
+    rom_banks_number: usize,
+    ram_banks_number: usize,
+
+    rom_bank: usize,
+    // 0x00-0x07: RAM bank, 0x08-0x0C: RTC register
+    ram_bank: usize,
+    ram_enabled: bool,
+    ram_updated: bool,
+
+    has_battery: bool,
+    has_rtc: bool,
+
+    clock: Box<dyn RtcClock>,
+    rtc: Rtc,
+    rtc_latched: Rtc,
+    // Clock time of the last RTC update
+    rtc_last_time: u64,
+    // Last value written in 0x6000-0x7FFF, latching happens on a 0x00 -> 0x01 sequence
+    latch_value: u8,
+}
+
+impl MBC3 {
+    pub fn new(rom: &[u8]) -> Result<Self, crate::Error> {
+        MBC3::with_clock(rom, Box::new(SystemClock))
+    }
+
+    pub fn with_clock(rom: &[u8], clock: Box<dyn RtcClock>) -> Result<Self, crate::Error> {
+        let cartridge_type = rom[0x0147];
+        let has_battery = matches!(cartridge_type, 0x0F | 0x10 | 0x13);
+        let has_rtc = matches!(cartridge_type, 0x0F | 0x10);
+        let ram_banks_number = match cartridge_type {
+            0x10 | 0x12 | 0x13 => get_number_ram_banks(rom[0x0149])?,
+            _ => 0,
+        };
+
+        let rtc_last_time = clock.now();
+
+        Ok(MBC3 {
+            rom: rom.to_vec(),
+            ram: vec![0; ram_banks_number * 0x2000],
+            rom_banks_number: get_number_rom_banks(rom[0x0148])?,
+            ram_banks_number,
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            ram_updated: false,
+            has_battery,
+            has_rtc,
+            clock,
+            rtc: Rtc::default(),
+            rtc_latched: Rtc::default(),
+            rtc_last_time,
+            latch_value: 0xFF,
+        })
+    }
+
+    fn update_rtc(&mut self) {
+        let now = self.clock.now();
+        self.rtc.advance(now.saturating_sub(self.rtc_last_time));
+        self.rtc_last_time = now;
+    }
+}
+
+impl MBC for MBC3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 { 0 } else { self.rom_bank };
+        let index = bank * 0x4000 + (address as usize & 0x3FFF);
+        self.rom.get(index).copied().unwrap_or(0xFF)
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A, // Enables both the RAM and the RTC registers
+            0x2000..=0x3FFF => {
+                let rom_bank = (value & 0x7F).max(1) as usize; // 0x00 -> 0x01
+                self.rom_bank = rom_bank % self.rom_banks_number;
+            }
+            0x4000..=0x5FFF => self.ram_bank = value as usize & 0x0F,
+            0x6000..=0x7FFF => {
+                if self.has_rtc && self.latch_value == 0x00 && value == 0x01 {
+                    self.update_rtc();
+                    self.rtc_latched = self.rtc;
+                }
+                self.latch_value = value;
+            }
+            _ => { /* Do nothing */ }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match self.ram_bank {
+            0x00..=0x07 if self.ram_banks_number > 0 => {
+                let bank = self.ram_bank % self.ram_banks_number;
+                self.ram[(bank * 0x2000) | (address as usize & 0x1FFF)]
+            }
+            0x08..=0x0C if self.has_rtc => self.rtc_latched.read(self.ram_bank),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        match self.ram_bank {
+            0x00..=0x07 if self.ram_banks_number > 0 => {
+                let bank = self.ram_bank % self.ram_banks_number;
+                self.ram[(bank * 0x2000) | (address as usize & 0x1FFF)] = value;
+                self.ram_updated = true;
+            }
+            0x08..=0x0C if self.has_rtc => {
+                self.update_rtc();
+                self.rtc.write(self.ram_bank, value);
+                self.ram_updated = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.has_battery
+    }
+
+    fn info(&self) -> String {
+        format!(
+            "MBC3: ROM bank: {:02X}, RAM bank: {:02X}, RAM enabled: {}, RTC: {}",
+            self.rom_bank, self.ram_bank, self.ram_enabled, self.has_rtc
+        )
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        let mut data = self.ram.to_vec();
+        if self.has_rtc {
+            let mut rtc = self.rtc;
+            rtc.advance(self.clock.now().saturating_sub(self.rtc_last_time));
+            rtc.dump(&mut data);
+            self.rtc_latched.dump(&mut data);
+            data.extend_from_slice(&self.clock.now().to_le_bytes());
+        }
+        data
+    }
+
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), crate::Error> {
+        let ram_size = self.ram.len();
+        let rtc_size = data.len().saturating_sub(ram_size);
+        if !self.has_rtc || (rtc_size != RTC_SAVE_SIZE && rtc_size != RTC_SAVE_SIZE_32) {
+            return load_ram_data(&mut self.ram, data);
+        }
+
+        load_ram_data(&mut self.ram, &data[..ram_size])?;
+        let rtc = &data[ram_size..];
+        self.rtc.load(&rtc[0..20]);
+        self.rtc_latched.load(&rtc[20..40]);
+
+        let mut timestamp = [0; 8];
+        timestamp[..rtc_size - 40].copy_from_slice(&rtc[40..]);
+        let timestamp = u64::from_le_bytes(timestamp);
+
+        // Catch up with the time elapsed since the save
+        let now = self.clock.now();
+        self.rtc.advance(now.saturating_sub(timestamp));
+        self.rtc_last_time = now;
+        Ok(())
+    }
+
+    fn check_ram_updated(&mut self) -> bool {
+        std::mem::take(&mut self.ram_updated)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn bank_writes(&self) -> Vec<(u16, u8)> {
+        vec![
+            (0x0000, if self.ram_enabled { 0x0A } else { 0x00 }),
+            (0x2000, self.rom_bank as u8),
+            (0x4000, self.ram_bank as u8),
+        ]
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.rom_bank as u8);
+        state.u8(self.ram_bank as u8);
+        state.bool(self.ram_enabled);
+        state.u8(self.latch_value);
+        // Same layout as the .sav file, the RTC catches up with the time elapsed since the save
+        state.bytes(&self.dump_ram());
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.rom_bank = state.u8()? as usize % self.rom_banks_number;
+        self.ram_bank = state.u8()? as usize & 0x0F;
+        self.ram_enabled = state.bool()?;
+        self.latch_value = state.u8()?;
+        self.load_ram(state.bytes()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    struct TestClock(Arc<AtomicU64>);
+
+    impl RtcClock for TestClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    fn new_mbc3() -> (MBC3, Arc<AtomicU64>) {
+        let mut rom = vec![0; 0x4000 * 8];
+        rom[0x0147] = 0x10;
+        rom[0x0148] = 0x02;
+        rom[0x0149] = 0x03;
+        for bank in 0..8 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        let time = Arc::new(AtomicU64::new(1_000));
+        (MBC3::with_clock(&rom, Box::new(TestClock(time.clone()))).unwrap(), time)
+    }
+
+    fn latch(mbc: &mut MBC3) {
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+    }
+
+    #[test]
+    fn test_mbc3_rom_banking() {
+        let (mut mbc, _) = new_mbc3();
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(mbc.read_rom(0x4000), 5);
+    }
+
+    #[test]
+    fn test_mbc3_ram_banking() {
+        let (mut mbc, _) = new_mbc3();
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x02);
+        mbc.write_ram(0xA000, 0x42);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_ram(0xA000), 0x42);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_latch() {
+        let (mut mbc, time) = new_mbc3();
+        mbc.write_rom(0x0000, 0x0A);
+
+        time.fetch_add(SECONDS_PER_DAY + 3661, Ordering::SeqCst);
+        mbc.write_rom(0x4000, 0x08);
+        assert_eq!(mbc.read_ram(0xA000), 0);
+
+        latch(&mut mbc);
+        let registers: Vec<u8> = (0x08..=0x0C)
+            .map(|register| {
+                mbc.write_rom(0x4000, register);
+                mbc.read_ram(0xA000)
+            })
+            .collect();
+        assert_eq!(registers, vec![1, 1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn test_mbc3_save_with_rtc() {
+        let (mut mbc, time) = new_mbc3();
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA000, 0x42);
+        mbc.write_rom(0x4000, 0x0A);
+        mbc.write_ram(0xA000, 0x05);
+        assert!(mbc.check_ram_updated());
+        assert!(!mbc.check_ram_updated());
+
+        let save = mbc.dump_ram();
+        assert_eq!(save.len(), 4 * 0x2000 + RTC_SAVE_SIZE);
+
+        // Two hours pass while the game is not running
+        time.fetch_add(2 * 60 * 60, Ordering::SeqCst);
+        let (mut restored, _) = new_mbc3();
+        restored.clock = Box::new(TestClock(time.clone()));
+        restored.load_ram(&save).unwrap();
+        restored.write_rom(0x0000, 0x0A);
+        assert_eq!(restored.read_ram(0xA000), 0x42);
+
+        latch(&mut restored);
+        restored.write_rom(0x4000, 0x0A);
+        assert_eq!(restored.read_ram(0xA000), 0x07);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_halt_and_carry() {
+        let (mut mbc, time) = new_mbc3();
+        mbc.write_rom(0x0000, 0x0A);
+
+        // Halt the clock on day 511
+        mbc.write_rom(0x4000, 0x0B);
+        mbc.write_ram(0xA000, 0xFF);
+        mbc.write_rom(0x4000, 0x0C);
+        mbc.write_ram(0xA000, 0x41);
+
+        time.fetch_add(SECONDS_PER_DAY, Ordering::SeqCst);
+        latch(&mut mbc);
+        assert_eq!(mbc.read_ram(0xA000), 0x41);
+
+        // Resume: the day counter overflows
+        mbc.write_ram(0xA000, 0x01);
+        time.fetch_add(SECONDS_PER_DAY, Ordering::SeqCst);
+        latch(&mut mbc);
+        assert_eq!(mbc.read_ram(0xA000), 0x80);
+        mbc.write_rom(0x4000, 0x0B);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+    }
+}
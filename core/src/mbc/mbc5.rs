@@ -1,5 +1,6 @@
-use super::{get_number_ram_banks, get_number_rom_banks};
+use super::{get_number_ram_banks, get_number_rom_banks, load_ram_data};
 use crate::mbc::MBC;
+use crate::state::{StateReader, StateWriter};
 
 pub struct MBC5 {
     rom: Vec<u8>,
@@ -7,24 +8,25 @@ pub struct MBC5 {
     rom_bank: usize,
     ram_bank: usize,
     ram_on: bool,
+    ram_updated: bool,
     has_battery: bool,
     rombanks: usize,
     rambanks: usize,
 }
 
 impl MBC5 {
-    pub fn new(data: &Vec<u8>) -> MBC5 {
+    pub fn new(data: &Vec<u8>) -> Result<MBC5, crate::Error> {
         let rom_type = data[0x147];
         let has_battery = match rom_type {
             0x1B | 0x1E => true,
             _ => false,
         };
         let rambanks = match rom_type {
-            0x1A | 0x1B | 0x1D | 0x1E => get_number_ram_banks(data[0x149]),
+            0x1A | 0x1B | 0x1D | 0x1E => get_number_ram_banks(data[0x149])?,
             _ => 0,
         };
         let ramsize = 0x2000 * rambanks;
-        let rombanks = get_number_rom_banks(data[0x148]);
+        let rombanks = get_number_rom_banks(data[0x148])?;
 
         let res = MBC5 {
             rom: data.to_vec(),
@@ -32,11 +34,12 @@ impl MBC5 {
             rom_bank: 1,
             ram_bank: 0,
             ram_on: false,
+            ram_updated: false,
             has_battery,
             rombanks,
             rambanks,
         };
-        res
+        Ok(res)
     }
 }
 
@@ -51,8 +54,9 @@ impl MBC for MBC5 {
         self.rom.get(index).copied().unwrap_or(0x00)
     }
     fn read_ram(&self, a: u16) -> u8 {
-        if !self.ram_on {
-            return 0;
+        // Nothing drives the bus without RAM
+        if !self.ram_on || self.ram.is_empty() {
+            return 0xFF;
         }
         self.ram[self.ram_bank * 0x2000 | ((a as usize) & 0x1FFF)]
     }
@@ -66,16 +70,17 @@ impl MBC for MBC5 {
                 self.rom_bank =
                     ((self.rom_bank & 0x0FF) | (((v & 0x1) as usize) << 8)) % self.rombanks
             }
-            0x4000..=0x5FFF => self.ram_bank = ((v & 0x0F) as usize) % self.rambanks,
+            0x4000..=0x5FFF => self.ram_bank = ((v & 0x0F) as usize) % self.rambanks.max(1),
             0x6000..=0x7FFF => {}
-            _ => panic!("Could not write to {:04X} (MBC5)", a),
+            _ => {}
         }
     }
     fn write_ram(&mut self, a: u16, v: u8) {
-        if self.ram_on == false {
+        if self.ram_on == false || self.ram.is_empty() {
             return;
         }
         self.ram[self.ram_bank * 0x2000 | ((a as usize) & 0x1FFF)] = v;
+        self.ram_updated = true;
     }
 
     fn has_battery(&self) -> bool {
@@ -88,4 +93,65 @@ impl MBC for MBC5 {
             self.rom_bank, self.ram_bank, self.ram_on
         )
     }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), crate::Error> {
+        load_ram_data(&mut self.ram, data)
+    }
+
+    fn check_ram_updated(&mut self) -> bool {
+        std::mem::take(&mut self.ram_updated)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn bank_writes(&self) -> Vec<(u16, u8)> {
+        vec![
+            (0x0000, if self.ram_on { 0x0A } else { 0x00 }),
+            (0x2000, self.rom_bank as u8),
+            (0x3000, (self.rom_bank >> 8) as u8),
+            (0x4000, self.ram_bank as u8),
+        ]
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.rom_bank as u16);
+        state.u8(self.ram_bank as u8);
+        state.bool(self.ram_on);
+        state.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.rom_bank = state.u16()? as usize % self.rombanks;
+        self.ram_bank = state.u8()? as usize % self.rambanks.max(1);
+        self.ram_on = state.bool()?;
+        state.bytes_into(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mbc5_without_ram() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x19; // MBC5 without RAM
+        let mut mbc = MBC5::new(&rom).unwrap();
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+        assert!(!mbc.check_ram_updated());
+    }
 }
@@ -1,8 +1,15 @@
 use mbc1::MBC1;
+use mbc2::MBC2;
+use mbc3::MBC3;
 use mbc5::MBC5;
 use no_mbc::NoMBC;
 
+use crate::state::{StateReader, StateWriter};
+use crate::Error;
+
 mod mbc1;
+mod mbc2;
+mod mbc3;
 mod no_mbc;
 mod mbc5;
 
@@ -13,52 +20,103 @@ pub trait MBC: Send {
     fn write_ram(&mut self, address: u16, value: u8);
     fn has_battery(&self) -> bool;
     fn info(&self) -> String;
+
+    /// Content of the external RAM, followed by the RTC state for clock carts (same layout as a .sav file)
+    fn dump_ram(&self) -> Vec<u8>;
+    /// Restore the external RAM from data produced by `dump_ram`
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), Error>;
+    /// Return true if the external RAM was written since the last call
+    fn check_ram_updated(&mut self) -> bool;
+
+    /// Raw external RAM, without the RTC
+    fn ram(&self) -> &[u8];
+    fn ram_mut(&mut self) -> &mut [u8];
+    /// Register writes that bring a freshly reset MBC into its current banking state
+    fn bank_writes(&self) -> Vec<(u16, u8)>;
+
+    /// Banking registers and RAM, the ROM itself is not part of the state
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error>;
 }
 
-pub fn from_rom(rom: &Vec<u8>) -> Result<Box<dyn MBC>, std::io::Error> {
+pub fn from_rom(rom: &Vec<u8>) -> Result<Box<dyn MBC>, Error> {
     if rom.len() <= 0x0150 {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid ROM size",
-        ));
+        return Err(Error::InvalidHeader);
+    }
+
+    let expected = get_number_rom_banks(rom[0x148])? * 0x4000;
+    if rom.len() != expected {
+        return Err(Error::RomSizeMismatch { expected, actual: rom.len() });
     }
 
     match rom[0x147] {
         0x00 => Ok(Box::new(NoMBC::new(rom))),
-        0x01..=0x03 => Ok(Box::new(MBC1::new(rom))),
-        0x19..=0x1E => Ok(Box::new(MBC5::new(rom))),
-        _ => Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            format!("Unsupported MBC type: {:02x}", rom[0x147]),
-        )),
+        0x01..=0x03 => Ok(Box::new(MBC1::new(rom)?)),
+        0x05..=0x06 => Ok(Box::new(MBC2::new(rom)?)),
+        0x0F..=0x13 => Ok(Box::new(MBC3::new(rom)?)),
+        0x19..=0x1E => Ok(Box::new(MBC5::new(rom)?)),
+        other => Err(Error::UnsupportedCartridge(other)),
     }
 }
 
-fn get_number_rom_banks(value: u8) -> usize {
+fn load_ram_data(ram: &mut [u8], data: &[u8]) -> Result<(), Error> {
+    if data.len() != ram.len() {
+        return Err(Error::InvalidSaveSize { expected: ram.len(), actual: data.len() });
+    }
+    ram.copy_from_slice(data);
+    Ok(())
+}
+
+fn get_number_rom_banks(value: u8) -> Result<usize, Error> {
     match value {
-        0x00 => 2,
-        0x01 => 4,
-        0x02 => 8,
-        0x03 => 16,
-        0x04 => 32,
-        0x05 => 64,
-        0x06 => 128,
-        0x07 => 256,
-        0x52 => 72,
-        0x53 => 80,
-        0x54 => 96,
-        _ => panic!("Invalid MBC1 bank size: {:02x}", value),
+        0x00 => Ok(2),
+        0x01 => Ok(4),
+        0x02 => Ok(8),
+        0x03 => Ok(16),
+        0x04 => Ok(32),
+        0x05 => Ok(64),
+        0x06 => Ok(128),
+        0x07 => Ok(256),
+        0x08 => Ok(512),
+        0x52 => Ok(72),
+        0x53 => Ok(80),
+        0x54 => Ok(96),
+        _ => Err(Error::InvalidRomSize(value)),
     }
 }
 
-fn get_number_ram_banks(value: u8) -> usize {
+fn get_number_ram_banks(value: u8) -> Result<usize, Error> {
     match value {
-        0x00 => 0,
-        0x01 => 1,
-        0x02 => 1,
-        0x03 => 4,
-        0x04 => 16,
-        0x05 => 8,
-        _ => panic!("Invalid MBC1 RAM bank size: {:02x}", value),
+        0x00 => Ok(0),
+        0x01 => Ok(1),
+        0x02 => Ok(1),
+        0x03 => Ok(4),
+        0x04 => Ok(16),
+        0x05 => Ok(8),
+        _ => Err(Error::InvalidRamSize(value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(cartridge_type: u8, rom_size: u8, length: usize) -> Vec<u8> {
+        let mut rom = vec![0; length];
+        rom[0x147] = cartridge_type;
+        rom[0x148] = rom_size;
+        rom
+    }
+
+    #[test]
+    fn test_from_rom_errors() {
+        assert!(from_rom(&rom(0x01, 0x01, 0x10000)).is_ok());
+        assert!(matches!(from_rom(&vec![0; 0x100]), Err(Error::InvalidHeader)));
+        assert!(matches!(from_rom(&rom(0xFC, 0x00, 0x8000)), Err(Error::UnsupportedCartridge(0xFC))));
+        assert!(matches!(from_rom(&rom(0x00, 0x42, 0x8000)), Err(Error::InvalidRomSize(0x42))));
+        assert!(matches!(
+            from_rom(&rom(0x01, 0x02, 0x10000)),
+            Err(Error::RomSizeMismatch { expected: 0x20000, actual: 0x10000 })
+        ));
     }
 }
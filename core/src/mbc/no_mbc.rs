@@ -1,4 +1,5 @@
-use super::MBC;
+use super::{load_ram_data, MBC};
+use crate::state::{StateReader, StateWriter};
 
 pub struct NoMBC {
     rom: Vec<u8>,
@@ -30,4 +31,34 @@ impl MBC for NoMBC {
     fn info(&self) -> String {
         "No MBC".to_string()
     }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), crate::Error> {
+        load_ram_data(&mut [], data)
+    }
+
+    fn check_ram_updated(&mut self) -> bool {
+        false
+    }
+
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    fn bank_writes(&self) -> Vec<(u16, u8)> {
+        Vec::new()
+    }
+
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), crate::Error> {
+        Ok(())
+    }
 }
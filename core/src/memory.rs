@@ -1,75 +1,121 @@
-use crate::{gpu::GPU, keypad::Keypad, mbc::MBC, serial::Serial, timer::Timer};
+use crate::{apu::Apu, gameboy::{GBMode, Model}, gpu::GPU, hdma::{Hdma, HdmaMode, HDMA_BLOCK_SIZE}, keypad::Keypad, mbc::MBC, serial::Serial, state::{StateReader, StateWriter}, timer::Timer};
 
-const ROM_SIZE: usize = 0x8000;
-const WRAM_SIZE: usize = 0x2000;
+// 8 banks of 4 KiB, only the first two are used on DMG
+const WRAM_SIZE: usize = 0x8000;
 const HRAM_SIZE: usize = 0x7F;
 
+// Cycles the CPU is halted for each block copied by the VRAM DMA
+const HDMA_BLOCK_CYCLES: u32 = 32;
+
+// Size of the DMG/MGB/SGB boot ROMs, mapped over 0x0000-0x00FF
+pub const BOOT_ROM_SIZE: usize = 0x100;
+// Size of the CGB boot ROM, also mapped over 0x0200-0x08FF (0x0100-0x01FF is the cartridge header)
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
 pub struct Memory {
+    gbmode: GBMode,
+
     pub mbc: Box<dyn MBC+'static>,
     pub gpu: GPU,
+    pub apu: Apu,
     
     pub keypad: Keypad,
     timer: Timer,
-    serial: Serial,
+    pub serial: Serial,
+    hdma: Hdma,
+
+    // === BOOT (0xFF50) === see https://gbdev.io/pandocs/Power_Up_Sequence.html#monochrome-models-dmg0-dmg-mgb
+    boot_rom: Vec<u8>,
+    boot_rom_mapped: bool,
 
     pub interrupt_flags: u8,
     pub interrupt_enable: u8,
 
-    wram: [u8; WRAM_SIZE],
+    pub wram: [u8; WRAM_SIZE],
     wram_bank: u8,
-    hram: [u8; HRAM_SIZE],
+    pub hram: [u8; HRAM_SIZE],
+
+    // === KEY1 (0xFF4D) === see https://gbdev.io/pandocs/CGB_Registers.html#ff4d--key1-cgb-mode-only-prepare-speed-switch
+    double_speed: bool,
+    speed_switch_armed: bool,
+
+    // Cycles during which the CPU is stalled by a VRAM DMA, charged by the CPU
+    pub dma_cycles: u32,
 }
 
 impl Memory {
-    pub fn new(mbc: Box<dyn MBC+'static>) -> Memory {
+    /// Without a boot ROM, the memory starts in the state left by the boot ROM
+    pub fn new(mbc: Box<dyn MBC+'static>, gbmode: GBMode, boot_rom: Option<Vec<u8>>) -> Memory {
         let mut memory = Memory {
+            gbmode,
             mbc,
-            gpu: GPU::new(),
+            gpu: match gbmode {
+                GBMode::DMG => GPU::new(),
+                GBMode::CGB => GPU::new_cgb(),
+            },
+            apu: Apu::new(),
             
             keypad: Keypad::new(),
-            serial: Serial::new(),
+            serial: Serial::new(gbmode == GBMode::CGB),
             timer: Timer::new(),
+            hdma: Hdma::new(),
+
+            boot_rom_mapped: boot_rom.is_some(),
+            boot_rom: boot_rom.unwrap_or_default(),
 
             wram: [0; WRAM_SIZE],
-            wram_bank: 0,
+            wram_bank: 1,
             hram: [0; HRAM_SIZE],
+            double_speed: false,
+            speed_switch_armed: false,
+            dma_cycles: 0,
 
             interrupt_flags: 0,
             interrupt_enable: 0,
         };
-        memory.init();
+        if !memory.boot_rom_mapped {
+            memory.init();
+        }
         memory
     }
 
+    pub fn mode(&self) -> GBMode {
+        self.gbmode
+    }
+
     pub fn read(&self, address: u16) -> u8 {
         match address {
+            0x0000..=0x00FF if self.boot_rom_mapped => self.boot_rom[address as usize], // Boot ROM
+            0x0200..=0x08FF if self.boot_rom_mapped && self.boot_rom.len() == CGB_BOOT_ROM_SIZE => {
+                self.boot_rom[address as usize]
+            } // CGB boot ROM
             0x0000..=0x7FFF => self.mbc.read_rom(address),
-            0x8000..=0x9FFF => self.gpu.vram[address as usize - ROM_SIZE], // VRAM
+            0x8000..=0x9FFF => self.gpu.read(address), // VRAM
             0xA000..=0xBFFF => self.mbc.read_ram(address),           // External RAM
-            0xC000..=0xDFFF => self.wram[address as usize - 0xC000], // Work RAM (WRAM) -- TD Handle WRAM bank switching
+            0xC000..=0xCFFF => self.wram[address as usize - 0xC000], // Work RAM (WRAM) bank 0
+            0xD000..=0xDFFF => self.wram[(self.wram_bank as usize * 0x1000) | (address as usize & 0x0FFF)], // Work RAM (WRAM) bank 1-7
             0xE000..=0xFDFF => self.read(address - 0x2000),          // Echo RAM
             0xFE00..=0xFE9F => self.gpu.oam[address as usize - 0xFE00], // OAM
             0xFEA0..=0xFEFF => 0,                                   // Unusable
             0xFF00 => self.keypad.read(),                            // Keypad
             
-            // TODO: Sound I/O & Serial I/O
             0xFF01..=0xFF02 => self.serial.read(address), // Serial I/O
             0xFF04..=0xFF07 => self.timer.read(address),             // Timer I/O
             
-            0xFF0F => self.interrupt_flags,                          // Interrupt Flags
+            0xFF0F => self.interrupt_flags | 0xE0,                   // Interrupt Flags, the upper bits read 1
             
-            // TODO: Sound I/O
-            0xFF10..=0xFF3F => { 0 } // Sound I/O
+            0xFF10..=0xFF3F => self.apu.read(address), // Sound I/O
 
             0xFF40..=0xFF4B => self.gpu.read(address), //LCD Control, Status, Position, Scrolling, and Palettes
-            0xFF4F => self.gpu.vram_bank as u8,              // VRAM Bank
-            0xFF50 => 0,                               // Boot ROM disable
-            
-            // TODO: VRAM DMA
-            0xFF51..=0xFF55 => self.gpu.read(address), // VRAM DMA
+            0xFF4D if self.gbmode == GBMode::CGB => {
+                ((self.double_speed as u8) << 7) | 0x7E | self.speed_switch_armed as u8
+            } // Prepare speed switch
+            0xFF4F => self.gpu.read(address),          // VRAM Bank
+            0xFF50 => 0xFE | !self.boot_rom_mapped as u8, // Boot ROM disable
+            0xFF51..=0xFF55 if self.gbmode == GBMode::CGB => self.hdma.read(address), // VRAM DMA
             
             0xFF68..=0xFF6b => self.gpu.read(address), // Background/Object Palette Data
-            0xFF70 => self.wram_bank,                  // WRAM Bank
+            0xFF70 if self.gbmode == GBMode::CGB => 0xF8 | self.wram_bank, // WRAM Bank
             0xFF80..=0xFFFE => self.hram[address as usize & HRAM_SIZE], // High RAM
             0xFFFF => self.interrupt_enable,           // Interrupt Enable
             _ => { /* panic!("Unimplemented memory read at address: {:#06x}", address); */ 0 }
@@ -79,33 +125,44 @@ impl Memory {
     pub fn write(&mut self, address: u16, value: u8) {
         match address {
             0x0000..=0x7FFF => self.mbc.write_rom(address, value), // Rom
-            0x8000..=0x9FFF => self.gpu.vram[address as usize - 0x8000] = value, // VRAM
+            0x8000..=0x9FFF => self.gpu.write(address, value), // VRAM
             0xA000..=0xBFFF => self.mbc.write_ram(address, value),           // External RAM
-            0xC000..=0xDFFF => self.wram[address as usize - 0xC000] = value, // Work RAM (WRAM) -- TD Handle WRAM bank switching
+            0xC000..=0xCFFF => self.wram[address as usize - 0xC000] = value, // Work RAM (WRAM) bank 0
+            0xD000..=0xDFFF => self.wram[(self.wram_bank as usize * 0x1000) | (address as usize & 0x0FFF)] = value, // Work RAM (WRAM) bank 1-7
             0xE000..=0xFDFF => self.write(address - 0x2000, value),          // Echo RAM
             0xFE00..=0xFE9F => self.gpu.oam[address as usize - 0xFE00] = value, // OAM
             0xFEA0..=0xFEFF => (),                                           // Unusable
             0xFF00 => self.keypad.write(value),                              // Keypad
             
-            // TODO: Sound I/O & Serial I/O
             0xFF01..=0xFF02 => self.serial.write(address, value), // Serial I/O
             0xFF04..=0xFF07 => self.timer.write(address, value),             // Timer I/O
             
-            0xFF0F => self.interrupt_flags = value,                           // Interrupt Flags
+            0xFF0F => self.interrupt_flags = value & 0x1F,                    // Interrupt Flags
             
-            // TODO: Sound I/O
-            0xFF10..=0xFF3F => {} // Sound I/O
+            0xFF10..=0xFF3F => self.apu.write(address, value), // Sound I/O
             
             0xFF46 => { self.dma_transfer(value); } // OAM DMA
             0xFF40..=0xFF4B => self.gpu.write(address, value), //LCD Control, Status, Position, Scrolling, and Palettes
-            0xFF4f => self.gpu.vram_bank = value as usize,              // VRAM Bank
-            0xFF50 => (),                                      // Boot ROM disable
-            
-            // TODO: VRAM DMA
-            0xFF51..=0xFF55 => self.gpu.write(address, value), // VRAM DMA
+            // The CGB boot ROM switches to the DMG compatibility mode when the game is not made for the CGB
+            0xFF4C if self.boot_rom_mapped && self.gbmode == GBMode::CGB && value & 0x04 != 0 => {
+                self.gbmode = GBMode::DMG;
+                self.gpu.set_dmg_compatibility();
+                self.serial.set_dmg_compatibility();
+            } // KEY0: CPU mode select
+            0xFF4D if self.gbmode == GBMode::CGB => self.speed_switch_armed = value & 0x01 != 0, // Prepare speed switch
+            0xFF4F => self.gpu.write(address, value),          // VRAM Bank
+            // The boot ROM can only be unmapped, until the next reset
+            0xFF50 if value != 0 => self.boot_rom_mapped = false, // Boot ROM disable
+            0xFF51..=0xFF55 if self.gbmode == GBMode::CGB => {
+                self.hdma.write(address, value);
+                // A general purpose DMA copies everything at once
+                while self.hdma.is_active(HdmaMode::General) {
+                    self.hdma_transfer_block();
+                }
+            } // VRAM DMA
             
             0xFF68..=0xFF6B => self.gpu.write(address, value), // Background/Object Palette Data
-            0xFF70 => self.wram_bank = value,                  // WRAM Bank
+            0xFF70 if self.gbmode == GBMode::CGB => self.wram_bank = (value & 0x07).max(1), // WRAM Bank
             0xFF80..=0xFFFE => self.hram[address as usize & HRAM_SIZE] = value, // High RAM
             0xFFFF => self.interrupt_enable = value,           // Interrupt Enable
             _ => { /* panic!("Unimplemented memory write at address: {:#06x}", address); */ }
@@ -122,16 +179,144 @@ impl Memory {
     }
 
     pub fn step(&mut self, cycles: u8) {
-        self.interrupt_flags = self.keypad.interrupt | self.gpu.interrupt | self.timer.interrupt;
+        // IF keeps its bits until the CPU services them or the game clears them
+        self.interrupt_flags |= self.keypad.interrupt;
         self.keypad.interrupt = 0;
 
-        self.gpu.step(cycles);
+        // In double speed mode the CPU and the timer run twice as fast, the PPU and the APU keep the same speed
+        let cycles_normal_speed = cycles / self.speed_factor() as u8;
+
+        self.gpu.step(cycles_normal_speed);
         self.interrupt_flags |= self.gpu.interrupt;
         self.gpu.interrupt = 0;
 
+        if self.gpu.hblank {
+            self.gpu.hblank = false;
+            if self.hdma.is_active(HdmaMode::HBlank) {
+                self.hdma_transfer_block();
+            }
+        }
+
         self.timer.step(cycles);
         self.interrupt_flags |= self.timer.interrupt;
         self.timer.interrupt = 0;
+
+        self.serial.step(cycles);
+        self.interrupt_flags |= self.serial.interrupt;
+        self.serial.interrupt = 0;
+
+        self.apu.step(cycles_normal_speed);
+    }
+
+    /// Restore the I/O registers (0xFF00-0xFF7F) from a dump of their values
+    /// Writes with side effects (DMA, trigger of the sound channels, serial transfers, DIV reset...) are not replayed
+    pub fn restore_io_registers(&mut self, registers: &[u8; 0x80]) {
+        // The APU ignores every write while it is powered off
+        self.write(0xFF26, registers[0x26]);
+
+        for (index, &value) in registers.iter().enumerate() {
+            let address = 0xFF00 + index as u16;
+            match address {
+                0xFF02 => self.serial.restore_registers(registers[0x01], value),
+                0xFF04 => self.timer.set_div(value),
+                0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => self.apu.write(address, value & 0x7F),
+                0xFF01 | 0xFF26 | 0xFF41 | 0xFF44 | 0xFF46 | 0xFF50..=0xFF55 | 0xFF69 | 0xFF6B => {}
+                0xFF4D if self.gbmode == GBMode::CGB => {
+                    self.double_speed = value & 0x80 != 0;
+                    self.speed_switch_armed = value & 0x01 != 0;
+                }
+                _ => self.write(address, value),
+            }
+        }
+
+        self.gpu.restore_lcd_status(registers[0x41], registers[0x44]);
+        self.gpu.interrupt = 0;
+        self.keypad.interrupt = 0;
+        self.timer.interrupt = 0;
+        self.serial.interrupt = 0;
+        self.interrupt_flags = registers[0x0F] & 0x1F;
+    }
+
+    /// IO registers that differ from a model to another after the boot ROM
+    /// @see: https://gbdev.io/pandocs/Power_Up_Sequence.html#hardware-registers
+    pub fn init_model(&mut self, model: Model) {
+        // The time spent in the SGB and CGB boot ROMs depends on the cartridge header, so does DIV
+        let div = match model {
+            Model::DMG0 => 0x18,
+            Model::DMG | Model::MGB => 0xAB,
+            Model::SGB | Model::SGB2 | Model::CGB | Model::AGB => 0x00,
+        };
+        self.timer.set_div(div);
+
+        // The unused bits of SC read as 1, bit 1 (clock speed) only exists on CGB
+        self.write(0xFF02, if model.is_cgb() { 0x7F } else { 0x7E });
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.gbmode == GBMode::CGB);
+        state.bool(self.boot_rom_mapped);
+        self.mbc.save_state(state);
+        self.gpu.save_state(state);
+        self.apu.save_state(state);
+        self.keypad.save_state(state);
+        self.timer.save_state(state);
+        self.serial.save_state(state);
+        self.hdma.save_state(state);
+
+        state.u8(self.interrupt_flags);
+        state.u8(self.interrupt_enable);
+        state.bytes(&self.wram);
+        state.u8(self.wram_bank);
+        state.bytes(&self.hram);
+        state.bool(self.double_speed);
+        state.bool(self.speed_switch_armed);
+        state.u32(self.dma_cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        // The mode is part of the state: the CGB boot ROM can switch to the DMG compatibility mode
+        self.gbmode = if state.bool()? { GBMode::CGB } else { GBMode::DMG };
+        self.boot_rom_mapped = state.bool()?;
+        if self.boot_rom_mapped && self.boot_rom.is_empty() {
+            return Err(crate::Error::UnsupportedState("made while running a boot ROM".to_string()));
+        }
+        self.mbc.load_state(state)?;
+        self.gpu.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.keypad.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.hdma.load_state(state)?;
+
+        self.interrupt_flags = state.u8()?;
+        self.interrupt_enable = state.u8()?;
+        state.bytes_into(&mut self.wram)?;
+        self.wram_bank = (state.u8()? & 0x07).max(1);
+        state.bytes_into(&mut self.hram)?;
+        self.double_speed = state.bool()?;
+        self.speed_switch_armed = state.bool()?;
+        self.dma_cycles = state.u32()?;
+        Ok(())
+    }
+
+    /// 2 in CGB double speed mode, 1 otherwise
+    pub fn speed_factor(&self) -> u32 {
+        if self.double_speed {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Called by STOP, switch the CPU speed if it was requested through KEY1
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        self.timer.write(0xFF04, 0);
+        true
     }
 
     fn dma_transfer(&mut self, address: u8) {
@@ -141,6 +326,17 @@ impl Memory {
         }
     }
 
+    fn hdma_transfer_block(&mut self) {
+        let (source, destination) = self.hdma.next_block();
+        for i in 0..HDMA_BLOCK_SIZE {
+            let value = self.read(source.wrapping_add(i));
+            self.gpu.write(destination + i, value);
+        }
+        self.hdma.advance();
+        // The copy takes the same time at both speeds
+        self.dma_cycles += HDMA_BLOCK_CYCLES * self.speed_factor();
+    }
+
     fn init(&mut self) {
         self.write(0xFF05, 0x00);
         self.write(0xFF06, 0x00);
@@ -176,3 +372,82 @@ impl Memory {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cgb_memory() -> Memory {
+        let mbc = crate::mbc::from_rom(&vec![0; 0x8000]).unwrap();
+        Memory::new(mbc, GBMode::CGB, None)
+    }
+
+    #[test]
+    fn test_general_dma() {
+        let mut memory = cgb_memory();
+        for i in 0..0x20 {
+            memory.write(0xC000 + i, i as u8);
+        }
+        memory.write(0xFF51, 0xC0);
+        memory.write(0xFF52, 0x00);
+        memory.write(0xFF53, 0x01);
+        memory.write(0xFF54, 0x00);
+        memory.write(0xFF55, 0x01);
+
+        assert_eq!(memory.read(0x8100), 0x00);
+        assert_eq!(memory.read(0x811F), 0x1F);
+        assert_eq!(memory.read(0xFF55), 0xFF);
+        assert_eq!(memory.dma_cycles, 2 * HDMA_BLOCK_CYCLES);
+    }
+
+    #[test]
+    fn test_hblank_dma() {
+        let mut memory = cgb_memory();
+        memory.write(0xC010, 0x42);
+        memory.write(0xFF51, 0xC0);
+        memory.write(0xFF52, 0x00);
+        memory.write(0xFF53, 0x00);
+        memory.write(0xFF54, 0x00);
+        memory.write(0xFF55, 0x81);
+        assert_eq!(memory.read(0xFF55), 0x01);
+
+        // One block per HBlank
+        memory.gpu.hblank = true;
+        memory.step(4);
+        assert_eq!(memory.read(0xFF55), 0x00);
+        memory.gpu.hblank = true;
+        memory.step(4);
+        assert_eq!(memory.read(0xFF55), 0xFF);
+        assert_eq!(memory.read(0x8010), 0x42);
+    }
+
+    #[test]
+    fn test_wram_banks() {
+        let mut memory = cgb_memory();
+        memory.write(0xD000, 0x11);
+        memory.write(0xFF70, 0x02);
+        assert_eq!(memory.read(0xFF70), 0xFA);
+        assert_eq!(memory.read(0xD000), 0x00);
+        memory.write(0xD000, 0x22);
+
+        // Bank 0 selects bank 1
+        memory.write(0xFF70, 0x00);
+        assert_eq!(memory.read(0xD000), 0x11);
+        assert_eq!(memory.read(0xF000), 0x11);
+        memory.write(0xFF70, 0x02);
+        assert_eq!(memory.read(0xD000), 0x22);
+    }
+
+    #[test]
+    fn test_speed_switch() {
+        let mut memory = cgb_memory();
+        assert_eq!(memory.read(0xFF4D), 0x7E);
+        assert!(!memory.switch_speed());
+
+        memory.write(0xFF4D, 0x01);
+        assert_eq!(memory.read(0xFF4D), 0x7F);
+        assert!(memory.switch_speed());
+        assert_eq!(memory.read(0xFF4D), 0xFE);
+        assert_eq!(memory.speed_factor(), 2);
+    }
+}
@@ -0,0 +1,616 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::serial::LinkTransport;
+
+// Commands sent by the Gameboy, the adapter answers with the same command | 0x80
+const COMMAND_EMPTY: u8 = 0x0F;
+const COMMAND_BEGIN_SESSION: u8 = 0x10;
+const COMMAND_END_SESSION: u8 = 0x11;
+const COMMAND_DIAL: u8 = 0x12;
+const COMMAND_HANG_UP: u8 = 0x13;
+const COMMAND_TRANSFER_DATA: u8 = 0x15;
+const COMMAND_RESET: u8 = 0x16;
+const COMMAND_TELEPHONE_STATUS: u8 = 0x17;
+const COMMAND_READ_CONFIGURATION: u8 = 0x19;
+const COMMAND_WRITE_CONFIGURATION: u8 = 0x1A;
+const COMMAND_TRANSFER_DATA_END: u8 = 0x1F;
+const COMMAND_ISP_LOGIN: u8 = 0x21;
+const COMMAND_ISP_LOGOUT: u8 = 0x22;
+const COMMAND_TCP_OPEN: u8 = 0x23;
+const COMMAND_TCP_CLOSE: u8 = 0x24;
+const COMMAND_DNS_QUERY: u8 = 0x28;
+const COMMAND_ERROR: u8 = 0x6E;
+
+// Error codes sent with COMMAND_ERROR
+const ERROR_UNKNOWN_COMMAND: u8 = 0x00;
+const ERROR_INVALID: u8 = 0x01;
+const ERROR_NETWORK: u8 = 0x03;
+
+// Device IDs exchanged after the checksum
+const GAMEBOY_ID: u8 = 0x80;
+const ADAPTER_ID: u8 = 0x88;
+// Sent by the adapter while it receives a packet
+const ADAPTER_IDLE: u8 = 0xD2;
+const ACK_CHECKSUM_ERROR: u8 = 0xF1;
+
+// Telephone status
+const STATUS_IDLE: u8 = 0x00;
+const STATUS_CALL: u8 = 0x04;
+const STATUS_INTERNET: u8 = 0x05;
+
+// Connection used by the telephone call in the transfer commands
+const PHONE_CONNECTION: u8 = 0xFF;
+
+const CONFIGURATION_SIZE: usize = 0xC0;
+const MAX_DATA_SIZE: usize = 0xFE;
+
+// The games wait for the adapter, a server that does not answer must not freeze them
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where a connection of the adapter goes
+#[derive(Clone, PartialEq, Debug)]
+pub enum MobileDestination {
+    // Telephone call to a number
+    Phone(String),
+    // TCP connection opened after the ISP login
+    Tcp([u8; 4], u16),
+}
+
+/// Network side of the Mobile Adapter: telephone calls and internet connections
+pub trait MobileNetwork: Send {
+    /// Open the connection `id`, returns false if no one answers
+    fn open(&mut self, id: u8, destination: &MobileDestination) -> bool;
+
+    fn close(&mut self, id: u8);
+
+    /// Returns false if the connection is closed
+    fn send(&mut self, id: u8, data: &[u8]) -> bool;
+
+    /// Data received since the last call, None once the connection is closed
+    fn receive(&mut self, id: u8) -> Option<Vec<u8>>;
+
+    /// Address of a host name, for the DNS queries
+    fn resolve(&mut self, name: &str) -> Option<[u8; 4]>;
+}
+
+/**
+ * Stand-in for the telephone and internet networks: every connection goes to a single server,
+ * which is told where the game wanted to go with a first line "DIAL <number>" or "TCP <ip>:<port>".
+ * Every host name resolves to 127.0.0.1.
+ */
+pub struct LocalServer {
+    address: String,
+    connections: HashMap<u8, TcpStream>,
+}
+
+impl LocalServer {
+    pub fn new(address: &str) -> Self {
+        LocalServer {
+            address: address.to_string(),
+            connections: HashMap::new(),
+        }
+    }
+}
+
+impl MobileNetwork for LocalServer {
+    fn open(&mut self, id: u8, destination: &MobileDestination) -> bool {
+        let line = match destination {
+            MobileDestination::Phone(number) => format!("DIAL {}\r\n", number),
+            MobileDestination::Tcp([a, b, c, d], port) => format!("TCP {}.{}.{}.{}:{}\r\n", a, b, c, d, port),
+        };
+        let stream = self.address.to_socket_addrs().and_then(|mut addresses| {
+            let address = addresses.next().ok_or(std::io::ErrorKind::AddrNotAvailable)?;
+            let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            stream.write_all(line.as_bytes())?;
+            stream.set_nonblocking(true)?;
+            Ok(stream)
+        });
+        match stream {
+            Ok(stream) => {
+                self.connections.insert(id, stream);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn close(&mut self, id: u8) {
+        self.connections.remove(&id);
+    }
+
+    fn send(&mut self, id: u8, data: &[u8]) -> bool {
+        match self.connections.get_mut(&id) {
+            Some(stream) => {
+                // Blocking write, given up after the write timeout
+                stream.set_nonblocking(false).is_ok()
+                    && stream.write_all(data).is_ok()
+                    && stream.set_nonblocking(true).is_ok()
+            }
+            None => false,
+        }
+    }
+
+    fn receive(&mut self, id: u8) -> Option<Vec<u8>> {
+        let stream = self.connections.get_mut(&id)?;
+        let mut data = Vec::new();
+        let mut buffer = [0; MAX_DATA_SIZE];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) if data.is_empty() => {
+                    self.connections.remove(&id);
+                    return None;
+                }
+                Ok(0) => return Some(data),
+                Ok(size) => data.extend_from_slice(&buffer[..size]),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Some(data),
+                Err(_) => {
+                    self.connections.remove(&id);
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn resolve(&mut self, _name: &str) -> Option<[u8; 4]> {
+        Some([127, 0, 0, 1])
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Header,
+    Data,
+    Checksum,
+    DeviceId,
+    Acknowledge,
+    // Sending the answer, the Gameboy clocks it with idle bytes
+    Answer,
+}
+
+/**
+ * Mobile Adapter GB, plugged in the serial port. The Gameboy drives the clock and sends packets:
+ * magic bytes (0x99 0x66), command, 0x00, data length (big endian), data, checksum (big endian), then
+ * its device ID and an acknowledge byte. The adapter answers with a packet of the same format.
+ * @see: https://shonumi.github.io/dandocs.html#magb
+ */
+pub struct MobileAdapter {
+    state: PacketState,
+    // Command, unused byte and length
+    header: Vec<u8>,
+    data: Vec<u8>,
+    checksum: Vec<u8>,
+    answer: VecDeque<u8>,
+
+    session: bool,
+    call: bool,
+    internet: bool,
+    // TCP connections opened by the game
+    connections: Vec<u8>,
+    // Data received but not sent to the Gameboy yet, an answer holds 253 bytes at most
+    received: HashMap<u8, VecDeque<u8>>,
+    configuration: [u8; CONFIGURATION_SIZE],
+
+    network: Box<dyn MobileNetwork>,
+}
+
+impl MobileAdapter {
+    pub fn new(network: Box<dyn MobileNetwork>) -> Self {
+        MobileAdapter {
+            state: PacketState::Magic1,
+            header: Vec::with_capacity(4),
+            data: Vec::new(),
+            checksum: Vec::with_capacity(2),
+            answer: VecDeque::new(),
+            session: false,
+            call: false,
+            internet: false,
+            connections: Vec::new(),
+            received: HashMap::new(),
+            configuration: [0; CONFIGURATION_SIZE],
+            network,
+        }
+    }
+
+    /// Settings of the adapter (ISP login, mail server...), written by the games
+    pub fn configuration(&self) -> &[u8] {
+        &self.configuration
+    }
+
+    pub fn load_configuration(&mut self, data: &[u8]) {
+        let size = data.len().min(CONFIGURATION_SIZE);
+        self.configuration[..size].copy_from_slice(&data[..size]);
+    }
+
+    fn length(&self) -> usize {
+        (self.header[2] as usize) << 8 | self.header[3] as usize
+    }
+
+    fn sum(&self) -> u16 {
+        self.header.iter().chain(&self.data).fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16))
+    }
+
+    /// Handle a byte sent by the Gameboy, returns the byte sent back
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut answer = ADAPTER_IDLE;
+        self.state = match self.state {
+            PacketState::Magic1 if byte == 0x99 => PacketState::Magic2,
+            PacketState::Magic1 => PacketState::Magic1,
+            PacketState::Magic2 if byte == 0x66 => {
+                self.header.clear();
+                self.data.clear();
+                self.checksum.clear();
+                PacketState::Header
+            }
+            PacketState::Magic2 if byte == 0x99 => PacketState::Magic2,
+            PacketState::Magic2 => PacketState::Magic1,
+            PacketState::Header => {
+                self.header.push(byte);
+                match self.header.len() {
+                    4 if self.length() > 0 => PacketState::Data,
+                    4 => PacketState::Checksum,
+                    _ => PacketState::Header,
+                }
+            }
+            PacketState::Data => {
+                self.data.push(byte);
+                if self.data.len() == self.length() { PacketState::Checksum } else { PacketState::Data }
+            }
+            PacketState::Checksum => {
+                self.checksum.push(byte);
+                if self.checksum.len() == 2 { PacketState::DeviceId } else { PacketState::Checksum }
+            }
+            PacketState::DeviceId => {
+                answer = ADAPTER_ID;
+                if byte == GAMEBOY_ID { PacketState::Acknowledge } else { PacketState::Magic1 }
+            }
+            PacketState::Acknowledge => {
+                let checksum = (self.checksum[0] as u16) << 8 | self.checksum[1] as u16;
+                if checksum == self.sum() {
+                    answer = self.header[0] ^ 0x80;
+                    self.execute();
+                    PacketState::Answer
+                } else {
+                    answer = ACK_CHECKSUM_ERROR;
+                    PacketState::Magic1
+                }
+            }
+            PacketState::Answer => {
+                answer = self.answer.pop_front().unwrap_or(ADAPTER_IDLE);
+                if self.answer.is_empty() { PacketState::Magic1 } else { PacketState::Answer }
+            }
+        };
+        answer
+    }
+
+    fn reply(&mut self, command: u8, data: &[u8]) {
+        let data = &data[..data.len().min(MAX_DATA_SIZE)];
+        let mut packet = vec![command | 0x80, 0x00, 0x00, data.len() as u8];
+        packet.extend_from_slice(data);
+        let sum = packet.iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+
+        self.answer.clear();
+        self.answer.extend([0x99, 0x66]);
+        self.answer.extend(packet);
+        self.answer.extend(sum.to_be_bytes());
+        self.answer.extend([ADAPTER_ID, 0x00]);
+    }
+
+    fn error(&mut self, command: u8, code: u8) {
+        self.reply(COMMAND_ERROR, &[command, code]);
+    }
+
+    fn execute(&mut self) {
+        let command = self.header[0];
+        let data = std::mem::take(&mut self.data);
+        if !self.session && command != COMMAND_BEGIN_SESSION {
+            self.error(command, ERROR_INVALID);
+            return;
+        }
+
+        match command {
+            COMMAND_EMPTY => self.reply(command, &[]),
+            COMMAND_BEGIN_SESSION => {
+                if data != b"NINTENDO" || self.session {
+                    self.error(command, ERROR_INVALID);
+                    return;
+                }
+                self.session = true;
+                self.reply(command, &data);
+            }
+            COMMAND_END_SESSION | COMMAND_RESET => {
+                self.hang_up();
+                self.session = command == COMMAND_RESET;
+                self.reply(command, &[]);
+            }
+            COMMAND_DIAL => {
+                // The first byte is the kind of line, the number follows in ASCII
+                let number: String = data.iter().skip(1).map(|&digit| digit as char).collect();
+                if self.call {
+                    self.error(command, ERROR_INVALID);
+                } else if self.network.open(PHONE_CONNECTION, &MobileDestination::Phone(number)) {
+                    self.call = true;
+                    self.reply(command, &[]);
+                } else {
+                    self.error(command, ERROR_NETWORK);
+                }
+            }
+            COMMAND_HANG_UP => {
+                self.hang_up();
+                self.reply(command, &[]);
+            }
+            COMMAND_TELEPHONE_STATUS => {
+                let status = match (self.call, self.internet) {
+                    (_, true) => STATUS_INTERNET,
+                    (true, false) => STATUS_CALL,
+                    (false, false) => STATUS_IDLE,
+                };
+                self.reply(command, &[status, 0x4D, 0x00]);
+            }
+            COMMAND_TRANSFER_DATA => self.transfer_data(&data),
+            COMMAND_READ_CONFIGURATION if data.len() == 2 => {
+                let offset = (data[0] as usize).min(CONFIGURATION_SIZE);
+                let end = (offset + data[1] as usize).min(CONFIGURATION_SIZE);
+                let mut answer = vec![data[0]];
+                answer.extend_from_slice(&self.configuration[offset..end]);
+                self.reply(command, &answer);
+            }
+            COMMAND_WRITE_CONFIGURATION if !data.is_empty() => {
+                let offset = (data[0] as usize).min(CONFIGURATION_SIZE);
+                let size = (data.len() - 1).min(CONFIGURATION_SIZE - offset);
+                self.configuration[offset..offset + size].copy_from_slice(&data[1..1 + size]);
+                self.reply(command, &[data[0], size as u8]);
+            }
+            COMMAND_ISP_LOGIN if self.call => {
+                self.internet = true;
+                // IP address given to the adapter, then the primary and secondary DNS
+                self.reply(command, &[127, 0, 0, 1, 127, 0, 0, 1, 127, 0, 0, 1]);
+            }
+            COMMAND_ISP_LOGOUT => {
+                self.close_connections();
+                self.internet = false;
+                self.reply(command, &[]);
+            }
+            COMMAND_TCP_OPEN if self.internet && data.len() == 6 => {
+                let address = [data[0], data[1], data[2], data[3]];
+                let port = (data[4] as u16) << 8 | data[5] as u16;
+                let Some(id) = (0..PHONE_CONNECTION).find(|id| !self.connections.contains(id)) else {
+                    self.error(command, ERROR_NETWORK);
+                    return;
+                };
+                if self.network.open(id, &MobileDestination::Tcp(address, port)) {
+                    self.connections.push(id);
+                    self.reply(command, &[id]);
+                } else {
+                    self.error(command, ERROR_NETWORK);
+                }
+            }
+            COMMAND_TCP_CLOSE if data.len() == 1 => {
+                self.connections.retain(|&id| id != data[0]);
+                self.received.remove(&data[0]);
+                self.network.close(data[0]);
+                self.reply(command, &data);
+            }
+            COMMAND_DNS_QUERY if self.internet => {
+                let name: String = data.iter().map(|&byte| byte as char).collect();
+                match self.network.resolve(&name) {
+                    Some(address) => self.reply(command, &address),
+                    None => self.error(command, ERROR_NETWORK),
+                }
+            }
+            COMMAND_READ_CONFIGURATION | COMMAND_WRITE_CONFIGURATION | COMMAND_ISP_LOGIN
+            | COMMAND_TCP_OPEN | COMMAND_TCP_CLOSE | COMMAND_DNS_QUERY => self.error(command, ERROR_INVALID),
+            _ => self.error(command, ERROR_UNKNOWN_COMMAND),
+        }
+    }
+
+    /// Send the data after the connection ID, answer with the data received in the meantime
+    fn transfer_data(&mut self, data: &[u8]) {
+        let Some(&id) = data.first() else {
+            self.error(COMMAND_TRANSFER_DATA, ERROR_INVALID);
+            return;
+        };
+        let open = if id == PHONE_CONNECTION { self.call } else { self.connections.contains(&id) };
+        if !open {
+            self.error(COMMAND_TRANSFER_DATA, ERROR_INVALID);
+            return;
+        }
+
+        let received = if self.network.send(id, &data[1..]) { self.network.receive(id) } else { None };
+        let pending = self.received.entry(id).or_default();
+        match received {
+            Some(received) => pending.extend(received),
+            // The other side hung up, the data received before is still given to the Gameboy
+            None if !pending.is_empty() => (),
+            None => {
+                self.received.remove(&id);
+                if id == PHONE_CONNECTION {
+                    self.call = false;
+                    self.internet = false;
+                } else {
+                    self.connections.retain(|&connection| connection != id);
+                }
+                self.reply(COMMAND_TRANSFER_DATA_END, &[id]);
+                return;
+            }
+        }
+
+        let size = pending.len().min(MAX_DATA_SIZE - 1);
+        let mut answer = vec![id];
+        answer.extend(pending.drain(..size));
+        self.reply(COMMAND_TRANSFER_DATA, &answer);
+    }
+
+    fn close_connections(&mut self) {
+        for id in std::mem::take(&mut self.connections) {
+            self.network.close(id);
+            self.received.remove(&id);
+        }
+    }
+
+    fn hang_up(&mut self) {
+        self.close_connections();
+        if self.call {
+            self.network.close(PHONE_CONNECTION);
+            self.received.remove(&PHONE_CONNECTION);
+        }
+        self.call = false;
+        self.internet = false;
+    }
+}
+
+impl LinkTransport for MobileAdapter {
+    fn transfer(&mut self, byte: u8, _cycle: u64) -> u8 {
+        self.receive(byte)
+    }
+
+    // The adapter never drives the clock
+    fn poll(&mut self, _ready: Option<u8>, _cycle: u64) -> Option<u8> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    fn packet(command: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x99, 0x66, command, 0x00, 0x00, data.len() as u8];
+        packet.extend_from_slice(data);
+        let sum = packet[2..].iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        packet.extend_from_slice(&sum.to_be_bytes());
+        packet.extend_from_slice(&[GAMEBOY_ID, 0x00]);
+        packet
+    }
+
+    /// Send a packet and read the answer, returns the acknowledge byte, the command and data of the answer
+    fn send(adapter: &mut MobileAdapter, command: u8, data: &[u8]) -> (u8, u8, Vec<u8>) {
+        let answers: Vec<u8> = packet(command, data).iter().map(|&byte| adapter.transfer(byte, 0)).collect();
+        let acknowledge = answers[answers.len() - 1];
+        if acknowledge == ACK_CHECKSUM_ERROR {
+            return (acknowledge, 0, Vec::new());
+        }
+
+        let mut answer = Vec::new();
+        while adapter.state == PacketState::Answer {
+            answer.push(adapter.transfer(0x4B, 0));
+        }
+        assert_eq!(&answer[..2], &[0x99, 0x66]);
+        assert_eq!(&answer[answer.len() - 2..], &[ADAPTER_ID, 0x00]);
+        let length = answer[5] as usize;
+        (acknowledge, answer[2], answer[6..6 + length].to_vec())
+    }
+
+    #[test]
+    fn test_session() {
+        let mut adapter = MobileAdapter::new(Box::new(LocalServer::new("127.0.0.1:1")));
+        let (_, command, _) = send(&mut adapter, COMMAND_TELEPHONE_STATUS, &[]);
+        assert_eq!(command, COMMAND_ERROR | 0x80);
+
+        let (acknowledge, command, data) = send(&mut adapter, COMMAND_BEGIN_SESSION, b"NINTENDO");
+        assert_eq!(acknowledge, COMMAND_BEGIN_SESSION ^ 0x80);
+        assert_eq!(command, COMMAND_BEGIN_SESSION | 0x80);
+        assert_eq!(data, b"NINTENDO");
+
+        let (_, _, data) = send(&mut adapter, COMMAND_TELEPHONE_STATUS, &[]);
+        assert_eq!(data[0], STATUS_IDLE);
+
+        send(&mut adapter, COMMAND_WRITE_CONFIGURATION, &[0x10, 0x12, 0x34]);
+        let (_, _, data) = send(&mut adapter, COMMAND_READ_CONFIGURATION, &[0x10, 0x02]);
+        assert_eq!(data, vec![0x10, 0x12, 0x34]);
+
+        let mut corrupted = packet(COMMAND_END_SESSION, &[]);
+        corrupted[7] ^= 0x01;
+        let answers: Vec<u8> = corrupted.iter().map(|&byte| adapter.transfer(byte, 0)).collect();
+        assert_eq!(answers[answers.len() - 1], ACK_CHECKSUM_ERROR);
+
+        let (_, command, _) = send(&mut adapter, COMMAND_END_SESSION, &[]);
+        assert_eq!(command, COMMAND_END_SESSION | 0x80);
+    }
+
+    #[test]
+    fn test_call_through_local_server() {
+        // Stand-in server that checks the number and echoes everything
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "DIAL 0755\r\n");
+            let mut stream = stream;
+            let mut data = [0; 5];
+            reader.read_exact(&mut data).unwrap();
+            stream.write_all(&data).unwrap();
+        });
+
+        let mut adapter = MobileAdapter::new(Box::new(LocalServer::new(&address)));
+        send(&mut adapter, COMMAND_BEGIN_SESSION, b"NINTENDO");
+        let (_, command, _) = send(&mut adapter, COMMAND_DIAL, b"\x000755");
+        assert_eq!(command, COMMAND_DIAL | 0x80);
+        let (_, _, data) = send(&mut adapter, COMMAND_TELEPHONE_STATUS, &[]);
+        assert_eq!(data[0], STATUS_CALL);
+
+        let (_, command, data) = send(&mut adapter, COMMAND_TRANSFER_DATA, b"\xFFHELLO");
+        assert_eq!(command, COMMAND_TRANSFER_DATA | 0x80);
+        let mut received = data[1..].to_vec();
+        while received.len() < 5 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            let (_, command, data) = send(&mut adapter, COMMAND_TRANSFER_DATA, b"\xFF");
+            assert_eq!(command, COMMAND_TRANSFER_DATA | 0x80);
+            received.extend_from_slice(&data[1..]);
+        }
+        assert_eq!(received, b"HELLO");
+        server.join().unwrap();
+
+        // The server closed the connection
+        let mut command = COMMAND_TRANSFER_DATA | 0x80;
+        while command == COMMAND_TRANSFER_DATA | 0x80 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            command = send(&mut adapter, COMMAND_TRANSFER_DATA, b"\xFF").1;
+        }
+        assert_eq!(command, COMMAND_TRANSFER_DATA_END | 0x80);
+        let (_, _, data) = send(&mut adapter, COMMAND_TELEPHONE_STATUS, &[]);
+        assert_eq!(data[0], STATUS_IDLE);
+    }
+
+    #[test]
+    fn test_transfer_split_in_answers() {
+        // The server sends more than an answer can hold, then hangs up
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(stream.try_clone().unwrap()).read_line(&mut line).unwrap();
+            let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+            stream.write_all(&data).unwrap();
+        });
+
+        let mut adapter = MobileAdapter::new(Box::new(LocalServer::new(&address)));
+        send(&mut adapter, COMMAND_BEGIN_SESSION, b"NINTENDO");
+        send(&mut adapter, COMMAND_DIAL, b"\x000755");
+        server.join().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        let mut received = Vec::new();
+        loop {
+            let (_, command, data) = send(&mut adapter, COMMAND_TRANSFER_DATA, b"\xFF");
+            if command == COMMAND_TRANSFER_DATA_END | 0x80 {
+                break;
+            }
+            assert!(data.len() <= MAX_DATA_SIZE);
+            received.extend_from_slice(&data[1..]);
+        }
+        assert_eq!(received, (0..300).map(|i| i as u8).collect::<Vec<u8>>());
+    }
+}
@@ -0,0 +1,92 @@
+// Pixel data is stored without compression, deflate blocks are limited to 65535 bytes
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+
+/**
+ * Minimal PNG encoder for 8 bits grayscale images
+ * @see: https://www.w3.org/TR/png/
+ */
+pub fn encode_grayscale(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width * height, "Invalid image size");
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, color type 0 (grayscale), default compression, filter and interlace methods
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // Each line starts with its filter type, 0 for none
+    let mut lines = Vec::with_capacity((width + 1) * height);
+    for line in pixels.chunks(width.max(1)) {
+        lines.push(0);
+        lines.extend_from_slice(line);
+    }
+
+    let mut data = vec![0x78, 0x01];
+    let mut blocks = lines.chunks(STORED_BLOCK_SIZE).peekable();
+    if blocks.peek().is_none() {
+        data.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        data.push(blocks.peek().is_none() as u8);
+        data.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        data.extend_from_slice(block);
+    }
+    data.extend_from_slice(&adler32(&lines).to_be_bytes());
+    write_chunk(&mut png, b"IDAT", &data);
+
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFF;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE426082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn test_encode_grayscale() {
+        let png = encode_grayscale(2, 2, &[0x00, 0x55, 0xAA, 0xFF]);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 2]);
+        assert_eq!(&png[png.len() - 12..], b"\x00\x00\x00\x00IEND\xAE\x42\x60\x82");
+    }
+}
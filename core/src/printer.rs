@@ -0,0 +1,374 @@
+use crate::serial::LinkTransport;
+
+// Commands sent by the Gameboy
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+// Status bits returned at the end of each packet
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_DATA_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED_DATA: u8 = 0x08;
+const STATUS_PACKET_ERROR: u8 = 0x10;
+
+// Answer of the printer while the Gameboy sends the first byte after the checksum
+const DEVICE_ID: u8 = 0x81;
+
+// The printer RAM holds 9 DATA packets of 2 tile rows: 160x144 pixels
+const RAM_SIZE: usize = 0x2000;
+const MAX_DATA_SIZE: usize = 0x280;
+
+pub const PAGE_WIDTH: usize = 160;
+const TILES_PER_LINE: usize = PAGE_WIDTH / 8;
+const TILE_SIZE: usize = 16;
+
+// Printing a 160x144 image takes about a second
+const PRINT_CYCLES: u64 = 4_194_304;
+
+// Gray levels of the PNG for the 4 shades
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    DeviceId,
+    Status,
+}
+
+/// Image printed on the paper, one byte per pixel from 0 (white) to 3 (black)
+#[derive(Clone, PartialEq, Debug)]
+pub struct PrintedPage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl PrintedPage {
+    /// Encode the page as a grayscale PNG
+    pub fn to_png(&self) -> Vec<u8> {
+        let pixels: Vec<u8> = self.pixels.iter().map(|&shade| SHADES[shade as usize & 0x03]).collect();
+        crate::png::encode_grayscale(self.width, self.height, &pixels)
+    }
+
+    pub fn save_png(&self, path: &str) -> Result<(), std::io::Error> {
+        std::fs::write(path, self.to_png())
+    }
+}
+
+/**
+ * Game Boy Printer, plugged in the serial port. The Gameboy drives the clock and sends packets:
+ * magic bytes (0x88 0x33), command, compression flag, data length, data, checksum, then two bytes
+ * during which the printer answers with its ID and its status.
+ * @see: https://gbdev.io/pandocs/Gameboy_Printer.html
+ */
+pub struct Printer {
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    // Sum of the bytes from the command to the end of the data
+    sum: u16,
+
+    status: u8,
+    ram: Vec<u8>,
+    printing_until: u64,
+
+    // Lines printed since the last page was cut
+    page: Vec<u8>,
+    on_page: Box<dyn FnMut(&PrintedPage) + Send>,
+}
+
+impl Printer {
+    pub fn new(on_page: Box<dyn FnMut(&PrintedPage) + Send>) -> Self {
+        Printer {
+            state: PacketState::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            sum: 0,
+            status: 0,
+            ram: Vec::new(),
+            printing_until: 0,
+            page: Vec::new(),
+            on_page,
+        }
+    }
+
+    /// Handle a byte sent by the Gameboy, returns the byte sent back
+    fn receive(&mut self, byte: u8, cycle: u64) -> u8 {
+        let mut answer = 0x00;
+        self.state = match self.state {
+            PacketState::Magic1 if byte == 0x88 => PacketState::Magic2,
+            PacketState::Magic1 => PacketState::Magic1,
+            PacketState::Magic2 if byte == 0x33 => PacketState::Command,
+            PacketState::Magic2 if byte == 0x88 => PacketState::Magic2,
+            PacketState::Magic2 => PacketState::Magic1,
+            PacketState::Command => {
+                self.command = byte;
+                self.sum = byte as u16;
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.sum = self.sum.wrapping_add(byte as u16);
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow => {
+                self.length = byte as usize;
+                self.sum = self.sum.wrapping_add(byte as u16);
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh => {
+                self.length |= (byte as usize) << 8;
+                self.sum = self.sum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length == 0 { PacketState::ChecksumLow } else { PacketState::Data }
+            }
+            PacketState::Data => {
+                self.data.push(byte);
+                self.sum = self.sum.wrapping_add(byte as u16);
+                if self.data.len() == self.length { PacketState::ChecksumLow } else { PacketState::Data }
+            }
+            PacketState::ChecksumLow => {
+                self.checksum = byte as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh => {
+                self.checksum |= (byte as u16) << 8;
+                self.execute(cycle);
+                PacketState::DeviceId
+            }
+            PacketState::DeviceId => {
+                answer = DEVICE_ID;
+                PacketState::Status
+            }
+            PacketState::Status => {
+                answer = self.status;
+                PacketState::Magic1
+            }
+        };
+        answer
+    }
+
+    fn execute(&mut self, cycle: u64) {
+        if self.status & STATUS_PRINTING != 0 && cycle >= self.printing_until {
+            self.status &= !STATUS_PRINTING;
+        }
+
+        if self.checksum != self.sum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !(STATUS_CHECKSUM_ERROR | STATUS_PACKET_ERROR);
+
+        match self.command {
+            COMMAND_INIT => {
+                self.ram.clear();
+                self.status = 0;
+            }
+            COMMAND_DATA => {
+                // An empty packet marks the end of the image
+                if self.data.is_empty() {
+                    self.status |= STATUS_IMAGE_DATA_FULL;
+                    return;
+                }
+                let data = if self.compressed { decompress(&self.data) } else { self.data.clone() };
+                if data.len() > MAX_DATA_SIZE || self.ram.len() + data.len() > RAM_SIZE {
+                    self.status |= STATUS_PACKET_ERROR;
+                    return;
+                }
+                self.ram.extend_from_slice(&data);
+                self.status |= STATUS_UNPROCESSED_DATA;
+            }
+            COMMAND_PRINT if self.data.len() == 4 => {
+                let margins = self.data[1];
+                // Palette 0x00 is handled as the default palette 0xE4 by the printer
+                let palette = match self.data[2] {
+                    0x00 => 0xE4,
+                    palette => palette,
+                };
+                self.print(palette);
+                self.status = (self.status | STATUS_PRINTING) & !(STATUS_UNPROCESSED_DATA | STATUS_IMAGE_DATA_FULL);
+                self.printing_until = cycle + PRINT_CYCLES;
+
+                // The paper is fed after the image when there is a bottom margin, which ends the page
+                if margins & 0x0F != 0 {
+                    self.cut();
+                }
+            }
+            COMMAND_STATUS => {}
+            _ => self.status |= STATUS_PACKET_ERROR,
+        }
+    }
+
+    /// Add the tiles of the RAM to the current page
+    fn print(&mut self, palette: u8) {
+        let tiles = std::mem::take(&mut self.ram);
+        let lines = tiles.len() / (TILES_PER_LINE * TILE_SIZE) * 8;
+        let start = self.page.len();
+        self.page.resize(start + lines * PAGE_WIDTH, 0);
+
+        for (index, tile) in tiles.chunks_exact(TILE_SIZE).enumerate() {
+            let tile_x = index % TILES_PER_LINE * 8;
+            let tile_y = index / TILES_PER_LINE * 8;
+            if tile_y >= lines {
+                break;
+            }
+            for row in 0..8 {
+                let low = tile[row * 2];
+                let high = tile[row * 2 + 1];
+                for x in 0..8 {
+                    let color = ((high >> (7 - x)) & 0x01) << 1 | ((low >> (7 - x)) & 0x01);
+                    let shade = (palette >> (color * 2)) & 0x03;
+                    self.page[start + (tile_y + row) * PAGE_WIDTH + tile_x + x] = shade;
+                }
+            }
+        }
+    }
+
+    fn cut(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+        let pixels = std::mem::take(&mut self.page);
+        let page = PrintedPage {
+            width: PAGE_WIDTH,
+            height: pixels.len() / PAGE_WIDTH,
+            pixels,
+        };
+        (self.on_page)(&page);
+    }
+}
+
+impl LinkTransport for Printer {
+    fn transfer(&mut self, byte: u8, cycle: u64) -> u8 {
+        self.receive(byte, cycle)
+    }
+
+    // The printer never drives the clock
+    fn poll(&mut self, _ready: Option<u8>, _cycle: u64) -> Option<u8> {
+        None
+    }
+}
+
+/// Run-length encoding of the DATA packets: a byte with bit 7 set repeats the next byte
+/// (byte & 0x7F) + 2 times, otherwise (byte + 1) bytes are copied as is
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(MAX_DATA_SIZE);
+    let mut position = 0;
+    while position < data.len() {
+        let control = data[position];
+        position += 1;
+        if control & 0x80 != 0 {
+            let Some(&value) = data.get(position) else { break };
+            output.resize(output.len() + (control & 0x7F) as usize + 2, value);
+            position += 1;
+        } else {
+            let end = (position + control as usize + 1).min(data.len());
+            output.extend_from_slice(&data[position..end]);
+            position = end;
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x88, 0x33, command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+        packet.extend_from_slice(data);
+        let sum = packet[2..].iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        packet.extend_from_slice(&sum.to_le_bytes());
+        packet.extend_from_slice(&[0x00, 0x00]);
+        packet
+    }
+
+    fn send(printer: &mut Printer, packet: &[u8]) -> Vec<u8> {
+        packet.iter().map(|&byte| printer.transfer(byte, 0)).collect()
+    }
+
+    fn new_printer() -> (Printer, Arc<Mutex<Vec<PrintedPage>>>) {
+        let pages = Arc::new(Mutex::new(Vec::new()));
+        let printed = pages.clone();
+        let printer = Printer::new(Box::new(move |page| printed.lock().unwrap().push(page.clone())));
+        (printer, pages)
+    }
+
+    #[test]
+    fn test_decompress() {
+        assert_eq!(decompress(&[0x81, 0xAA, 0x01, 0x12, 0x34]), vec![0xAA, 0xAA, 0xAA, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_status() {
+        let (mut printer, _) = new_printer();
+        let answer = send(&mut printer, &packet(COMMAND_STATUS, false, &[]));
+        assert_eq!(&answer[answer.len() - 2..], &[DEVICE_ID, 0x00]);
+
+        let mut data = packet(COMMAND_DATA, false, &[0x00; 0x10]);
+        data[7] = 0x01;
+        let answer = send(&mut printer, &data);
+        assert_eq!(answer[answer.len() - 1], STATUS_CHECKSUM_ERROR);
+
+        let answer = send(&mut printer, &packet(COMMAND_DATA, false, &[0x00; 0x280]));
+        assert_eq!(answer[answer.len() - 1], STATUS_UNPROCESSED_DATA);
+    }
+
+    #[test]
+    fn test_print() {
+        let (mut printer, pages) = new_printer();
+        send(&mut printer, &packet(COMMAND_INIT, false, &[]));
+
+        // A row of tiles filled with color 3, sent as 129 + 129 + 62 bytes of 0xFF
+        send(&mut printer, &packet(COMMAND_DATA, true, &[0xFF, 0xFF, 0xFF, 0xFF, 0xBC, 0xFF]));
+        // A row of tiles filled with color 1
+        let tiles = [0xFF, 0x00].repeat(TILES_PER_LINE * 8);
+        send(&mut printer, &packet(COMMAND_DATA, false, &tiles));
+        send(&mut printer, &packet(COMMAND_DATA, false, &[]));
+        assert!(pages.lock().unwrap().is_empty());
+
+        // Palette 0xE4: color n is printed with shade n, the page is cut with the bottom margin
+        let answer = send(&mut printer, &packet(COMMAND_PRINT, false, &[0x01, 0x13, 0xE4, 0x40]));
+        assert_eq!(answer[answer.len() - 1], STATUS_PRINTING);
+
+        let pages = pages.lock().unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!((pages[0].width, pages[0].height), (PAGE_WIDTH, 16));
+        assert_eq!(pages[0].pixels[0], 3);
+        assert_eq!(pages[0].pixels[PAGE_WIDTH * 8], 1);
+        assert!(pages[0].to_png().starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn test_print_default_palette() {
+        let (mut printer, pages) = new_printer();
+        send(&mut printer, &packet(COMMAND_INIT, false, &[]));
+
+        // A row of tiles filled with color 1, printed with palette 0x00
+        let tiles = [0xFF, 0x00].repeat(TILES_PER_LINE * 8);
+        send(&mut printer, &packet(COMMAND_DATA, false, &tiles));
+        send(&mut printer, &packet(COMMAND_DATA, false, &[]));
+        send(&mut printer, &packet(COMMAND_PRINT, false, &[0x01, 0x13, 0x00, 0x40]));
+
+        let pages = pages.lock().unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].pixels[0], 1);
+    }
+}
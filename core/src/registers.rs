@@ -1,3 +1,5 @@
+use crate::gameboy::{GBMode, Model};
+use crate::state::{StateReader, StateWriter};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Flag {
@@ -56,6 +58,94 @@ impl Registers {
         }
     }
 
+    /// Registers after the CGB boot ROM, A = 0x11 lets games detect the CGB
+    pub fn new_cgb() -> Registers {
+        Registers {
+            a: 0x11,
+            f: 0x80,
+            b: 0x00,
+            c: 0x00,
+            d: 0xff,
+            e: 0x56,
+            h: 0x00,
+            l: 0x0d,
+            sp: 0xfffe,
+            pc: 0x0100,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.af());
+        state.u16(self.bc());
+        state.u16(self.de());
+        state.u16(self.hl());
+        state.u16(self.sp);
+        state.u16(self.pc);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        // The lower 4 bits of F are always 0
+        self.set_af(state.u16()? & 0xFFF0);
+        self.set_bc(state.u16()?);
+        self.set_de(state.u16()?);
+        self.set_hl(state.u16()?);
+        self.sp = state.u16()?;
+        self.pc = state.u16()?;
+        Ok(())
+    }
+
+    /// Registers left by the boot ROM of `model`, `header` is the cartridge header (0x0100-0x014F)
+    /// @see: https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
+    pub fn after_boot(model: Model, gbmode: GBMode, header: &[u8]) -> Registers {
+        // The DMG boot ROM leaves H and C set unless the header checksum is 0
+        let dmg_flags = if header[0x4D] == 0 { 0x80 } else { 0xB0 };
+
+        let (a, f, b, c, d, e, h, l) = match (model, gbmode) {
+            (Model::DMG0, _) => (0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03),
+            (Model::DMG, _) => (0x01, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            (Model::MGB, _) => (0xFF, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            (Model::SGB, _) => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            (Model::SGB2, _) => (0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            (Model::CGB | Model::AGB, GBMode::CGB) => (0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+            (Model::CGB | Model::AGB, GBMode::DMG) => {
+                // B is the sum of the title bytes for the games licensed by Nintendo, used to pick a palette
+                let nintendo = header[0x4B] == 0x01 || (header[0x4B] == 0x33 && &header[0x44..0x46] == b"01");
+                let b = if nintendo {
+                    header[0x34..0x44].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+                } else {
+                    0x00
+                };
+                (0x11, 0x80, b, 0x00, 0x00, 0x08, 0x00, 0x7C)
+            }
+        };
+
+        let mut registers = Registers { a, f, b, c, d, e, h, l, sp: 0xFFFE, pc: 0x0100 };
+        if model == Model::AGB {
+            // The AGB boot ROM ends with an extra INC B, the only way for a game to detect it
+            registers.b = registers.b.wrapping_add(1);
+            registers.set_flag(Flag::Zero, registers.b == 0);
+            registers.set_flag(Flag::Sub, false);
+            registers.set_flag(Flag::HalfCarry, registers.b & 0x0F == 0);
+        }
+        registers
+    }
+
+    /// Registers at power on, before the boot ROM runs
+    pub fn power_on() -> Registers {
+        Registers {
+            a: 0x00,
+            f: 0x00,
+            b: 0x00,
+            c: 0x00,
+            d: 0x00,
+            e: 0x00,
+            h: 0x00,
+            l: 0x00,
+            sp: 0x0000,
+            pc: 0x0000,
+        }
+    }
+
     pub fn bc(&self) -> u16 {
         (self.b as u16) << 8 | self.c as u16
     }
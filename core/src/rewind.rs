@@ -0,0 +1,257 @@
+use std::collections::VecDeque;
+
+// A full state is stored every second, the other frames are stored as a difference with it
+const KEYFRAME_INTERVAL: usize = 60;
+
+// Zero runs shorter than this are cheaper to keep in the literal bytes
+const MIN_ZERO_RUN: usize = 4;
+
+/**
+ * Frames of a keyframe group: the keyframe is a compressed full state, each delta is the
+ * compressed XOR of a later state with the keyframe. Most of the memory does not change from a
+ * frame to the next so the deltas are mostly made of zeros and compress very well.
+ */
+struct Group {
+    keyframe: Vec<u8>,
+    deltas: Vec<Vec<u8>>,
+}
+
+impl Group {
+    fn size(&self) -> usize {
+        self.keyframe.len() + self.deltas.iter().map(|delta| delta.len()).sum::<usize>()
+    }
+}
+
+/**
+ * Ring buffer of the states of the last frames, bounded by a memory budget.
+ * The oldest keyframe group is dropped when the budget is exceeded.
+ */
+pub struct Rewind {
+    // Maximum size of the compressed states in bytes, 0 disables the rewind
+    budget: usize,
+    groups: VecDeque<Group>,
+    size: usize,
+
+    // Uncompressed keyframe of the newest group, used to compute the deltas
+    keyframe: Vec<u8>,
+}
+
+impl Rewind {
+    pub fn new() -> Self {
+        Rewind {
+            budget: 0,
+            groups: VecDeque::new(),
+            size: 0,
+            keyframe: Vec::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        if budget == 0 {
+            self.clear();
+        } else {
+            self.evict();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.groups.clear();
+        self.size = 0;
+        self.keyframe.clear();
+    }
+
+    /// Number of frames that can be rewound
+    pub fn frames(&self) -> usize {
+        let frames: usize = self.groups.iter().map(|group| group.deltas.len() + 1).sum();
+        frames.saturating_sub(1)
+    }
+
+    /// Memory used by the compressed states
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Record the state of the frame that was just run
+    pub fn push(&mut self, state: Vec<u8>) {
+        let new_group = match self.groups.back() {
+            Some(group) => group.deltas.len() + 1 >= KEYFRAME_INTERVAL || state.len() != self.keyframe.len(),
+            None => true,
+        };
+
+        if new_group {
+            let group = Group {
+                keyframe: compress(&state),
+                deltas: Vec::new(),
+            };
+            self.size += group.keyframe.len();
+            self.groups.push_back(group);
+            self.keyframe = state;
+        } else {
+            let delta: Vec<u8> = state.iter().zip(&self.keyframe).map(|(a, b)| a ^ b).collect();
+            let delta = compress(&delta);
+            self.size += delta.len();
+            self.groups.back_mut().unwrap().deltas.push(delta);
+        }
+        self.evict();
+    }
+
+    /// Drop the `frames` newest states and return the state that is now the newest one,
+    /// along with the number of frames actually dropped
+    pub fn rewind(&mut self, frames: usize) -> Option<(Vec<u8>, usize)> {
+        let frames = frames.min(self.frames());
+        for _ in 0..frames {
+            let group = self.groups.back_mut().unwrap();
+            match group.deltas.pop() {
+                Some(delta) => self.size -= delta.len(),
+                None => {
+                    self.size -= group.keyframe.len();
+                    self.groups.pop_back();
+                }
+            }
+        }
+
+        let group = self.groups.back()?;
+        if frames > 0 {
+            self.keyframe = decompress(&group.keyframe);
+        }
+        let state = match group.deltas.last() {
+            Some(delta) => {
+                let mut state = decompress(delta);
+                state.iter_mut().zip(&self.keyframe).for_each(|(a, b)| *a ^= b);
+                state
+            }
+            None => self.keyframe.clone(),
+        };
+        Some((state, frames))
+    }
+
+    fn evict(&mut self) {
+        // The newest group is always kept, even if it alone exceeds the budget
+        while self.size > self.budget && self.groups.len() > 1 {
+            let group = self.groups.pop_front().unwrap();
+            self.size -= group.size();
+        }
+    }
+}
+
+/**
+ * Run-length encoding of the zero bytes: the data is a list of (zero run, literal length, literal bytes),
+ * lengths being stored as LEB128 varints.
+ */
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() / 8);
+    let mut position = 0;
+    while position < data.len() {
+        let zeros = data[position..].iter().take_while(|&&value| value == 0).count();
+        position += zeros;
+
+        // The literal stops at the first run of zeros long enough to be encoded as a run
+        let start = position;
+        while position < data.len() {
+            let run = data[position..].iter().take(MIN_ZERO_RUN).take_while(|&&value| value == 0).count();
+            if run == MIN_ZERO_RUN || position + run == data.len() {
+                break;
+            }
+            position += run.max(1);
+        }
+
+        write_varint(&mut output, zeros);
+        write_varint(&mut output, position - start);
+        output.extend_from_slice(&data[start..position]);
+    }
+    output
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let zeros = read_varint(data, &mut position);
+        let literal = read_varint(data, &mut position);
+        output.resize(output.len() + zeros, 0);
+        output.extend_from_slice(&data[position..position + literal]);
+        position += literal;
+    }
+    output
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        output.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(frame: u8) -> Vec<u8> {
+        let mut state = vec![0; 0x1000];
+        state[0x10] = frame;
+        state[0x800..0x810].fill(frame.wrapping_mul(3));
+        state
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let mut data = vec![0; 300];
+        data[0] = 1;
+        data[2] = 2;
+        data[150..160].fill(0xAA);
+        data.push(3);
+        assert_eq!(decompress(&compress(&data)), data);
+        assert_eq!(decompress(&compress(&[0, 0, 1, 0])), vec![0, 0, 1, 0]);
+        assert!(compress(&data).len() < 30);
+    }
+
+    #[test]
+    fn test_rewind_frames() {
+        let mut rewind = Rewind::new();
+        rewind.set_budget(1 << 20);
+        for frame in 0..150 {
+            rewind.push(state(frame));
+        }
+        assert_eq!(rewind.frames(), 149);
+
+        assert_eq!(rewind.rewind(1), Some((state(148), 1)));
+        assert_eq!(rewind.rewind(60), Some((state(88), 60)));
+        assert_eq!(rewind.rewind(1000), Some((state(0), 88)));
+        assert_eq!(rewind.rewind(1), Some((state(0), 0)));
+    }
+
+    #[test]
+    fn test_rewind_budget() {
+        let mut rewind = Rewind::new();
+        rewind.set_budget(1500);
+        for frame in 0..200 {
+            rewind.push(state(frame));
+        }
+        assert!(rewind.frames() < 199);
+        assert!(rewind.size() <= 1500);
+
+        // The history is still consistent after the oldest groups were dropped
+        let frames = rewind.frames();
+        assert_eq!(rewind.rewind(frames), Some((state((199 - frames) as u8), frames)));
+    }
+}
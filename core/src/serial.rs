@@ -1,3 +1,72 @@
+use std::sync::{Arc, Mutex};
+
+use crate::state::{StateReader, StateWriter};
+
+// The internal clock shifts a bit every 512 cycles (8192 Hz), or every 16 cycles (262144 Hz) with the CGB fast clock
+const BIT_CYCLES: u32 = 512;
+const FAST_BIT_CYCLES: u32 = 16;
+
+/// Other end of the link cable
+pub trait LinkTransport: Send {
+    /// This side drives the clock: `byte` was shifted out, return the byte shifted in
+    /// `cycle` is the number of cycles run by this Gameboy, 0xFF is read when no one answers
+    fn transfer(&mut self, byte: u8, cycle: u64) -> u8;
+
+    /// Called on every step, `ready` holds SB while this side waits for the other side's clock
+    /// Return the byte shifted in if the other side ran a transfer, `ready` being shifted out in exchange
+    fn poll(&mut self, ready: Option<u8>, cycle: u64) -> Option<u8>;
+}
+
+#[derive(Default)]
+struct LocalLinkState {
+    // SB of each side while it waits for an external clock
+    ready: [Option<u8>; 2],
+    // Byte received by each side, delivered on its next poll
+    received: [Option<u8>; 2],
+}
+
+/// Link cable between two Gameboys of the same process, see `Gameboy::run_linked_frame` to keep them in sync
+pub struct LocalLink {
+    side: usize,
+    state: Arc<Mutex<LocalLinkState>>,
+}
+
+impl LocalLink {
+    /// Both ends of a cable
+    pub fn pair() -> (LocalLink, LocalLink) {
+        let state = Arc::new(Mutex::new(LocalLinkState::default()));
+        (
+            LocalLink { side: 0, state: state.clone() },
+            LocalLink { side: 1, state },
+        )
+    }
+}
+
+impl LinkTransport for LocalLink {
+    fn transfer(&mut self, byte: u8, _cycle: u64) -> u8 {
+        let other = 1 - self.side;
+        let mut state = self.state.lock().unwrap();
+        match state.ready[other].take() {
+            Some(received) => {
+                state.received[other] = Some(byte);
+                received
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn poll(&mut self, ready: Option<u8>, _cycle: u64) -> Option<u8> {
+        let mut state = self.state.lock().unwrap();
+        match state.received[self.side].take() {
+            Some(byte) => Some(byte),
+            None => {
+                state.ready[self.side] = ready;
+                None
+            }
+        }
+    }
+}
+
 /**
 * @see: https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
 */
@@ -6,30 +75,211 @@ pub struct Serial {
     sb: u8,
     // 0xFF02 — SC: Serial transfer control
     sc: u8,
+
+    // The clock speed bit of SC only exists in CGB mode
+    cgb: bool,
+
+    // Cycles left before the end of a transfer driven by the internal clock
+    transfer_cycles: u32,
+
+    // Cycles run since power on, sent along the bytes to the other side
+    cycles: u64,
+
+    transport: Option<Box<dyn LinkTransport>>,
+
+    pub interrupt: u8,
 }
 
 impl Serial {
-    
-    pub fn new() -> Self {
+
+    pub fn new(cgb: bool) -> Self {
         Serial {
             sb: 0,
             sc: 0,
+            cgb,
+            transfer_cycles: 0,
+            cycles: 0,
+            transport: None,
+            interrupt: 0,
         }
     }
 
     pub fn read(&self, address: u16) -> u8 {
         match address {
             0xFF01 => self.sb,
-            0xFF02 => self.sc,
-            _ => panic!("Invalid serial address: {:04x}", address),
+            0xFF02 => self.sc | if self.cgb { 0x7C } else { 0x7E },
+            // Not a serial register, nothing drives the bus
+            _ => 0xFF,
         }
     }
 
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.sb);
+        state.u8(self.sc);
+        state.bool(self.cgb);
+        state.u32(self.transfer_cycles);
+        state.u64(self.cycles);
+        state.u8(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.sb = state.u8()?;
+        self.sc = state.u8()?;
+        self.cgb = state.bool()?;
+        self.transfer_cycles = state.u32()?;
+        self.cycles = state.u64()?;
+        self.interrupt = state.u8()?;
+        Ok(())
+    }
+
     pub fn write(&mut self, address: u16, value: u8) {
         match address {
             0xFF01 => self.sb = value,
-            0xFF02 => self.sc = value,
-            _ => panic!("Invalid serial address: {:04x}", address),
+            0xFF02 => {
+                self.sc = value;
+                self.transfer_cycles = if value & 0x81 == 0x81 {
+                    8 * if self.cgb && value & 0x02 != 0 { FAST_BIT_CYCLES } else { BIT_CYCLES }
+                } else {
+                    0
+                };
+            }
+            _ => {}
+        }
+    }
+
+    /// Restore SB and SC from a save state without sending anything on the cable.
+    /// A transfer driven by the internal clock ends as if the cable was unplugged.
+    pub fn restore_registers(&mut self, sb: u8, sc: u8) {
+        self.sb = sb;
+        self.sc = sc;
+        self.transfer_cycles = 0;
+        if sc & 0x81 == 0x81 {
+            self.end_transfer(0xFF);
+        }
+    }
+
+    /// Called when the CGB boot ROM switches to the DMG compatibility mode
+    pub fn set_dmg_compatibility(&mut self) {
+        self.cgb = false;
+    }
+
+    /// Plug the link cable, or unplug it with `None`
+    pub fn connect(&mut self, transport: Option<Box<dyn LinkTransport>>) -> Option<Box<dyn LinkTransport>> {
+        std::mem::replace(&mut self.transport, transport)
+    }
+
+    pub fn step(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+
+        if self.transfer_cycles > 0 {
+            self.transfer_cycles = self.transfer_cycles.saturating_sub(cycles as u32);
+            if self.transfer_cycles == 0 {
+                let received = match self.transport.as_mut() {
+                    Some(transport) => transport.transfer(self.sb, self.cycles),
+                    None => 0xFF,
+                };
+                self.end_transfer(received);
+            }
+        } else if let Some(transport) = self.transport.as_mut() {
+            // Without a cable, a transfer waiting for the external clock never ends
+            let ready = (self.sc & 0x81 == 0x80).then_some(self.sb);
+            if let Some(received) = transport.poll(ready, self.cycles) {
+                if ready.is_some() {
+                    self.end_transfer(received);
+                }
+            }
+        }
+    }
+
+    fn end_transfer(&mut self, received: u8) {
+        self.sb = received;
+        self.sc &= 0x7F;
+        self.interrupt = 0x08;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(serial: &mut Serial, cycles: u32) {
+        for _ in 0..cycles / 4 {
+            serial.step(4);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_internal_clock() {
+        let mut serial = Serial::new(false);
+        serial.write(0xFF01, 0x42);
+        serial.write(0xFF02, 0x81);
+        run(&mut serial, 4092);
+        assert_eq!(serial.read(0xFF02), 0xFF);
+        assert_eq!(serial.interrupt, 0);
+
+        // Nothing is connected, the bits shifted in are all 1
+        run(&mut serial, 4);
+        assert_eq!(serial.read(0xFF01), 0xFF);
+        assert_eq!(serial.read(0xFF02), 0x7F);
+        assert_eq!(serial.interrupt, 0x08);
+
+        // The CGB fast clock is 32 times faster
+        let mut serial = Serial::new(true);
+        serial.write(0xFF02, 0x83);
+        run(&mut serial, 128);
+        assert_eq!(serial.interrupt, 0x08);
+    }
+
+    #[test]
+    fn test_external_clock() {
+        let mut serial = Serial::new(false);
+        serial.write(0xFF02, 0x80);
+        run(&mut serial, 10000);
+        assert_eq!(serial.read(0xFF02), 0xFE);
+        assert_eq!(serial.interrupt, 0);
+    }
+
+    #[test]
+    fn test_local_link() {
+        let (first, second) = LocalLink::pair();
+        let mut master = Serial::new(false);
+        let mut slave = Serial::new(false);
+        master.connect(Some(Box::new(first)));
+        slave.connect(Some(Box::new(second)));
+
+        master.write(0xFF01, 0x42);
+        slave.write(0xFF01, 0x24);
+        slave.write(0xFF02, 0x80);
+        master.write(0xFF02, 0x81);
+        for _ in 0..1024 {
+            slave.step(4);
+            master.step(4);
+        }
+        slave.step(4);
+
+        assert_eq!(master.read(0xFF01), 0x24);
+        assert_eq!(slave.read(0xFF01), 0x42);
+        assert_eq!(master.interrupt, 0x08);
+        assert_eq!(slave.interrupt, 0x08);
+    }
+
+    #[test]
+    fn test_restore_registers() {
+        let (first, second) = LocalLink::pair();
+        let mut master = Serial::new(false);
+        let mut slave = Serial::new(false);
+        master.connect(Some(Box::new(first)));
+        slave.connect(Some(Box::new(second)));
+
+        slave.write(0xFF01, 0x24);
+        slave.write(0xFF02, 0x80);
+        master.restore_registers(0x42, 0x81);
+        assert_eq!(master.read(0xFF02), 0x7F);
+        for _ in 0..1024 {
+            slave.step(4);
+            master.step(4);
+        }
+        assert_eq!(slave.read(0xFF01), 0x24);
+        assert_eq!(slave.interrupt, 0);
+    }
+}
@@ -0,0 +1,570 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::bus::{BusAccess, TestBus};
+use crate::cpu::CPU;
+use crate::registers::Registers;
+
+/// Fixture that can't be read or parsed
+#[derive(Debug)]
+pub struct TestError(String);
+
+impl fmt::Display for TestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid single step test: {}", self.0)
+    }
+}
+
+impl std::error::Error for TestError {}
+
+impl From<std::io::Error> for TestError {
+    fn from(error: std::io::Error) -> Self {
+        TestError(error.to_string())
+    }
+}
+
+/// CPU state at the start or at the end of a single step test
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct CpuState {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub f: u8,
+    pub h: u8,
+    pub l: u8,
+    pub pc: u16,
+    pub sp: u16,
+    pub ime: bool,
+    // IE is not given by every fixture
+    pub ie: Option<u8>,
+    // Bytes of the RAM set before the test or checked after it, the rest is 0
+    pub ram: Vec<(u16, u8)>,
+}
+
+/**
+ * One instruction run from `initial`, the CPU must end in `expected` after going through `cycles`
+ * @see: https://github.com/SingleStepTests/sm83
+ */
+#[derive(PartialEq, Clone, Debug)]
+pub struct SingleStepTest {
+    pub name: String,
+    pub initial: CpuState,
+    pub expected: CpuState,
+    // Bus activity of each M-cycle, `None` when the bus is idle
+    pub cycles: Vec<Option<BusAccess>>,
+}
+
+/// Parse a fixture file: a JSON array of tests
+pub fn parse_tests(json: &str) -> Result<Vec<SingleStepTest>, TestError> {
+    let mut parser = JsonParser { data: json.as_bytes(), position: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.position != parser.data.len() {
+        return Err(parser.error("trailing data"));
+    }
+
+    value.array()?.iter().map(parse_test).collect()
+}
+
+fn parse_test(value: &Json) -> Result<SingleStepTest, TestError> {
+    let cycles = value.field("cycles")?.array()?.iter().map(parse_cycle).collect::<Result<_, _>>()?;
+    Ok(SingleStepTest {
+        name: value.field("name")?.string()?.to_string(),
+        initial: parse_state(value.field("initial")?)?,
+        expected: parse_state(value.field("final")?)?,
+        cycles,
+    })
+}
+
+fn parse_state(value: &Json) -> Result<CpuState, TestError> {
+    let byte = |name: &str| value.field(name)?.byte();
+    let word = |name: &str| value.field(name)?.word();
+    let ram = value.field("ram")?.array()?.iter().map(|entry| {
+        match entry.array()? {
+            [address, value] => Ok((address.word()?, value.byte()?)),
+            _ => Err(TestError("RAM entries are [address, value]".to_string())),
+        }
+    });
+    Ok(CpuState {
+        a: byte("a")?,
+        b: byte("b")?,
+        c: byte("c")?,
+        d: byte("d")?,
+        e: byte("e")?,
+        f: byte("f")?,
+        h: byte("h")?,
+        l: byte("l")?,
+        pc: word("pc")?,
+        sp: word("sp")?,
+        ime: value.optional_field("ime").map(|ime| ime.byte()).transpose()?.unwrap_or(0) != 0,
+        ie: value.optional_field("ie").map(|ie| ie.byte()).transpose()?,
+        ram: ram.collect::<Result<_, _>>()?,
+    })
+}
+
+// [address, value, "r-m"] for a read, [address, value, "-wm"] for a write, null or "---" when the bus is idle
+fn parse_cycle(value: &Json) -> Result<Option<BusAccess>, TestError> {
+    let [address, data, kind] = match value {
+        Json::Null => return Ok(None),
+        Json::Array(entry) => match entry.as_slice() {
+            [address, data, kind] => [address, data, kind],
+            _ => return Err(TestError("cycles are [address, value, kind]".to_string())),
+        },
+        _ => return Err(TestError("cycles are [address, value, kind]".to_string())),
+    };
+    let kind = kind.string()?;
+    // The address and the value may be null on idle cycles
+    if kind.contains('w') {
+        Ok(Some(BusAccess::Write { address: address.word()?, value: data.byte()? }))
+    } else if kind.contains('r') {
+        Ok(Some(BusAccess::Read { address: address.word()?, value: data.byte()? }))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Run a test on a CPU connected to a `TestBus`, returns the differences with the expected state
+pub fn run_test(test: &SingleStepTest) -> Result<(), String> {
+    let initial = &test.initial;
+    let mut registers = Registers::power_on();
+    registers.a = initial.a;
+    registers.b = initial.b;
+    registers.c = initial.c;
+    registers.d = initial.d;
+    registers.e = initial.e;
+    registers.f = initial.f;
+    registers.h = initial.h;
+    registers.l = initial.l;
+    registers.pc = initial.pc;
+    registers.sp = initial.sp;
+
+    let mut bus = TestBus::new();
+    for &(address, value) in &initial.ram {
+        bus.ram[address as usize] = value;
+    }
+    if let Some(ie) = initial.ie {
+        bus.ram[0xFFFF] = ie;
+    }
+
+    let mut cpu = CPU::with_bus(bus, registers);
+    cpu.ime = initial.ime;
+    let cycles = cpu.step();
+    let accesses = cpu.memory.take_accesses();
+
+    let expected = &test.expected;
+    let registers = &cpu.registers;
+    let mut errors = Vec::new();
+    let mut check = |name: &str, expected: u16, actual: u16| {
+        if expected != actual {
+            errors.push(format!("{}: expected {:02X}, got {:02X}", name, expected, actual));
+        }
+    };
+    check("A", expected.a as u16, registers.a as u16);
+    check("B", expected.b as u16, registers.b as u16);
+    check("C", expected.c as u16, registers.c as u16);
+    check("D", expected.d as u16, registers.d as u16);
+    check("E", expected.e as u16, registers.e as u16);
+    check("F", expected.f as u16, registers.f as u16);
+    check("H", expected.h as u16, registers.h as u16);
+    check("L", expected.l as u16, registers.l as u16);
+    check("PC", expected.pc, registers.pc);
+    check("SP", expected.sp, registers.sp);
+    check("IME", expected.ime as u16, cpu.interrupts_enabled() as u16);
+    if let Some(ie) = expected.ie {
+        check("IE", ie as u16, cpu.memory.ram[0xFFFF] as u16);
+    }
+    for &(address, value) in &expected.ram {
+        check(&format!("({:04X})", address), value as u16, cpu.memory.ram[address as usize] as u16);
+    }
+    check("M-cycles", test.cycles.len() as u16, (cycles / 4) as u16);
+
+    // Bus activity of each M-cycle, an access past the end of the instruction is reported too
+    let mut timeline = vec![None; cycles as usize / 4];
+    for (cycle, access) in accesses {
+        let cycle = cycle as usize;
+        if cycle >= timeline.len() {
+            timeline.resize(cycle + 1, None);
+        }
+        timeline[cycle] = Some(access);
+    }
+    for cycle in 0..timeline.len().max(test.cycles.len()) {
+        let expected = test.cycles.get(cycle).copied().flatten();
+        let actual = timeline.get(cycle).copied().flatten();
+        if expected != actual {
+            errors.push(format!("M-cycle {}: expected {}, got {}", cycle, describe(expected), describe(actual)));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+fn describe(access: Option<BusAccess>) -> String {
+    match access {
+        Some(BusAccess::Read { address, value }) => format!("read {:02X} at {:04X}", value, address),
+        Some(BusAccess::Write { address, value }) => format!("write {:02X} to {:04X}", value, address),
+        None => "idle".to_string(),
+    }
+}
+
+/// Results of the tests of one opcode
+pub struct FileResult {
+    // Name of the fixture without extension: the opcode, "cb xx" for the CB-prefixed ones
+    pub name: String,
+    pub passed: usize,
+    pub total: usize,
+    // Name of the first failed test and its differences
+    pub first_failure: Option<(String, String)>,
+}
+
+impl FileResult {
+    pub fn all_passed(&self) -> bool {
+        self.passed == self.total
+    }
+}
+
+/// Results of every fixture of a directory, displayed as a table
+pub struct SingleStepReport {
+    pub files: Vec<FileResult>,
+}
+
+impl SingleStepReport {
+    /// Number of opcodes whose tests all passed
+    pub fn passed(&self) -> usize {
+        self.files.iter().filter(|file| file.all_passed()).count()
+    }
+}
+
+impl fmt::Display for SingleStepReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.files.iter().map(|file| file.name.len()).max().unwrap_or(0).max(6);
+        writeln!(f, "sm83 ({}/{} opcodes passed)", self.passed(), self.files.len())?;
+        writeln!(f, "| {:<width$} | {:>11} |", "Opcode", "Tests")?;
+        writeln!(f, "|-{}-|-{}-|", "-".repeat(width), "-".repeat(11))?;
+        for file in &self.files {
+            let tests = format!("{}/{}", file.passed, file.total);
+            writeln!(f, "| {:<width$} | {:>11} |", file.name, tests)?;
+        }
+
+        for file in &self.files {
+            if let Some((name, message)) = &file.first_failure {
+                writeln!(f)?;
+                writeln!(f, "{}: {}", name, message)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the tests of a fixture file
+pub fn run_file(path: &Path) -> Result<FileResult, TestError> {
+    let tests = parse_tests(&std::fs::read_to_string(path)?)
+        .map_err(|error| TestError(format!("{}: {}", path.display(), error.0)))?;
+
+    let mut passed = 0;
+    let mut first_failure = None;
+    for test in &tests {
+        match run_test(test) {
+            Ok(()) => passed += 1,
+            Err(message) => {
+                first_failure.get_or_insert((test.name.clone(), message));
+            }
+        }
+    }
+
+    let name = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    Ok(FileResult { name, passed, total: tests.len(), first_failure })
+}
+
+/// Run every .json fixture of `directory`, sorted by name
+pub fn run_directory(directory: &Path) -> Result<SingleStepReport, TestError> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "json") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let files = paths.iter().map(|path| run_file(path)).collect::<Result<_, _>>()?;
+    Ok(SingleStepReport { files })
+}
+
+// Just enough JSON for the fixtures: no floats and no escapes other than \" and \\
+enum Json {
+    Null,
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn optional_field(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(key, _)| key == name).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn field(&self, name: &str) -> Result<&Json, TestError> {
+        self.optional_field(name).ok_or_else(|| TestError(format!("missing field {:?}", name)))
+    }
+
+    fn array(&self) -> Result<&[Json], TestError> {
+        match self {
+            Json::Array(values) => Ok(values),
+            _ => Err(TestError("expected an array".to_string())),
+        }
+    }
+
+    fn string(&self) -> Result<&str, TestError> {
+        match self {
+            Json::String(value) => Ok(value),
+            _ => Err(TestError("expected a string".to_string())),
+        }
+    }
+
+    fn number(&self, max: i64) -> Result<i64, TestError> {
+        match self {
+            Json::Number(value) if (0..=max).contains(value) => Ok(*value),
+            _ => Err(TestError(format!("expected a number between 0 and {}", max))),
+        }
+    }
+
+    fn byte(&self) -> Result<u8, TestError> {
+        self.number(0xFF).map(|value| value as u8)
+    }
+
+    fn word(&self) -> Result<u16, TestError> {
+        self.number(0xFFFF).map(|value| value as u16)
+    }
+}
+
+struct JsonParser<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl JsonParser<'_> {
+    fn error(&self, message: &str) -> TestError {
+        TestError(format!("{} at byte {}", message, self.position))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.data.get(self.position).is_some_and(|byte| byte.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    // Skip whitespace, then consume `byte` if it comes next
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        if self.data.get(self.position) == Some(&byte) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), TestError> {
+        if self.eat(byte) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", byte as char)))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, TestError> {
+        self.skip_whitespace();
+        match self.data.get(self.position) {
+            Some(b'[') => {
+                self.position += 1;
+                let mut values = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        values.push(self.value()?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Json::Array(values))
+            }
+            Some(b'{') => {
+                self.position += 1;
+                let mut fields = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        fields.push((key, self.value()?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Json::Object(fields))
+            }
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'n') if self.data[self.position..].starts_with(b"null") => {
+                self.position += 4;
+                Ok(Json::Null)
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.position;
+                self.position += 1;
+                while self.data.get(self.position).is_some_and(|byte| byte.is_ascii_digit()) {
+                    self.position += 1;
+                }
+                std::str::from_utf8(&self.data[start..self.position])
+                    .ok()
+                    .and_then(|number| number.parse().ok())
+                    .map(Json::Number)
+                    .ok_or_else(|| self.error("invalid number"))
+            }
+            _ => Err(self.error("unexpected character")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, TestError> {
+        if self.data.get(self.position) != Some(&b'"') {
+            return Err(self.error("expected a string"));
+        }
+        self.position += 1;
+        let mut string = Vec::new();
+        loop {
+            match self.data.get(self.position) {
+                Some(b'"') => break,
+                Some(b'\\') if matches!(self.data.get(self.position + 1), Some(b'"' | b'\\')) => {
+                    string.push(self.data[self.position + 1]);
+                    self.position += 2;
+                }
+                Some(b'\\') => return Err(self.error("unsupported escape")),
+                Some(&byte) => {
+                    string.push(byte);
+                    self.position += 1;
+                }
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+        self.position += 1;
+        String::from_utf8(string).map_err(|_| self.error("invalid UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One test of 00.json, c0.json, c5.json and cb 37.json, laid out like the SingleStepTests/sm83 fixtures
+    const FIXTURES: &str = r#"[
+  {
+    "name": "00 0000",
+    "initial": {
+      "a": 203, "b": 196, "c": 165, "d": 110, "e": 186, "f": 208, "h": 99, "l": 213,
+      "pc": 19935, "sp": 24262, "ime": 0, "ie": 1,
+      "ram": [[19935, 0]]
+    },
+    "final": {
+      "a": 203, "b": 196, "c": 165, "d": 110, "e": 186, "f": 208, "h": 99, "l": 213,
+      "pc": 19936, "sp": 24262, "ime": 0, "ie": 1,
+      "ram": [[19935, 0]]
+    },
+    "cycles": [[19935, 0, "r-m"]]
+  },
+  {
+    "name": "c0 0000",
+    "initial": {
+      "a": 117, "b": 3, "c": 240, "d": 58, "e": 201, "f": 96, "h": 17, "l": 142,
+      "pc": 29446, "sp": 8192, "ime": 0, "ie": 0,
+      "ram": [[8192, 52], [8193, 18], [29446, 192]]
+    },
+    "final": {
+      "a": 117, "b": 3, "c": 240, "d": 58, "e": 201, "f": 96, "h": 17, "l": 142,
+      "pc": 4660, "sp": 8194, "ime": 0, "ie": 0,
+      "ram": [[8192, 52], [8193, 18], [29446, 192]]
+    },
+    "cycles": [[29446, 192, "r-m"], null, [8192, 52, "r-m"], [8193, 18, "r-m"], null]
+  },
+  {
+    "name": "c5 0000",
+    "initial": {
+      "a": 25, "b": 86, "c": 129, "d": 222, "e": 50, "f": 64, "h": 175, "l": 11,
+      "pc": 41409, "sp": 31287, "ime": 0, "ie": 0,
+      "ram": [[41409, 197]]
+    },
+    "final": {
+      "a": 25, "b": 86, "c": 129, "d": 222, "e": 50, "f": 64, "h": 175, "l": 11,
+      "pc": 41410, "sp": 31285, "ime": 0, "ie": 0,
+      "ram": [[31285, 129], [31286, 86], [41409, 197]]
+    },
+    "cycles": [[41409, 197, "r-m"], null, [31286, 86, "-wm"], [31285, 129, "-wm"]]
+  },
+  {
+    "name": "cb 37 0000",
+    "initial": {
+      "a": 241, "b": 72, "c": 14, "d": 160, "e": 3, "f": 240, "h": 88, "l": 201,
+      "pc": 50123, "sp": 9911, "ime": 1, "ie": 0,
+      "ram": [[50123, 203], [50124, 55]]
+    },
+    "final": {
+      "a": 31, "b": 72, "c": 14, "d": 160, "e": 3, "f": 0, "h": 88, "l": 201,
+      "pc": 50125, "sp": 9911, "ime": 1, "ie": 0,
+      "ram": [[50123, 203], [50124, 55]]
+    },
+    "cycles": [[50123, 203, "r-m"], [50124, 55, "r-m"]]
+  }
+]"#;
+
+    #[test]
+    fn test_parse() {
+        let tests = parse_tests(FIXTURES).unwrap();
+        assert_eq!(tests.len(), 4);
+        assert_eq!(tests[2].name, "c5 0000");
+        assert_eq!(tests[2].initial.ie, Some(0));
+        assert_eq!(tests[2].expected.sp, 31285);
+        assert_eq!(tests[2].expected.ram[1], (31286, 86));
+        assert_eq!(tests[2].cycles[1], None);
+        assert_eq!(tests[2].cycles[2], Some(BusAccess::Write { address: 31286, value: 86 }));
+        assert!(tests[3].initial.ime);
+
+        assert!(parse_tests("[{\"name\": \"00\"}]").is_err());
+        assert!(parse_tests("[1, 2").is_err());
+        assert!(parse_tests("[] []").is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        for test in parse_tests(FIXTURES).unwrap() {
+            assert_eq!(run_test(&test), Ok(()), "{}", test.name);
+        }
+    }
+
+    #[test]
+    fn test_run_mismatch() {
+        let mut test = parse_tests(FIXTURES).unwrap().remove(2);
+        test.expected.b = 0x13;
+        // The idle M-cycle comes before the writes
+        test.cycles.swap(1, 2);
+        let error = run_test(&test).unwrap_err();
+        assert_eq!(
+            error,
+            "B: expected 13, got 56, M-cycle 1: expected write 56 to 7A36, got idle, \
+             M-cycle 2: expected idle, got write 56 to 7A36"
+        );
+
+        test.cycles.pop();
+        assert!(run_test(&test).unwrap_err().contains("M-cycles: expected 03, got 04"));
+    }
+}
@@ -0,0 +1,191 @@
+use crate::Error;
+
+// "RBST": Rusty Boy STate
+pub const STATE_MAGIC: &[u8; 4] = b"RBST";
+// Must be increased every time the layout of the state changes
+pub const STATE_VERSION: u16 = 7;
+// Magic, version, header checksum and global checksum of the ROM
+pub const STATE_HEADER_SIZE: usize = 9;
+
+/**
+ * Little-endian binary writer used to build save states.
+ * Every component writes its fields in a fixed order and reads them back in the same order.
+ */
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Write a block of bytes as is, without its length
+    pub fn raw_bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Write a block of bytes prefixed by its length
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, position: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    /// Read a block of `length` bytes that is not prefixed by its length
+    pub fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        // The length comes from the data, it can overflow on 32-bit targets
+        let end = match self.position.checked_add(length) {
+            Some(end) if end <= self.data.len() => end,
+            _ => return Err(Error::InvalidState("unexpected end of data".to_string())),
+        };
+        let slice = &self.data[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    /// Read a length-prefixed block of bytes
+    pub fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    /// Read a length-prefixed block of bytes into `buffer`, which must have the same length
+    pub fn bytes_into(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let data = self.bytes()?;
+        if data.len() != buffer.len() {
+            return Err(Error::InvalidState(format!(
+                "invalid block size: expected {}, got {}",
+                buffer.len(),
+                data.len()
+            )));
+        }
+        buffer.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.u8(0x12);
+        writer.bool(true);
+        writer.u16(0x3456);
+        writer.u32(0x789ABCDE);
+        writer.i32(-1);
+        writer.bytes(&[1, 2, 3]);
+        let data = writer.into_vec();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.u8().unwrap(), 0x12);
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.u16().unwrap(), 0x3456);
+        assert_eq!(reader.u32().unwrap(), 0x789ABCDE);
+        assert_eq!(reader.i32().unwrap(), -1);
+        let mut buffer = [0; 3];
+        reader.bytes_into(&mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3]);
+        assert!(reader.is_empty());
+        assert!(reader.u8().is_err());
+    }
+
+    #[test]
+    fn test_take_overflow() {
+        let mut reader = StateReader::new(&[1, 2, 3]);
+        reader.u8().unwrap();
+        assert!(matches!(reader.take(usize::MAX), Err(Error::InvalidState(_))));
+        assert_eq!(reader.take(2).unwrap(), &[2, 3]);
+    }
+}
@@ -0,0 +1,285 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::serial::LinkTransport;
+
+// Sent by both sides when the connection opens: magic and protocol version
+const HELLO: &[u8; 5] = b"RBLK\x01";
+
+// Every message is 10 bytes: kind, data byte and timestamp in cycles as a little endian u64
+const MESSAGE_SIZE: usize = 10;
+
+// Cycles between two timestamps sent to the other side
+const SYNC_INTERVAL: u64 = 4096;
+
+// A side waits for the other one when it runs more than a frame ahead
+const MAX_AHEAD: u64 = 70224;
+
+// A peer silent for that long while this side waits for it is considered gone
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Message {
+    // The sender reached the timestamp
+    Sync = 0,
+    // The sender started a transfer with its internal clock
+    Transfer = 1,
+    // Answer to a transfer, holds the byte shifted out by the other side (0xFF if it was not listening)
+    Reply = 2,
+}
+
+/**
+ * Link cable between two processes over TCP. Both sides exchange their timestamps so that
+ * neither runs more than a frame ahead of the other one, and a transfer reaches the other side
+ * when it gets to the time the transfer was started.
+ * The link behaves like an unplugged cable once the connection is lost.
+ */
+pub struct TcpLink {
+    stream: TcpStream,
+    connected: bool,
+    // Received bytes that don't make a full message yet
+    buffer: Vec<u8>,
+
+    // Cycles run since the connection opened, the cycle counter of the Gameboy can go back with save states
+    elapsed: u64,
+    last_cycle: Option<u64>,
+    next_sync: u64,
+    // Latest timestamp received from the other side
+    peer_elapsed: u64,
+
+    // Transfer started by the other side, answered once this side reaches its timestamp
+    pending: Option<(u8, u64)>,
+    reply: Option<u8>,
+}
+
+impl TcpLink {
+    /// Wait for the other side on `address` (e.g. "0.0.0.0:5000")
+    pub fn host<A: ToSocketAddrs>(address: A) -> Result<TcpLink, std::io::Error> {
+        let listener = TcpListener::bind(address)?;
+        let (stream, _) = listener.accept()?;
+        TcpLink::from_stream(stream)
+    }
+
+    /// Connect to a side waiting with `host`
+    pub fn connect<A: ToSocketAddrs>(address: A) -> Result<TcpLink, std::io::Error> {
+        TcpLink::from_stream(TcpStream::connect(address)?)
+    }
+
+    pub fn from_stream(mut stream: TcpStream) -> Result<TcpLink, std::io::Error> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.write_all(HELLO)?;
+        let mut hello = [0; HELLO.len()];
+        stream.read_exact(&mut hello)?;
+        if &hello != HELLO {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "The other side does not speak the link protocol",
+            ));
+        }
+
+        Ok(TcpLink {
+            stream,
+            connected: true,
+            buffer: Vec::new(),
+            elapsed: 0,
+            last_cycle: None,
+            next_sync: SYNC_INTERVAL,
+            peer_elapsed: 0,
+            pending: None,
+            reply: None,
+        })
+    }
+
+    /// False once the other side closed the connection or stopped answering
+    pub fn connected(&self) -> bool {
+        self.connected
+    }
+
+    /// How long to wait for the other side before unplugging the cable, 5 seconds by default
+    pub fn set_read_timeout(&mut self, timeout: Duration) -> Result<(), std::io::Error> {
+        self.stream.set_read_timeout(Some(timeout))
+    }
+
+    fn advance(&mut self, cycle: u64) {
+        if let Some(last_cycle) = self.last_cycle {
+            self.elapsed += cycle.saturating_sub(last_cycle);
+        }
+        self.last_cycle = Some(cycle);
+    }
+
+    fn send(&mut self, kind: Message, byte: u8) {
+        let mut message = [0; MESSAGE_SIZE];
+        message[0] = kind as u8;
+        message[1] = byte;
+        message[2..].copy_from_slice(&self.elapsed.to_le_bytes());
+        if self.stream.write_all(&message).is_err() {
+            self.connected = false;
+        }
+    }
+
+    /// Read the messages available, waiting for at least one if `wait` is set
+    fn receive(&mut self, wait: bool) {
+        if !wait && self.stream.set_nonblocking(true).is_err() {
+            self.connected = false;
+            return;
+        }
+
+        let mut data = [0; 256];
+        loop {
+            match self.stream.read(&mut data) {
+                Ok(0) => {
+                    self.connected = false;
+                    break;
+                }
+                Ok(size) => {
+                    self.buffer.extend_from_slice(&data[..size]);
+                    if wait && self.buffer.len() >= MESSAGE_SIZE {
+                        break;
+                    }
+                }
+                // Nothing more to read without waiting, or the wait timed out
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    if wait {
+                        self.connected = false;
+                    }
+                    break;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.connected = false;
+                    break;
+                }
+            }
+        }
+
+        if !wait && self.stream.set_nonblocking(false).is_err() {
+            self.connected = false;
+        }
+
+        let messages = self.buffer.len() / MESSAGE_SIZE * MESSAGE_SIZE;
+        let buffer: Vec<u8> = self.buffer.drain(..messages).collect();
+        for message in buffer.chunks_exact(MESSAGE_SIZE) {
+            let elapsed = u64::from_le_bytes(message[2..].try_into().unwrap());
+            self.peer_elapsed = self.peer_elapsed.max(elapsed);
+            match message[0] {
+                kind if kind == Message::Sync as u8 => {}
+                kind if kind == Message::Transfer as u8 => self.pending = Some((message[1], elapsed)),
+                kind if kind == Message::Reply as u8 => self.reply = Some(message[1]),
+                _ => self.connected = false,
+            }
+        }
+    }
+}
+
+impl LinkTransport for TcpLink {
+    fn transfer(&mut self, byte: u8, cycle: u64) -> u8 {
+        self.advance(cycle);
+        self.send(Message::Transfer, byte);
+        loop {
+            if let Some(reply) = self.reply.take() {
+                return reply;
+            }
+            if !self.connected {
+                return 0xFF;
+            }
+            self.receive(true);
+
+            // Both sides started a transfer at the same time, neither listens to the other
+            if self.pending.take().is_some() {
+                self.send(Message::Reply, 0xFF);
+            }
+        }
+    }
+
+    fn poll(&mut self, ready: Option<u8>, cycle: u64) -> Option<u8> {
+        self.advance(cycle);
+        if !self.connected {
+            return None;
+        }
+
+        if self.elapsed >= self.next_sync {
+            self.next_sync = self.elapsed + SYNC_INTERVAL;
+            self.send(Message::Sync, 0);
+            self.receive(false);
+            while self.connected && self.pending.is_none() && self.elapsed > self.peer_elapsed + MAX_AHEAD {
+                self.receive(true);
+            }
+        }
+
+        match self.pending {
+            Some((byte, elapsed)) if self.elapsed >= elapsed => {
+                self.pending = None;
+                self.send(Message::Reply, ready.unwrap_or(0xFF));
+                ready.map(|_| byte)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::Serial;
+
+    // Run a Gameboy serial port that loads SB and SC, long enough for the other side to catch up
+    fn run(link: TcpLink, sb: u8, sc: u8) -> Serial {
+        let mut serial = Serial::new(false);
+        serial.connect(Some(Box::new(link)));
+        serial.write(0xFF01, sb);
+        serial.write(0xFF02, sc);
+        for _ in 0..MAX_AHEAD {
+            serial.step(4);
+        }
+        serial
+    }
+
+    #[test]
+    fn test_tcp_link() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let slave = std::thread::spawn(move || run(TcpLink::connect(address).unwrap(), 0x24, 0x80));
+        let (stream, _) = listener.accept().unwrap();
+        let master = run(TcpLink::from_stream(stream).unwrap(), 0x42, 0x81);
+        let slave = slave.join().unwrap();
+
+        assert_eq!(master.read(0xFF01), 0x24);
+        assert_eq!(slave.read(0xFF01), 0x42);
+        assert_eq!(master.interrupt, 0x08);
+        assert_eq!(slave.interrupt, 0x08);
+    }
+
+    #[test]
+    fn test_tcp_link_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let other = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(b"HTTP/").unwrap();
+        });
+        let (stream, _) = listener.accept().unwrap();
+        assert!(TcpLink::from_stream(stream).is_err());
+        other.join().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_link_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // The other side says hello, then never answers
+        let other = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(HELLO).unwrap();
+            std::thread::sleep(Duration::from_millis(500));
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let mut link = TcpLink::from_stream(stream).unwrap();
+        link.set_read_timeout(Duration::from_millis(50)).unwrap();
+
+        assert_eq!(link.transfer(0x42, 0), 0xFF);
+        assert!(!link.connected());
+        other.join().unwrap();
+    }
+}
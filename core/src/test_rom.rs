@@ -0,0 +1,346 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::cpu::CPU;
+use crate::gameboy::{Gameboy, Lockup, Model};
+use crate::serial::LinkTransport;
+use crate::Error;
+
+/// Two minutes of emulated time, the longest blargg ROMs (cpu_instrs) need about one
+pub const DEFAULT_FRAMES: u32 = 60 * 120;
+
+// `LD B,B` is used by mooneye as a software breakpoint once the test is done
+const LD_B_B: u8 = 0x40;
+
+// Registers B, C, D, E, H and L of a passed mooneye test
+const MOONEYE_PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+// Signature written by blargg at 0xA001-0xA003 once the results in the cartridge RAM are valid
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+// Status at 0xA000 while the test is still running
+const BLARGG_RUNNING: u8 = 0x80;
+
+/// Family of test ROMs, each one reports its result in its own way
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Suite {
+    /**
+     * "Passed" or "Failed" printed over the serial port, or into the cartridge RAM at 0xA000
+     * @see: https://github.com/retrio/gb-test-roms
+     */
+    Blargg,
+    /**
+     * Fibonacci numbers in B, C, D, E, H and L when `LD B,B` is reached
+     * @see: https://github.com/Gekkio/mooneye-test-suite
+     */
+    Mooneye,
+}
+
+impl fmt::Display for Suite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Suite::Blargg => write!(f, "blargg"),
+            Suite::Mooneye => write!(f, "mooneye"),
+        }
+    }
+}
+
+/// Verdict of a single test ROM
+#[derive(PartialEq, Clone, Debug)]
+pub enum Outcome {
+    Passed,
+    // The ROM reported a failure, with what it printed or the registers it left
+    Failed(String),
+    // No verdict within the frame budget
+    Timeout,
+    // The ROM ran an illegal opcode
+    Lockup(Lockup),
+    // The ROM could not be loaded
+    Invalid(String),
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        *self == Outcome::Passed
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "Passed"),
+            Outcome::Failed(_) => write!(f, "Failed"),
+            Outcome::Timeout => write!(f, "Timeout"),
+            Outcome::Lockup(lockup) => write!(f, "Lockup ({:02X} at {:04X})", lockup.opcode, lockup.pc),
+            Outcome::Invalid(_) => write!(f, "Invalid"),
+        }
+    }
+}
+
+pub struct TestResult {
+    // Path of the ROM, relative to the directory of the suite
+    pub name: String,
+    pub outcome: Outcome,
+    // Frames run before the verdict
+    pub frames: u32,
+}
+
+/// Results of every ROM of a directory, displayed as a table
+pub struct SuiteReport {
+    pub suite: Suite,
+    pub results: Vec<TestResult>,
+}
+
+impl SuiteReport {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|result| result.outcome.passed()).count()
+    }
+}
+
+impl fmt::Display for SuiteReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.results.iter().map(|result| result.name.len()).max().unwrap_or(0).max(3);
+        writeln!(f, "{} ({}/{} passed)", self.suite, self.passed(), self.results.len())?;
+        writeln!(f, "| {:<width$} | {:<20} | {:>6} |", "ROM", "Result", "Frames")?;
+        writeln!(f, "|-{}-|-{}-|-{}-|", "-".repeat(width), "-".repeat(20), "-".repeat(6))?;
+        for result in &self.results {
+            let outcome = result.outcome.to_string();
+            writeln!(f, "| {:<width$} | {:<20} | {:>6} |", result.name, outcome, result.frames)?;
+        }
+
+        // What the failed ROMs printed does not fit in the table
+        for result in &self.results {
+            if let Outcome::Failed(message) | Outcome::Invalid(message) = &result.outcome {
+                writeln!(f)?;
+                writeln!(f, "{}:", result.name)?;
+                for line in message.lines() {
+                    writeln!(f, "    {}", line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Keeps the bytes sent by the ROM over the serial port, nothing answers
+struct SerialOutput(Arc<Mutex<Vec<u8>>>);
+
+impl LinkTransport for SerialOutput {
+    fn transfer(&mut self, byte: u8, _cycle: u64) -> u8 {
+        self.0.lock().unwrap().push(byte);
+        0xFF
+    }
+
+    fn poll(&mut self, _ready: Option<u8>, _cycle: u64) -> Option<u8> {
+        None
+    }
+}
+
+/// Run a test ROM headless for at most `frames` frames
+pub fn run_rom(rom: &Vec<u8>, suite: Suite, model: Option<Model>, frames: u32) -> (Outcome, u32) {
+    let mut gameboy = match Gameboy::new_from_data(rom, model, true) {
+        Ok(gameboy) => gameboy,
+        Err(error) => return (Outcome::Invalid(error.to_string()), 0),
+    };
+    let serial = Arc::new(Mutex::new(Vec::new()));
+    gameboy.connect_link(Box::new(SerialOutput(serial.clone())));
+
+    for frame in 1..=frames {
+        let breakpoint = match suite {
+            Suite::Blargg => gameboy.run_frame_until(|_| false),
+            Suite::Mooneye => gameboy.run_frame_until(|cpu| !cpu.halt && cpu.memory.read(cpu.registers.pc) == LD_B_B),
+        };
+        if let Some(lockup) = gameboy.lockup() {
+            return (Outcome::Lockup(lockup), frame);
+        }
+
+        let outcome = match suite {
+            Suite::Blargg => blargg_outcome(&gameboy.cpu, &serial.lock().unwrap()),
+            Suite::Mooneye if breakpoint => Some(mooneye_outcome(&gameboy.cpu)),
+            Suite::Mooneye => None,
+        };
+        if let Some(outcome) = outcome {
+            return (outcome, frame);
+        }
+    }
+    (Outcome::Timeout, frames)
+}
+
+fn blargg_outcome(cpu: &CPU, serial: &[u8]) -> Option<Outcome> {
+    let text = String::from_utf8_lossy(serial);
+    if text.contains("Passed") {
+        return Some(Outcome::Passed);
+    }
+    if text.contains("Failed") {
+        return Some(Outcome::Failed(text.trim().to_string()));
+    }
+
+    // ROMs that can't use the serial port (sound, timing...) write their output in the cartridge RAM
+    let signature = [cpu.memory.read(0xA001), cpu.memory.read(0xA002), cpu.memory.read(0xA003)];
+    let status = cpu.memory.read(0xA000);
+    if signature != BLARGG_SIGNATURE || status == BLARGG_RUNNING {
+        return None;
+    }
+    let text: Vec<u8> = (0xA004..0xC000)
+        .map(|address| cpu.memory.read(address))
+        .take_while(|&byte| byte != 0)
+        .collect();
+    let text = String::from_utf8_lossy(&text).trim().to_string();
+    Some(match status {
+        0 => Outcome::Passed,
+        _ => Outcome::Failed(text),
+    })
+}
+
+fn mooneye_outcome(cpu: &CPU) -> Outcome {
+    let registers = &cpu.registers;
+    let values = [registers.b, registers.c, registers.d, registers.e, registers.h, registers.l];
+    if values == MOONEYE_PASSED {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(format!(
+            "B={:02X} C={:02X} D={:02X} E={:02X} H={:02X} L={:02X}",
+            values[0], values[1], values[2], values[3], values[4], values[5]
+        ))
+    }
+}
+
+/// Run every .gb and .gbc ROM found in `directory` and its subdirectories, sorted by path
+pub fn run_suite(directory: &Path, suite: Suite, frames: u32) -> Result<SuiteReport, Error> {
+    let mut paths = Vec::new();
+    find_roms(directory, &mut paths)?;
+    paths.sort();
+
+    let mut results = Vec::new();
+    for path in paths {
+        let rom = std::fs::read(&path)?;
+        let (outcome, frames) = run_rom(&rom, suite, None, frames);
+        let name = path.strip_prefix(directory).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+        results.push(TestResult { name, outcome, frames });
+    }
+    Ok(SuiteReport { suite, results })
+}
+
+fn find_roms(directory: &Path, paths: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_roms(&path, paths)?;
+        } else if matches!(path.extension().and_then(|extension| extension.to_str()), Some("gb" | "gbc")) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 32 KiB ROM without MBC jumping over the header to `program`
+    fn new_rom(program: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0103].copy_from_slice(&[0xC3, 0x50, 0x01]); // JP 0x0150
+        rom[0x0150..0x0150 + program.len()].copy_from_slice(program);
+        rom
+    }
+
+    // Send `text` over the serial port with the internal clock, then loop
+    fn serial_program(text: &str) -> Vec<u8> {
+        let mut program = Vec::new();
+        for byte in text.bytes() {
+            program.extend_from_slice(&[
+                0x3E, byte, // LD A,byte
+                0xE0, 0x01, // LDH (SB),A
+                0x3E, 0x81, // LD A,0x81
+                0xE0, 0x02, // LDH (SC),A
+                0xF0, 0x02, // LDH A,(SC)
+                0xCB, 0x7F, // BIT 7,A
+                0x20, 0xFA, // JR NZ,-6
+            ]);
+        }
+        program.extend_from_slice(&[0x18, 0xFE]); // JR -2
+        program
+    }
+
+    // Load `values` in B, C, D, E, H and L, then LD B,B
+    fn mooneye_program(values: [u8; 6]) -> Vec<u8> {
+        let mut program = Vec::new();
+        for (opcode, value) in [0x06, 0x0E, 0x16, 0x1E, 0x26, 0x2E].into_iter().zip(values) {
+            program.extend_from_slice(&[opcode, value]);
+        }
+        program.extend_from_slice(&[LD_B_B, 0x18, 0xFE]);
+        program
+    }
+
+    #[test]
+    fn test_blargg_serial() {
+        let (outcome, frames) = run_rom(&new_rom(&serial_program("Passed\n")), Suite::Blargg, None, 60);
+        assert_eq!(outcome, Outcome::Passed);
+        assert!(frames < 60);
+
+        let (outcome, _) = run_rom(&new_rom(&serial_program("01:03\nFailed #2\n")), Suite::Blargg, None, 60);
+        assert_eq!(outcome, Outcome::Failed("01:03\nFailed #2".to_string()));
+    }
+
+    #[test]
+    fn test_blargg_memory() {
+        let mut rom = new_rom(&[
+            0x3E, 0x0A, // LD A,0x0A
+            0xEA, 0x00, 0x00, // LD (0x0000),A: enable the RAM
+            0x21, 0x04, 0xA0, // LD HL,0xA004
+            0x36, b'O', 0x23, // LD (HL),'O'; INC HL
+            0x36, b'k', 0x23, // LD (HL),'k'; INC HL
+            0x36, 0x00, // LD (HL),0
+            0x21, 0x01, 0xA0, // LD HL,0xA001
+            0x36, 0xDE, 0x23, // signature
+            0x36, 0xB0, 0x23,
+            0x36, 0x61,
+            0xAF, // XOR A
+            0xEA, 0x00, 0xA0, // LD (0xA000),A: passed
+            0x18, 0xFE, // JR -2
+        ]);
+        rom[0x0147] = 0x03; // MBC1+RAM+BATTERY
+        rom[0x0149] = 0x02;
+        let (outcome, _) = run_rom(&rom, Suite::Blargg, None, 60);
+        assert_eq!(outcome, Outcome::Passed);
+    }
+
+    #[test]
+    fn test_mooneye() {
+        let (outcome, frames) = run_rom(&new_rom(&mooneye_program(MOONEYE_PASSED)), Suite::Mooneye, None, 60);
+        assert_eq!(outcome, Outcome::Passed);
+        assert_eq!(frames, 1);
+
+        let (outcome, _) = run_rom(&new_rom(&mooneye_program([0x42; 6])), Suite::Mooneye, None, 60);
+        assert_eq!(outcome, Outcome::Failed("B=42 C=42 D=42 E=42 H=42 L=42".to_string()));
+    }
+
+    #[test]
+    fn test_timeout_and_lockup() {
+        let (outcome, frames) = run_rom(&new_rom(&[0x18, 0xFE]), Suite::Mooneye, None, 10);
+        assert_eq!(outcome, Outcome::Timeout);
+        assert_eq!(frames, 10);
+
+        let (outcome, _) = run_rom(&new_rom(&[0xD3]), Suite::Blargg, None, 10);
+        assert_eq!(outcome, Outcome::Lockup(Lockup { pc: 0x0150, opcode: 0xD3 }));
+
+        let (outcome, _) = run_rom(&vec![0; 0x100], Suite::Blargg, None, 10);
+        assert!(matches!(outcome, Outcome::Invalid(_)));
+    }
+
+    #[test]
+    fn test_report_table() {
+        let report = SuiteReport {
+            suite: Suite::Mooneye,
+            results: vec![
+                TestResult { name: "acceptance/ei_timing.gb".to_string(), outcome: Outcome::Passed, frames: 3 },
+                TestResult { name: "acceptance/di_timing.gb".to_string(), outcome: Outcome::Timeout, frames: 7200 },
+            ],
+        };
+        let table = report.to_string();
+        assert!(table.starts_with("mooneye (1/2 passed)\n"));
+        assert!(table.contains("| acceptance/ei_timing.gb | Passed               |      3 |"));
+        assert!(table.contains("| acceptance/di_timing.gb | Timeout              |   7200 |"));
+    }
+}
@@ -1,30 +1,33 @@
+use crate::state::{StateReader, StateWriter};
+
 #[derive(Clone, Copy)]
 enum TimerMode {
-    Clock256Mhz,
-    Clock4Mhz,
-    Clock16Mhz,
-    Clock64Khz,
+    Hz4096,
+    Hz262144,
+    Hz65536,
+    Hz16384,
 }
 
-
-impl Into<u8> for TimerMode {
-    fn into(self) -> u8 {
+impl TimerMode {
+    /// Bit of the system counter whose falling edge increments TIMA
+    fn bit(self) -> u16 {
         match self {
-            TimerMode::Clock256Mhz => 64,
-            TimerMode::Clock4Mhz => 1,
-            TimerMode::Clock16Mhz => 4,
-            TimerMode::Clock64Khz => 16,
+            TimerMode::Hz4096 => 1 << 9,
+            TimerMode::Hz262144 => 1 << 3,
+            TimerMode::Hz65536 => 1 << 5,
+            TimerMode::Hz16384 => 1 << 7,
         }
     }
 }
 
 /**
 * @see: https://gbdev.io/pandocs/Timer_and_Divider_Registers.html
+* @see: https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html
 */
 pub struct Timer {
-    
-    // 0xFF04 — DIV: Divider register
-    div: u8,
+
+    // System counter incremented every cycle, DIV (0xFF04) is its upper byte
+    counter: u16,
 
     // 0xFF05 — TIMA: Timer counter
     tima: u8,
@@ -36,11 +39,11 @@ pub struct Timer {
     enable: bool,
     mode: TimerMode,
 
-    timer_clock: u8,
+    // TIMA overflowed during the last M-cycle, it reads 0 until TMA is loaded in the next one
+    overflow: bool,
 
-    /* active: bool,
-    timer_clock: u8,
-    internal_clock: u8, */
+    // TMA is being loaded in TIMA during the current M-cycle
+    reloading: bool,
 
     pub interrupt: u8,
 }
@@ -48,69 +51,225 @@ pub struct Timer {
 impl Timer {
     pub fn new() -> Timer {
         Timer {
-            div: 0,
+            counter: 0,
             tima: 0,
             tma: 0,
-            
+
             enable: false,
-            mode: TimerMode::Clock256Mhz,
-            
+            mode: TimerMode::Hz4096,
+
+            overflow: false,
+            reloading: false,
+
             interrupt: 0,
-            timer_clock: 0,
         }
     }
 
     pub fn read(&self, address: u16) -> u8 {
         match address {
-            0xFF04 => self.div,
+            0xFF04 => (self.counter >> 8) as u8,
             0xFF05 => self.tima,
             0xFF06 => self.tma,
             0xFF07 => {
                 0xF8 |
                 (self.enable as u8) << 2 |
                 match self.mode {
-                    TimerMode::Clock256Mhz => 0b00,
-                    TimerMode::Clock4Mhz => 0b01,
-                    TimerMode::Clock16Mhz => 0b10,
-                    TimerMode::Clock64Khz => 0b11,
+                    TimerMode::Hz4096 => 0b00,
+                    TimerMode::Hz262144 => 0b01,
+                    TimerMode::Hz65536 => 0b10,
+                    TimerMode::Hz16384 => 0b11,
                 }
             }
-            _ => panic!("Invalid timer read address: {:04x}", address),
+            // Not a timer register, nothing drives the bus
+            _ => 0xFF,
         }
     }
 
     pub fn write(&mut self, address: u16, value: u8) {
+        // The multiplexer output can fall when the counter or TAC change, which increments TIMA
+        let signal = self.signal();
         match address {
-            0xFF04 => self.div = 0,
-            0xFF05 => self.tima = value,
-            0xFF06 => self.tma = value,
-            0xFF07 => {
-                self.enable = value & 0b100 != 0;
-                self.mode = match value & 0b11 {
-                    0b00 => TimerMode::Clock256Mhz,
-                    0b01 => TimerMode::Clock4Mhz,
-                    0b10 => TimerMode::Clock16Mhz,
-                    0b11 => TimerMode::Clock64Khz,
-                    _ => { unreachable!() },
-                };
+            0xFF04 => self.counter = 0,
+            // The write is lost when TMA is being loaded, it cancels a pending reload otherwise
+            0xFF05 if !self.reloading => {
+                self.tima = value;
+                self.overflow = false;
+            }
+            0xFF06 => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
             }
-            _ => panic!("Invalid timer write address: {:04x}", address),
+            0xFF07 => self.set_tac(value),
+            _ => {}
         }
+        if signal && !self.signal() {
+            self.increment();
+        }
+    }
+
+    fn set_tac(&mut self, value: u8) {
+        self.enable = value & 0b100 != 0;
+        self.mode = match value & 0b11 {
+            0b00 => TimerMode::Hz4096,
+            0b01 => TimerMode::Hz262144,
+            0b10 => TimerMode::Hz65536,
+            0b11 => TimerMode::Hz16384,
+            _ => { unreachable!() },
+        };
+    }
+
+    /// Set DIV without resetting it like a write from the CPU does
+    pub fn set_div(&mut self, value: u8) {
+        self.counter = (value as u16) << 8;
     }
 
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.counter);
+        state.u8(self.tima);
+        state.u8(self.tma);
+        state.u8(self.read(0xFF07));
+        state.bool(self.overflow);
+        state.bool(self.reloading);
+        state.u8(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), crate::Error> {
+        self.counter = state.u16()?;
+        self.tima = state.u8()?;
+        self.tma = state.u8()?;
+        self.set_tac(state.u8()?);
+        self.overflow = state.bool()?;
+        self.reloading = state.bool()?;
+        self.interrupt = state.u8()?;
+        Ok(())
+    }
+
+    /// Advance the timer of `cycles` T-cycles, one M-cycle at a time
     pub fn step(&mut self, cycles: u8) {
-        
-        self.timer_clock += cycles;
+        for _ in 0..cycles / 4 {
+            self.reloading = false;
+            if self.overflow {
+                self.overflow = false;
+                self.reloading = true;
+                self.tima = self.tma;
+                self.interrupt = 0x04;
+            }
 
-        let mode_value= TimerMode::into(self.mode);
-        while self.timer_clock >= mode_value {
-            self.div = self.div.wrapping_add(1);
-            self.timer_clock = self.timer_clock.wrapping_sub(mode_value);
+            let signal = self.signal();
+            self.counter = self.counter.wrapping_add(4);
+            if signal && !self.signal() {
+                self.increment();
+            }
         }
+    }
 
-        if self.enable && (255 - self.tima) < cycles {
-            self.tima = self.tma;
-            self.interrupt = 0x04;
-        }
+    /// Selected bit of the system counter AND the timer enable bit
+    fn signal(&self) -> bool {
+        self.enable && self.counter & self.mode.bit() != 0
     }
-}
\ No newline at end of file
+
+    fn increment(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        self.overflow |= overflow;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_timer(tac: u8) -> Timer {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, tac);
+        timer
+    }
+
+    #[test]
+    fn test_div() {
+        let mut timer = Timer::new();
+        timer.step(252);
+        assert_eq!(timer.read(0xFF04), 0);
+        timer.step(4);
+        assert_eq!(timer.read(0xFF04), 1);
+        timer.write(0xFF04, 0x42);
+        assert_eq!(timer.read(0xFF04), 0);
+    }
+
+    #[test]
+    fn test_tima_frequency() {
+        let mut timer = new_timer(0b101);
+        timer.step(160);
+        assert_eq!(timer.read(0xFF05), 10);
+
+        let mut timer = new_timer(0b100);
+        timer.step(252);
+        timer.step(252);
+        timer.step(252);
+        timer.step(252);
+        assert_eq!(timer.read(0xFF05), 0);
+        timer.step(16);
+        assert_eq!(timer.read(0xFF05), 1);
+
+        // Nothing happens while the timer is disabled
+        let mut timer = new_timer(0b001);
+        timer.step(160);
+        assert_eq!(timer.read(0xFF05), 0);
+    }
+
+    #[test]
+    fn test_overflow_reload_delay() {
+        let mut timer = new_timer(0b101);
+        timer.write(0xFF06, 0x80);
+        timer.write(0xFF05, 0xFF);
+        timer.step(16);
+        // TIMA reads 0 during the M-cycle following the overflow
+        assert_eq!(timer.read(0xFF05), 0x00);
+        assert_eq!(timer.interrupt, 0);
+        timer.step(4);
+        assert_eq!(timer.read(0xFF05), 0x80);
+        assert_eq!(timer.interrupt, 0x04);
+    }
+
+    #[test]
+    fn test_tima_write_during_reload() {
+        // A write right after the overflow cancels the reload and the interrupt
+        let mut timer = new_timer(0b101);
+        timer.write(0xFF06, 0x80);
+        timer.write(0xFF05, 0xFF);
+        timer.step(16);
+        timer.write(0xFF05, 0x10);
+        timer.step(4);
+        assert_eq!(timer.read(0xFF05), 0x10);
+        assert_eq!(timer.interrupt, 0);
+
+        // A write while TMA is loaded is ignored, a TMA write goes through to TIMA
+        let mut timer = new_timer(0b101);
+        timer.write(0xFF05, 0xFF);
+        timer.step(20);
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.read(0xFF05), 0x00);
+        timer.write(0xFF06, 0x20);
+        assert_eq!(timer.read(0xFF05), 0x20);
+    }
+
+    #[test]
+    fn test_falling_edge_glitches() {
+        // Resetting DIV while the selected bit is set is a falling edge
+        let mut timer = new_timer(0b101);
+        timer.step(8);
+        timer.write(0xFF04, 0);
+        assert_eq!(timer.read(0xFF05), 1);
+
+        // So is disabling the timer or selecting a bit that is cleared
+        let mut timer = new_timer(0b101);
+        timer.step(8);
+        timer.write(0xFF07, 0b001);
+        assert_eq!(timer.read(0xFF05), 1);
+        timer.write(0xFF07, 0b101);
+        timer.write(0xFF07, 0b100);
+        assert_eq!(timer.read(0xFF05), 2);
+    }
+}